@@ -0,0 +1,233 @@
+//! Access logging in Combined Log Format
+
+use crate::sim::Clock;
+use crate::trace::TraceContext;
+use http::Request;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Everything worth logging about a single connection.
+#[derive(Debug)]
+pub struct AccessRecord {
+    pub peer: SocketAddr,
+    pub request_line: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    /// Status of the upgrade response, 101 on success.
+    pub status: u16,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Close code sent by the peer, if it closed cleanly.
+    pub close_code: Option<u16>,
+    /// Close the server sent on its own initiative, with its reason.
+    pub forced_close: Option<(u16, String)>,
+    /// Trace context propagated from the upgrade request.
+    pub trace: Option<TraceContext>,
+    started: SystemTime,
+    timer: Instant,
+}
+
+impl AccessRecord {
+    /// A record of a connection from `peer` opening now on `clock`.
+    pub fn new(peer: SocketAddr, clock: &dyn Clock) -> AccessRecord {
+        AccessRecord {
+            peer,
+            request_line: None,
+            referer: None,
+            user_agent: None,
+            status: 400,
+            bytes_in: 0,
+            bytes_out: 0,
+            close_code: None,
+            forced_close: None,
+            trace: None,
+            started: clock.system_time(),
+            timer: clock.now(),
+        }
+    }
+
+    /// Pick up the request line and the headers the combined format wants.
+    pub fn set_request(&mut self, request: &Request<()>) {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        self.request_line = Some(format!(
+            "{} {} {:?}",
+            request.method(),
+            request.uri(),
+            request.version()
+        ));
+        self.referer = header("Referer");
+        self.user_agent = header("User-Agent");
+        self.trace = TraceContext::from_request(request);
+    }
+
+    /// Combined Log Format followed by the WebSocket specific fields:
+    /// duration in milliseconds, bytes received, close code and the
+    /// `trace_id-parent_id` the client propagated. The close code is the
+    /// peer's, or the one the server forced if the peer sent none. Durations
+    /// run up to `now`.
+    pub fn format(&self, now: Instant) -> String {
+        fn or_dash(value: &Option<String>) -> String {
+            match value {
+                Some(value) => value.replace('"', "\\\""),
+                None => String::from("-"),
+            }
+        }
+
+        format!(
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {} {} {} {}",
+            self.peer.ip(),
+            clf_date(self.started),
+            or_dash(&self.request_line),
+            self.status,
+            self.bytes_out,
+            or_dash(&self.referer),
+            or_dash(&self.user_agent),
+            now.saturating_duration_since(self.timer).as_millis(),
+            self.bytes_in,
+            self.close_code
+                .or(self.forced_close.as_ref().map(|(code, _)| *code))
+                .map_or_else(|| String::from("-"), |code| code.to_string()),
+            self.trace.as_ref().map_or_else(
+                || String::from("-"),
+                |trace| format!("{}-{}", trace.trace_id, trace.parent_id)
+            ),
+        )
+    }
+}
+
+/// A shared sink for access log lines.
+pub struct AccessLog {
+    output: Mutex<Box<dyn Write + Send>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AccessLog {
+    /// Open a log target: `-` for stdout, otherwise a file to append to.
+    /// Connection durations are measured on `clock`.
+    pub fn open(target: &str, clock: Arc<dyn Clock>) -> io::Result<AccessLog> {
+        let output: Box<dyn Write + Send> = if target == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(target)?)
+        };
+        Ok(AccessLog {
+            output: Mutex::new(output),
+            clock,
+        })
+    }
+
+    pub fn write(&self, record: &AccessRecord) {
+        let line = record.format(self.clock.now());
+        let mut output = self.output.lock().unwrap();
+        // logging must never take a connection down
+        writeln!(output, "{line}").ok();
+        output.flush().ok();
+    }
+}
+
+/// Format a timestamp as `10/Oct/2000:13:55:36 +0000`.
+fn clf_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let seconds = secs % 86_400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Convert days since the Unix epoch to a (year, month, day) civil date.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::VirtualClock;
+    use std::time::Duration;
+
+    #[test]
+    fn civil_from_days_handles_epochs_leap_days_and_centuries() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        // 2100 is not a leap year
+        assert_eq!(civil_from_days(47_540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47_541), (2100, 3, 1));
+        assert_eq!(civil_from_days(-719_468), (0, 3, 1));
+    }
+
+    #[test]
+    fn clf_date_matches_the_apache_example() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(clf_date(time), "10/Oct/2000:13:55:36 +0000");
+    }
+
+    #[test]
+    fn record_formats_as_a_combined_log_line() {
+        let clock = VirtualClock::default();
+        let mut record = AccessRecord::new("127.0.0.1:4000".parse().unwrap(), &clock);
+        let request = Request::get("/chat")
+            .header("User-Agent", "say \"hi\"")
+            .body(())
+            .unwrap();
+        record.set_request(&request);
+        record.status = 101;
+        record.bytes_out = 129;
+        record.bytes_in = 42;
+        record.close_code = Some(1000);
+        clock.advance(Duration::from_millis(1500));
+
+        let line = record.format(clock.now());
+        let (address, rest) = line.split_once(" [").unwrap();
+        assert_eq!(address, "127.0.0.1 - -");
+        let (_, rest) = rest.split_once("] ").unwrap();
+        assert_eq!(
+            rest,
+            "\"GET /chat HTTP/1.1\" 101 129 \"-\" \"say \\\"hi\\\"\" 1500 42 1000 -"
+        );
+    }
+
+    #[test]
+    fn forced_close_code_is_logged_when_the_peer_sent_none() {
+        let clock = VirtualClock::default();
+        let mut record = AccessRecord::new("127.0.0.1:4000".parse().unwrap(), &clock);
+        record.forced_close = Some((1009, String::from("message too big")));
+        assert!(record.format(clock.now()).ends_with(" 0 0 1009 -"));
+
+        // a peer answering the server's Close gets its own code logged
+        record.close_code = Some(1000);
+        assert!(record.format(clock.now()).ends_with(" 0 0 1000 -"));
+    }
+}
@@ -0,0 +1,229 @@
+//! Instance affinity tokens for load-balanced deployments
+
+use crate::error::{Error, Result};
+use http::Request;
+use sha1::{Digest, Sha1};
+
+const COOKIE_NAME: &str = "ws-affinity";
+const HEADER_NAME: &str = "X-WS-Affinity";
+const BLOCK_SIZE: usize = 64;
+
+/// Where the token travels between server and client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Carrier {
+    /// `Set-Cookie` on the response, `Cookie` on the reconnect.
+    Cookie,
+    /// `X-WS-Affinity` both ways, for clients without a cookie jar.
+    Header,
+}
+
+/// Mints and checks tokens of the form `<instance>.<signature>`, where the
+/// signature is an HMAC-SHA1 of the instance id under a secret shared by the
+/// whole fleet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Affinity {
+    instance: String,
+    secret: Vec<u8>,
+    carrier: Carrier,
+}
+
+impl Affinity {
+    pub fn new(instance: &str, secret: &[u8], carrier: Carrier) -> Affinity {
+        Affinity {
+            instance: instance.to_string(),
+            secret: secret.to_vec(),
+            carrier,
+        }
+    }
+
+    /// The token handed out by this instance.
+    pub fn token(&self) -> String {
+        format!("{}.{}", self.instance, self.sign(&self.instance))
+    }
+
+    /// The header carrying the token on the upgrade response.
+    pub fn response_header(&self) -> (&'static str, String) {
+        match self.carrier {
+            Carrier::Cookie => (
+                "Set-Cookie",
+                format!("{COOKIE_NAME}={}; Path=/; HttpOnly", self.token()),
+            ),
+            Carrier::Header => (HEADER_NAME, self.token()),
+        }
+    }
+
+    /// Check the token a reconnecting client presents, if any. A forged token
+    /// or one minted by another instance fails the handshake straight away.
+    pub fn check(&self, request: &Request<()>) -> Result<()> {
+        let token = match self.find_token(request) {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+        let (instance, signature) = token.rsplit_once('.').ok_or(Error::InvalidAffinity)?;
+        if !constant_time_eq(self.sign(instance).as_bytes(), signature.as_bytes()) {
+            return Err(Error::InvalidAffinity);
+        }
+        if instance != self.instance {
+            return Err(Error::WrongInstance(instance.to_string()));
+        }
+        Ok(())
+    }
+
+    fn find_token<'a>(&self, request: &'a Request<()>) -> Option<&'a str> {
+        let headers = request.headers();
+        match self.carrier {
+            Carrier::Header => headers.get(HEADER_NAME)?.to_str().ok(),
+            Carrier::Cookie => headers
+                .get_all("Cookie")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|cookies| cookies.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(name, _)| *name == COOKIE_NAME)
+                .map(|(_, value)| value),
+        }
+    }
+
+    fn sign(&self, instance: &str) -> String {
+        let signature = hmac_sha1(&self.secret, instance.as_bytes());
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    }
+}
+
+/// HMAC-SHA1 (RFC 2104) of `message` under `secret`.
+fn hmac_sha1(secret: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut key = [0u8; BLOCK_SIZE];
+    if secret.len() > BLOCK_SIZE {
+        key[..20].copy_from_slice(&Sha1::digest(secret));
+    } else {
+        key[..secret.len()].copy_from_slice(secret);
+    }
+
+    let pad = |byte: u8| key.map(|k| k ^ byte);
+    let inner = Sha1::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha1::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+/// Compare two byte strings in time that depends only on their lengths.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    #[test]
+    fn hmac_sha1_matches_rfc_2202() {
+        let key4: Vec<u8> = (1..=25).collect();
+        let cases: [(&[u8], &[u8], &str); 7] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b617318655057264e28bc0b6fb378c8ef146be00",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79",
+            ),
+            (
+                &[0xaa; 20],
+                &[0xdd; 50],
+                "125d7342b9ac11cd91a39af48aa17b4f63f175d3",
+            ),
+            (
+                &key4,
+                &[0xcd; 50],
+                "4c9007f4026250c6bc8414f9bf50c86c2d7235da",
+            ),
+            (
+                &[0x0c; 20],
+                b"Test With Truncation",
+                "4c1a03424b55e07fe7f27be1d58bb9324a9a5a04",
+            ),
+            (
+                &[0xaa; 80],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "aa4ae5e15272d00e95705637ce8a3b55ed402112",
+            ),
+            (
+                &[0xaa; 80],
+                b"Test Using Larger Than Block-Size Key and Larger Than One Block-Size Data",
+                "e8e99d0f45237d786d6bbaa7965c7808bbff1a91",
+            ),
+        ];
+        for (key, message, digest) in cases {
+            assert_eq!(hex(&hmac_sha1(key, message)), digest);
+        }
+    }
+
+    fn request(cookie: &str) -> Request<()> {
+        Request::get("/")
+            .header("Cookie", format!("theme=dark; {COOKIE_NAME}={cookie}"))
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn own_token_passes() {
+        let affinity = Affinity::new("a", b"secret", Carrier::Cookie);
+        assert!(affinity.check(&request(&affinity.token())).is_ok());
+        assert!(affinity.check(&Request::get("/").body(()).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn tampered_cookie_fails() {
+        let affinity = Affinity::new("a", b"secret", Carrier::Cookie);
+        let token = affinity.token();
+        let (instance, signature) = token.rsplit_once('.').unwrap();
+
+        let mut flipped = signature.as_bytes().to_vec();
+        flipped[0] = if flipped[0] == b'A' { b'B' } else { b'A' };
+        let flipped = format!("{instance}.{}", String::from_utf8(flipped).unwrap());
+        let moved = format!("b.{signature}");
+        let other_secret = Affinity::new("a", b"guess", Carrier::Cookie).token();
+        for forged in [flipped, moved, other_secret, String::from("a")] {
+            assert!(
+                matches!(
+                    affinity.check(&request(&forged)),
+                    Err(Error::InvalidAffinity)
+                ),
+                "{forged} passed"
+            );
+        }
+    }
+
+    #[test]
+    fn token_from_another_instance_is_told_apart() {
+        let affinity = Affinity::new("a", b"secret", Carrier::Header);
+        let other = Affinity::new("b", b"secret", Carrier::Header).token();
+        let request = Request::get("/")
+            .header(HEADER_NAME, other)
+            .body(())
+            .unwrap();
+        assert!(matches!(
+            affinity.check(&request),
+            Err(Error::WrongInstance(instance)) if instance == "b"
+        ));
+    }
+
+    #[test]
+    fn comparison_needs_equal_lengths_and_bytes() {
+        assert!(constant_time_eq(b"same", b"same"));
+        assert!(!constant_time_eq(b"same", b"sane"));
+        assert!(!constant_time_eq(b"same", b"sam"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
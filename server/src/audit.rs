@@ -0,0 +1,359 @@
+//! Audit logging of security relevant events as JSON lines
+
+use crate::access_log::civil_from_days;
+use crate::sha256::Sha256;
+use crate::sim::Clock;
+use http::Request;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Headers whose values are never written out as they are.
+const SENSITIVE_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-ws-affinity",
+];
+
+/// Query parameters commonly used to carry credentials.
+const SENSITIVE_PARAMS: [&str; 4] = ["token", "access_token", "auth", "key"];
+
+/// Which header and query parameter values to hide, and how.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redaction {
+    /// Lowercase names of headers and query parameters to redact.
+    names: Vec<String>,
+    /// Replace values with a short digest instead of a placeholder, so the
+    /// same credential can be followed across events without revealing it.
+    pub hash: bool,
+}
+
+impl Default for Redaction {
+    fn default() -> Self {
+        Redaction {
+            names: SENSITIVE_HEADERS
+                .iter()
+                .chain(&SENSITIVE_PARAMS)
+                .map(|name| name.to_string())
+                .collect(),
+            hash: false,
+        }
+    }
+}
+
+impl Redaction {
+    /// Also redact the header or query parameter called `name`.
+    pub fn add(&mut self, name: &str) {
+        let name = name.to_ascii_lowercase();
+        if !self.names.contains(&name) {
+            self.names.push(name);
+        }
+    }
+
+    pub fn covers(&self, name: &str) -> bool {
+        self.names
+            .iter()
+            .any(|covered| covered.eq_ignore_ascii_case(name))
+    }
+
+    /// What is written in place of a redacted value.
+    pub fn redact(&self, value: &str) -> String {
+        if !self.hash {
+            return String::from("[redacted]");
+        }
+        let mut hasher = Sha256::default();
+        hasher.update(value.as_bytes());
+        let mut digest = String::from("sha256:");
+        for byte in &hasher.finalize()[..8] {
+            write!(digest, "{byte:02x}").unwrap();
+        }
+        digest
+    }
+
+    /// The request target with redacted query parameter values replaced.
+    pub fn target(&self, target: &str) -> String {
+        let (path, query) = match target.split_once('?') {
+            Some(parts) => parts,
+            None => return target.to_string(),
+        };
+        let query = query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, value)) if self.covers(name) => {
+                    format!("{name}={}", self.redact(value))
+                }
+                _ => pair.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        format!("{path}?{query}")
+    }
+}
+
+/// A value in an audit event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Number(u64),
+    Bool(bool),
+    Object(Vec<(String, Value)>),
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(value: u16) -> Self {
+        Value::Number(value.into())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl Value {
+    fn write_json(&self, out: &mut String) {
+        match self {
+            Value::String(value) => write_string(out, value),
+            Value::Number(value) => write!(out, "{value}").unwrap(),
+            Value::Bool(value) => write!(out, "{value}").unwrap(),
+            Value::Object(fields) => {
+                out.push('{');
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_string(out, name);
+                    out.push(':');
+                    value.write_json(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+fn write_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// One audit event. Fields keep the order they were set in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    fields: Vec<(String, Value)>,
+}
+
+impl Event {
+    /// An event of the given kind concerning `peer`, stamped with the time
+    /// on `clock`.
+    pub fn new(kind: &str, peer: SocketAddr, clock: &dyn Clock) -> Event {
+        let mut event = Event { fields: Vec::new() };
+        event
+            .set("time", rfc3339(clock.system_time()))
+            .set("event", kind)
+            .set("peer", peer.to_string());
+        event
+    }
+
+    pub fn set(&mut self, name: &str, value: impl Into<Value>) -> &mut Event {
+        self.fields.push((name.to_string(), value.into()));
+        self
+    }
+
+    /// Record what the upgrade request says about the client: its target,
+    /// origin and user agent, and whichever credentials it carried, redacted.
+    pub fn request(&mut self, request: &Request<()>, redaction: &Redaction) -> &mut Event {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+        self.set("target", redaction.target(&request.uri().to_string()));
+        if let Some(origin) = header("Origin") {
+            self.set("origin", origin);
+        }
+        if let Some(user_agent) = header("User-Agent") {
+            self.set("user_agent", user_agent);
+        }
+        let credentials: Vec<_> = request
+            .headers()
+            .iter()
+            .filter(|(name, _)| redaction.covers(name.as_str()))
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value.as_bytes());
+                (name.to_string(), Value::from(redaction.redact(&value)))
+            })
+            .collect();
+        if !credentials.is_empty() {
+            self.set("credentials", Value::Object(credentials));
+        }
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        Value::Object(self.fields.clone()).write_json(&mut out);
+        out
+    }
+}
+
+/// A shared sink for audit events, kept apart from the debug output.
+pub struct AuditLog {
+    output: Mutex<Box<dyn Write + Send>>,
+    redaction: Redaction,
+}
+
+impl AuditLog {
+    /// Open a log target: `-` for stdout, otherwise a file to append to.
+    pub fn open(target: &str, redaction: Redaction) -> io::Result<AuditLog> {
+        let output: Box<dyn Write + Send> = if target == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(target)?)
+        };
+        Ok(AuditLog {
+            output: Mutex::new(output),
+            redaction,
+        })
+    }
+
+    pub fn redaction(&self) -> &Redaction {
+        &self.redaction
+    }
+
+    pub fn write(&self, event: &Event) {
+        let line = event.to_json();
+        let mut output = self.output.lock().unwrap();
+        // logging must never take a connection down
+        writeln!(output, "{line}").ok();
+        output.flush().ok();
+    }
+}
+
+/// Format a timestamp as `2000-10-10T13:55:36Z`.
+fn rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let seconds = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::VirtualClock;
+    use std::time::Duration;
+
+    fn hashed() -> Redaction {
+        Redaction {
+            hash: true,
+            ..Redaction::default()
+        }
+    }
+
+    #[test]
+    fn credentials_in_the_query_are_redacted() {
+        let redaction = Redaction::default();
+        assert_eq!(
+            redaction.target("/chat?token=abc&x=1"),
+            "/chat?token=[redacted]&x=1"
+        );
+        assert_eq!(redaction.target("/chat?x=1"), "/chat?x=1");
+        assert_eq!(redaction.target("/chat"), "/chat");
+        assert_eq!(
+            hashed().target("/chat?x=1&Token=abc"),
+            "/chat?x=1&Token=sha256:ba7816bf8f01cfea"
+        );
+    }
+
+    #[test]
+    fn digests_follow_the_value() {
+        let redaction = hashed();
+        // the first 8 bytes of SHA-256("abc")
+        assert_eq!(redaction.redact("abc"), "sha256:ba7816bf8f01cfea");
+        assert_eq!(redaction.redact("abc"), redaction.redact("abc"));
+        assert_ne!(redaction.redact("abc"), redaction.redact("abd"));
+    }
+
+    #[test]
+    fn request_credentials_are_redacted() {
+        let request = Request::builder()
+            .uri("/chat?access_token=abc")
+            .header("Origin", "https://example.com")
+            .header("Authorization", "Bearer abc")
+            .header("X-Api-Key", "abc")
+            .header("Accept", "*/*")
+            .body(())
+            .unwrap();
+        let mut redaction = Redaction::default();
+        redaction.add("X-Api-Key");
+        let mut event = Event { fields: Vec::new() };
+        event.request(&request, &redaction);
+        assert_eq!(
+            event.to_json(),
+            concat!(
+                r#"{"target":"/chat?access_token=[redacted]","origin":"https://example.com","#,
+                r#""credentials":{"authorization":"[redacted]","x-api-key":"[redacted]"}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn events_are_stamped_with_the_clock() {
+        let clock = VirtualClock::starting_at(UNIX_EPOCH + Duration::from_secs(971_186_136));
+        let event = Event::new("reaped", "127.0.0.1:4000".parse().unwrap(), &clock);
+        assert_eq!(
+            event.to_json(),
+            r#"{"time":"2000-10-10T13:55:36Z","event":"reaped","peer":"127.0.0.1:4000"}"#
+        );
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let mut out = String::new();
+        write_string(&mut out, "a\"b\\c\nd\re\tf\u{1}g\u{7f}h\u{85}κ");
+        assert_eq!(out, r#""a\"b\\c\nd\re\tf\u0001g\u007fh\u0085κ""#);
+    }
+}
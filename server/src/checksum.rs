@@ -0,0 +1,179 @@
+//! CRC32 trailer extension
+//!
+//! With `x-crc32-trailer` negotiated, both ends append the CRC32 (IEEE) of
+//! each data frame's payload to it, big endian, and check and strip it on the
+//! way in. It catches corruption on links TCP doesn't fully protect, such as a
+//! serial or radio bridge carrying the stream.
+
+use crate::error::{Error, Result};
+use crate::extension::{Extension, Metadata};
+use crate::frame::{Frame, OpCode};
+
+pub const NAME: &str = "x-crc32-trailer";
+
+/// Bytes the trailer adds to each data frame's payload.
+pub const TRAILER: usize = 4;
+
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// What to do with a frame whose trailer doesn't match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnMismatch {
+    /// Fail with `Error::Extension`, closing the connection.
+    Close,
+    /// Let the frame through flagged with `Corrupted`, for the handler to drop.
+    Drop,
+}
+
+/// Metadata marking a frame that failed its checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corrupted;
+
+pub struct Crc32Trailer {
+    on_mismatch: OnMismatch,
+}
+
+impl Crc32Trailer {
+    pub fn new(on_mismatch: OnMismatch) -> Crc32Trailer {
+        Crc32Trailer { on_mismatch }
+    }
+}
+
+impl Extension for Crc32Trailer {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn decode(&mut self, frame: &mut Frame, metadata: &mut Metadata) -> Result<()> {
+        if !matches!(frame.header().opcode, OpCode::Data(_)) {
+            return Ok(());
+        }
+        let payload = frame.payload_mut();
+        let valid = match payload.len().checked_sub(TRAILER) {
+            Some(end) => {
+                let trailer = u32::from_be_bytes(payload[end..].try_into().unwrap());
+                payload.truncate(end);
+                crc32(payload) == trailer
+            }
+            None => false,
+        };
+        match (valid, self.on_mismatch) {
+            (true, _) => Ok(()),
+            (false, OnMismatch::Drop) => {
+                metadata.insert(Corrupted);
+                Ok(())
+            }
+            (false, OnMismatch::Close) => Err(Error::Extension(String::from(
+                "frame failed its CRC32 check",
+            ))),
+        }
+    }
+
+    fn encode(&mut self, frame: &mut Frame) -> Result<()> {
+        if matches!(frame.header().opcode, OpCode::Data(_)) {
+            let crc = crc32(frame.payload());
+            frame.payload_mut().extend_from_slice(&crc.to_be_bytes());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{Control, Data};
+
+    fn text(payload: &[u8]) -> Frame {
+        Frame::message(payload.to_vec(), OpCode::Data(Data::Text))
+    }
+
+    #[test]
+    fn crc32_matches_the_ieee_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+
+    #[test]
+    fn trailer_round_trips() {
+        let mut trailer = Crc32Trailer::new(OnMismatch::Close);
+        let mut frame = text(b"123456789");
+        trailer.encode(&mut frame).unwrap();
+        assert_eq!(&frame.payload()[9..], &0xcbf4_3926u32.to_be_bytes());
+
+        let mut metadata = Metadata::new();
+        trailer.decode(&mut frame, &mut metadata).unwrap();
+        assert_eq!(frame.payload(), b"123456789");
+        assert!(metadata.get::<Corrupted>().is_none());
+    }
+
+    #[test]
+    fn mismatches_close_or_flag_the_frame() {
+        let mut corrupt = text(b"hello");
+        Crc32Trailer::new(OnMismatch::Close)
+            .encode(&mut corrupt)
+            .unwrap();
+        corrupt.payload_mut()[0] ^= 1;
+
+        let mut metadata = Metadata::new();
+        assert!(matches!(
+            Crc32Trailer::new(OnMismatch::Close).decode(&mut corrupt.clone(), &mut metadata),
+            Err(Error::Extension(_))
+        ));
+
+        let mut metadata = Metadata::new();
+        Crc32Trailer::new(OnMismatch::Drop)
+            .decode(&mut corrupt, &mut metadata)
+            .unwrap();
+        assert_eq!(metadata.get::<Corrupted>(), Some(&Corrupted));
+    }
+
+    #[test]
+    fn frames_too_short_for_a_trailer_fail_it() {
+        let mut metadata = Metadata::new();
+        let mut frame = text(b"abc");
+        Crc32Trailer::new(OnMismatch::Drop)
+            .decode(&mut frame, &mut metadata)
+            .unwrap();
+        assert!(metadata.get::<Corrupted>().is_some());
+    }
+
+    #[test]
+    fn control_frames_carry_no_trailer() {
+        let mut trailer = Crc32Trailer::new(OnMismatch::Close);
+        let mut ping = Frame::message(b"hi".to_vec(), OpCode::Control(Control::Ping));
+        trailer.encode(&mut ping).unwrap();
+        assert_eq!(ping.payload(), b"hi");
+        trailer.decode(&mut ping, &mut Metadata::new()).unwrap();
+        assert_eq!(ping.payload(), b"hi");
+    }
+}
@@ -0,0 +1,295 @@
+//! WebSocket client connections
+
+use crate::affinity::constant_time_eq;
+use crate::error::{Error, Result};
+use crate::frame::{Control, Frame, OpCode};
+use crate::handshake::accept_key;
+use crate::reaper::Liveness;
+use crate::sim::{Clock, Rng, Sources};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+use std::ptr;
+use std::sync::atomic::{self, AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Payload of the keepalive's Pings, so their Pongs can be told apart from
+/// those answering `WebSocket::ping`, whose payloads are random.
+const KEEPALIVE_PAYLOAD: &[u8] = b"keepalive";
+
+/// Handshake secrets, zeroed when dropped so the nonce doesn't linger in
+/// memory however the handshake ends.
+struct Scrubbed(Vec<u8>);
+
+impl Drop for Scrubbed {
+    fn drop(&mut self) {
+        for byte in &mut self.0 {
+            // volatile, so the writes aren't optimized away as dead stores
+            unsafe { ptr::write_volatile(byte, 0) };
+        }
+        atomic::compiler_fence(Ordering::SeqCst);
+    }
+}
+
+/// How a client keeps an idle connection alive and notices it died, for
+/// NAT mappings and middleboxes that drop idle connections without a word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Ping the server after hearing nothing from it for this long.
+    pub interval: Duration,
+    /// Pings that may go unanswered before the connection counts as stale.
+    pub max_missed: u32,
+}
+
+/// A client connection that completed the opening handshake.
+pub struct WebSocket {
+    reader: BufReader<TcpStream>,
+    /// Shared with the keepalive watchdog, so frames don't interleave.
+    stream: Arc<Mutex<TcpStream>>,
+    broken: bool,
+    rng: Arc<dyn Rng>,
+    clock: Arc<dyn Clock>,
+    /// What `set_read_timeout` was last asked for, on `clock`.
+    read_timeout: Option<Duration>,
+    liveness: Arc<Liveness>,
+    stale: Arc<AtomicBool>,
+    watchdog: Option<Watchdog>,
+}
+
+/// The thread pinging on behalf of a connection, stopped when dropped.
+struct Watchdog {
+    stop: mpsc::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.send(()).ok();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Mask and write one frame.
+fn write_frame(stream: &Mutex<TcpStream>, rng: &dyn Rng, mut frame: Frame) -> Result<()> {
+    frame.header_mut().set_mask(rng);
+    let mut buffer = Vec::new();
+    frame
+        .format(&mut buffer)
+        .map_err(|error| Error::Protocol(error.to_string()))?;
+    Ok(stream.lock().unwrap().write_all(&buffer)?)
+}
+
+impl WebSocket {
+    /// Connect to `addr` and upgrade the connection at `path`.
+    pub fn connect(addr: &str, path: &str) -> Result<WebSocket> {
+        WebSocket::connect_with(addr, path, Sources::default())
+    }
+
+    /// Like `connect`, drawing the handshake key, masks and ping payloads
+    /// from `sources.rng` and keeping time on `sources.clock`.
+    pub fn connect_with(addr: &str, path: &str, sources: Sources) -> Result<WebSocket> {
+        let Sources { clock, rng } = sources;
+        let mut stream = TcpStream::connect(addr)?;
+        let mut nonce = Scrubbed(vec![0; 16]);
+        rng.fill(&mut nonce.0);
+        let key = Scrubbed(base64::encode(&nonce.0).into_bytes());
+        let expected = Scrubbed(accept_key(&key.0).into_bytes());
+        // sized up front, a reallocation would leave a copy of the key behind
+        let mut request = Scrubbed(Vec::with_capacity(256 + path.len() + addr.len()));
+        write!(
+            request.0,
+            "GET {path} HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            String::from_utf8_lossy(&key.0)
+        )?;
+        stream.write_all(&request.0)?;
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut status = String::new();
+        reader.read_line(&mut status)?;
+        if !status.starts_with("HTTP/1.1 101") {
+            return Err(Error::Handshake(format!(
+                "server refused the upgrade: {}",
+                status.trim_end()
+            )));
+        }
+
+        let mut accept = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(Error::Handshake(String::from("response ended early")));
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("Sec-WebSocket-Accept") {
+                    accept = Some(value.trim().as_bytes().to_vec());
+                }
+            }
+        }
+        // the response is thrown away unread past its head, so a server that
+        // fails this check never gets a frame processed
+        if !accept.is_some_and(|accept| constant_time_eq(&accept, &expected.0)) {
+            return Err(Error::Handshake(String::from(
+                "wrong Sec-WebSocket-Accept in response",
+            )));
+        }
+
+        Ok(WebSocket {
+            reader,
+            stream: Arc::new(Mutex::new(stream)),
+            broken: false,
+            rng,
+            liveness: Arc::new(Liveness::new(clock.clone())),
+            clock,
+            read_timeout: None,
+            stale: Arc::default(),
+            watchdog: None,
+        })
+    }
+
+    /// Keep the connection alive from now on: Ping the server whenever it has
+    /// been silent for `keepalive.interval`, and once more than
+    /// `keepalive.max_missed` Pings in a row went unanswered, shut the
+    /// connection down as stale. Pending and later reads then fail with
+    /// `Error::Stale`. Replaces any keepalive set before.
+    ///
+    /// Pongs count as they are read, so an idle connection should be waiting
+    /// in `read`.
+    pub fn keepalive(&mut self, keepalive: Keepalive) {
+        self.watchdog = None;
+        let (stop, stopped) = mpsc::channel();
+        let stream = self.stream.clone();
+        let rng = self.rng.clone();
+        let clock = self.clock.clone();
+        let liveness = self.liveness.clone();
+        let stale = self.stale.clone();
+        let thread = thread::spawn(move || {
+            let limit = keepalive.interval * (keepalive.max_missed + 1);
+            let wait = clock.real_timeout(keepalive.interval);
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(wait) {
+                clock.timed_out(keepalive.interval);
+                let silent = liveness.silent_for();
+                if silent >= limit {
+                    stale.store(true, Ordering::Release);
+                    stream.lock().unwrap().shutdown(Shutdown::Both).ok();
+                    return;
+                }
+                if silent >= keepalive.interval {
+                    let ping =
+                        Frame::message(KEEPALIVE_PAYLOAD.to_vec(), OpCode::Control(Control::Ping));
+                    if write_frame(&stream, &*rng, ping).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        self.watchdog = Some(Watchdog {
+            stop,
+            thread: Some(thread),
+        });
+    }
+
+    /// Send a frame, masked as the protocol requires of clients.
+    pub fn send(&mut self, frame: Frame) -> Result<()> {
+        let result = write_frame(&self.stream, &*self.rng, frame);
+        self.broken |= result.is_err();
+        result
+    }
+
+    /// Write bytes as they are, for sending frames that break the protocol.
+    pub(crate) fn send_raw(&mut self, bytes: &[u8]) -> Result<()> {
+        let result = self.stream.lock().unwrap().write_all(bytes);
+        self.broken |= result.is_err();
+        Ok(result?)
+    }
+
+    /// Make `read` fail with a timeout after waiting this long, or never with
+    /// `None`.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        let real = timeout.map(|timeout| self.clock.real_timeout(timeout));
+        self.reader.get_ref().set_read_timeout(real)?;
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Read the next frame. `None` means the server closed the connection.
+    /// Pongs answering the keepalive's Pings are not handed out.
+    pub fn read(&mut self) -> Result<Option<Frame>> {
+        loop {
+            let result = Frame::parse(&mut self.reader);
+            if self.is_stale() {
+                self.broken = true;
+                return Err(Error::Stale);
+            }
+            self.broken |= !matches!(result, Ok(Some(_)));
+            let frame = result.map_err(|error| match error.downcast::<std::io::Error>() {
+                Ok(error) => {
+                    let timed_out = matches!(
+                        error.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    );
+                    if let Some(timeout) = self.read_timeout.filter(|_| timed_out) {
+                        self.clock.timed_out(timeout);
+                    }
+                    Error::Io(*error)
+                }
+                Err(error) => Error::Protocol(error.to_string()),
+            })?;
+            if let Some(frame) = &frame {
+                self.liveness.heard();
+                if frame.header().opcode == OpCode::Control(Control::Pong)
+                    && frame.payload() == KEEPALIVE_PAYLOAD
+                {
+                    continue;
+                }
+            }
+            return Ok(frame);
+        }
+    }
+
+    /// Send a Ping and wait up to `timeout` for the matching Pong.
+    pub fn ping(&mut self, timeout: Duration) -> Result<()> {
+        let mut payload = vec![0; 8];
+        self.rng.fill(&mut payload);
+        self.send(Frame::message(
+            payload.clone(),
+            OpCode::Control(Control::Ping),
+        ))?;
+
+        self.set_read_timeout(Some(timeout))?;
+        let reply = self.read();
+        self.set_read_timeout(None)?;
+        match reply? {
+            Some(frame)
+                if frame.header().opcode == OpCode::Control(Control::Pong)
+                    && frame.payload() == payload.as_slice() =>
+            {
+                Ok(())
+            }
+            _ => {
+                self.broken = true;
+                Err(Error::Protocol(String::from(
+                    "expected a Pong for our Ping",
+                )))
+            }
+        }
+    }
+
+    /// Whether a previous send or read failed, or the keepalive found the
+    /// connection stale, leaving it unusable.
+    pub fn is_broken(&self) -> bool {
+        self.broken || self.is_stale()
+    }
+
+    /// Whether the keepalive gave up on the server, see `keepalive`.
+    pub fn is_stale(&self) -> bool {
+        self.stale.load(Ordering::Acquire)
+    }
+}
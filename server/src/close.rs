@@ -0,0 +1,325 @@
+//! Close codes for errors and applications
+
+use crate::error::{Error, Result};
+use crate::frame::{Control, Frame, OpCode};
+use std::collections::HashMap;
+
+/// Longest close reason that fits in a control frame next to the code.
+const MAX_REASON: usize = 123;
+
+/// Names of the codes RFC 6455 and the IANA registry define.
+const STANDARD: [(u16, &str); 14] = [
+    (1000, "normal closure"),
+    (1001, "going away"),
+    (1002, "protocol error"),
+    (1003, "unsupported data"),
+    (1005, "no status received"),
+    (1006, "abnormal closure"),
+    (1007, "invalid payload data"),
+    (1008, "policy violation"),
+    (1009, "message too big"),
+    (1010, "mandatory extension"),
+    (1011, "internal error"),
+    (1012, "service restart"),
+    (1013, "try again later"),
+    (1014, "bad gateway"),
+];
+
+/// Whether an endpoint may put `code` in a Close frame. 1005, 1006 and 1015
+/// only stand in for a missing code locally, and the rest of 0–2999 is
+/// reserved for the protocol.
+pub fn sendable(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// The status code of the peer's Close frame, `None` if it carries none. A
+/// payload too short to hold a code or a code no endpoint may send fails with
+/// `Error::Protocol`, a reason that isn't UTF-8 with `Error::Utf8`.
+pub fn status(close: &Frame) -> Result<Option<u16>> {
+    let payload = close.payload();
+    if payload.is_empty() {
+        return Ok(None);
+    }
+    if payload.len() == 1 {
+        return Err(Error::Protocol(String::from(
+            "Close frame with a 1-byte payload",
+        )));
+    }
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    if !sendable(code) {
+        return Err(Error::Protocol(format!("invalid close code {code}")));
+    }
+    std::str::from_utf8(&payload[2..]).map_err(|_| Error::Utf8)?;
+    Ok(Some(code))
+}
+
+/// The answer to a valid Close from the peer: its status code echoed, as the
+/// closing handshake asks, or an empty Close if it had none.
+pub fn echo(code: Option<u16>) -> Frame {
+    match code {
+        Some(code) => Frame::close(code, ""),
+        None => Frame::message(Vec::new(), OpCode::Control(Control::Close)),
+    }
+}
+
+/// Cut a close reason down to what fits in a control frame.
+fn truncate(reason: &str) -> &str {
+    let mut end = reason.len().min(MAX_REASON);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    &reason[..end]
+}
+
+/// A close code in the 4000–4999 range RFC 6455 leaves to applications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AppCode(u16);
+
+impl AppCode {
+    pub fn new(code: u16) -> Result<AppCode> {
+        match code {
+            4000..=4999 => Ok(AppCode(code)),
+            _ => Err(Error::CloseCode(code)),
+        }
+    }
+
+    pub fn code(self) -> u16 {
+        self.0
+    }
+
+    /// A Close frame with this code, the reason truncated to fit.
+    pub fn frame(self, reason: &str) -> Frame {
+        Frame::close(self.0, truncate(reason))
+    }
+}
+
+/// Names of close codes, for logs and metrics. Starts out knowing the
+/// standard codes, applications register their own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseCodes {
+    names: HashMap<u16, String>,
+}
+
+impl Default for CloseCodes {
+    fn default() -> Self {
+        CloseCodes {
+            names: STANDARD
+                .iter()
+                .map(|&(code, name)| (code, name.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl CloseCodes {
+    /// Name an application code. Each code can only be named once.
+    pub fn register(&mut self, code: AppCode, name: &str) -> Result<()> {
+        if self.names.contains_key(&code.0) {
+            return Err(Error::Config(format!(
+                "close code {} is already named",
+                code.0
+            )));
+        }
+        self.names.insert(code.0, name.to_string());
+        Ok(())
+    }
+
+    pub fn name(&self, code: u16) -> Option<&str> {
+        self.names.get(&code).map(String::as_str)
+    }
+
+    /// The code followed by its name, if it has one, e.g. `1008 policy
+    /// violation`.
+    pub fn describe(&self, code: u16) -> String {
+        match self.name(code) {
+            Some(name) => format!("{code} {name}"),
+            None => code.to_string(),
+        }
+    }
+}
+
+/// Which close code the server sends for each kind of error. The defaults are
+/// the codes RFC 6455 defines for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClosePolicy {
+    /// Malformed frames and broken sub-protocols, 1002.
+    pub protocol: u16,
+    /// Data the endpoint doesn't accept at all, such as Text on a Binary only
+    /// protocol, 1003.
+    pub unsupported: u16,
+    /// Text that isn't UTF-8, 1007.
+    pub utf8: u16,
+    /// Messages refused by validation or other policy, 1008.
+    pub policy: u16,
+    /// Messages too big to process, 1009.
+    pub capacity: u16,
+    /// Failures of the handler itself, 1011.
+    pub handler: u16,
+}
+
+impl Default for ClosePolicy {
+    fn default() -> Self {
+        ClosePolicy {
+            protocol: 1002,
+            unsupported: 1003,
+            utf8: 1007,
+            policy: 1008,
+            capacity: 1009,
+            handler: 1011,
+        }
+    }
+}
+
+impl ClosePolicy {
+    /// The close code for an error.
+    pub fn code(&self, error: &Error) -> u16 {
+        match error {
+            Error::Protocol(_)
+            | Error::Extension(_)
+            | Error::Mux(_)
+            | Error::SubFrame(_)
+            | Error::SubFrameVersion(_) => self.protocol,
+            Error::Unsupported(_) => self.unsupported,
+            Error::Utf8 => self.utf8,
+            Error::Policy(_) | Error::InvalidAffinity | Error::WrongInstance(_) => self.policy,
+            Error::Capacity(_) => self.capacity,
+            _ => self.handler,
+        }
+    }
+
+    /// The Close frame for an error, with the error as the reason.
+    pub fn frame(&self, error: &Error) -> Frame {
+        Frame::close(self.code(error), truncate(&error.to_string()))
+    }
+
+    /// Change the code for one kind of error: `protocol`, `unsupported`,
+    /// `utf8`, `policy`, `capacity` or `handler`. The code must be one an
+    /// endpoint may send.
+    pub fn set(&mut self, kind: &str, code: u16) -> Result<()> {
+        if !sendable(code) {
+            return Err(Error::Config(Error::CloseCode(code).to_string()));
+        }
+        let slot = match kind {
+            "protocol" => &mut self.protocol,
+            "unsupported" => &mut self.unsupported,
+            "utf8" => &mut self.utf8,
+            "policy" => &mut self.policy,
+            "capacity" => &mut self.capacity,
+            "handler" => &mut self.handler,
+            other => return Err(Error::Config(format!("unknown error kind {other}"))),
+        };
+        *slot = code;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_codes_endpoints_may_use_are_sendable() {
+        for code in [1000, 1003, 1007, 1014, 3000, 4999] {
+            assert!(sendable(code), "{code}");
+        }
+        for code in [0, 999, 1004, 1005, 1006, 1015, 2999, 5000] {
+            assert!(!sendable(code), "{code}");
+        }
+    }
+
+    #[test]
+    fn reasons_are_cut_on_a_character_boundary() {
+        let reason = "é".repeat(100);
+        let cut = truncate(&reason);
+        assert_eq!(cut.len(), MAX_REASON - 1);
+        assert_eq!(truncate("short"), "short");
+    }
+
+    #[test]
+    fn application_codes_are_4000_to_4999() {
+        assert_eq!(AppCode::new(4000).unwrap().code(), 4000);
+        assert!(matches!(AppCode::new(3999), Err(Error::CloseCode(3999))));
+        assert!(matches!(AppCode::new(5000), Err(Error::CloseCode(5000))));
+
+        let frame = AppCode::new(4321).unwrap().frame(&"x".repeat(200));
+        assert_eq!(&frame.payload()[..2], &4321u16.to_be_bytes());
+        assert_eq!(frame.payload().len(), 2 + MAX_REASON);
+    }
+
+    #[test]
+    fn codes_are_described_by_name_once_registered() {
+        let mut codes = CloseCodes::default();
+        assert_eq!(codes.describe(1008), "1008 policy violation");
+        assert_eq!(codes.describe(4001), "4001");
+
+        let code = AppCode::new(4001).unwrap();
+        codes.register(code, "quota exceeded").unwrap();
+        assert_eq!(codes.describe(4001), "4001 quota exceeded");
+        assert!(matches!(
+            codes.register(code, "again"),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn errors_map_to_the_rfc_codes_by_default() {
+        let policy = ClosePolicy::default();
+        let cases = [
+            (Error::Protocol(String::new()), 1002),
+            (Error::Mux(String::new()), 1002),
+            (Error::Utf8, 1007),
+            (Error::Policy(String::new()), 1008),
+            (Error::Capacity(String::new()), 1009),
+            (Error::Timeout, 1011),
+        ];
+        for (error, code) in cases {
+            assert_eq!(policy.code(&error), code, "{error}");
+        }
+
+        let frame = policy.frame(&Error::Policy(String::from("no")));
+        assert_eq!(&frame.payload()[..2], &1008u16.to_be_bytes());
+    }
+
+    #[test]
+    fn policy_codes_can_be_changed_to_sendable_ones() {
+        let mut policy = ClosePolicy::default();
+        policy.set("policy", 4008).unwrap();
+        assert_eq!(policy.code(&Error::Policy(String::new())), 4008);
+        assert!(matches!(policy.set("policy", 1005), Err(Error::Config(_))));
+        assert!(matches!(
+            policy.set("nonsense", 4000),
+            Err(Error::Config(_))
+        ));
+    }
+
+    fn close(payload: &[u8]) -> Frame {
+        Frame::message(payload.to_vec(), OpCode::Control(Control::Close))
+    }
+
+    #[test]
+    fn status_reads_valid_closes() {
+        assert_eq!(status(&close(b"")).unwrap(), None);
+        assert_eq!(status(&Frame::close(1000, "bye")).unwrap(), Some(1000));
+        assert_eq!(status(&Frame::close(4000, "κόσμε")).unwrap(), Some(4000));
+    }
+
+    #[test]
+    fn status_refuses_closes_that_break_the_rules() {
+        assert!(matches!(status(&close(&[3])), Err(Error::Protocol(_))));
+        for code in [999, 1005, 1006, 5000] {
+            assert!(matches!(
+                status(&Frame::close(code, "")),
+                Err(Error::Protocol(_))
+            ));
+        }
+        let mut payload = 1000u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"\xce\xba\xff");
+        assert!(matches!(status(&close(&payload)), Err(Error::Utf8)));
+    }
+
+    #[test]
+    fn echo_repeats_the_code() {
+        assert_eq!(echo(Some(1001)), Frame::close(1001, ""));
+        assert_eq!(echo(None), close(b""));
+    }
+}
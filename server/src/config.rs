@@ -0,0 +1,523 @@
+//! Server configuration
+
+use crate::affinity::{Affinity, Carrier};
+use crate::audit::Redaction;
+use crate::checksum::OnMismatch;
+use crate::close::{AppCode, CloseCodes, ClosePolicy};
+use crate::dedupe::{self, Dedupe};
+use crate::egress::TokenBucket;
+use crate::error::{Error, Result};
+use crate::frame::Data;
+use crate::handshake::Checks;
+use crate::lifetime::Lifetime;
+use crate::mux::{DEFAULT_MAX_CHANNELS, DEFAULT_WINDOW};
+use crate::reaper::Keepalive;
+use crate::sim::{SeededRng, Sources, VirtualClock};
+use crate::throttle::{OverLimit, Throttle, Warmup};
+use crate::transfer;
+use crate::validate::{Reject, Reserved, Validator};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Runtime settings, taken from the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Address to listen on.
+    pub addr: String,
+    /// Where to write access log lines: `-` for stdout or a file path.
+    pub access_log: Option<String>,
+    /// Where to write audit events: `-` for stdout or a file path.
+    pub audit_log: Option<String>,
+    /// Headers and query parameters redacted in audit events, besides the
+    /// usual credential carriers.
+    pub audit_redact: Vec<String>,
+    /// Replace redacted values with a digest rather than a placeholder.
+    pub audit_hash: bool,
+    /// Identifier of this instance, used in affinity tokens.
+    pub instance_id: Option<String>,
+    /// Secret shared by all instances to sign affinity tokens. Affinity is
+    /// only enabled when both this and `instance_id` are set.
+    pub affinity_secret: Option<String>,
+    /// Carry the affinity token in a header instead of a cookie.
+    pub affinity_header: bool,
+    /// Refuse upgrades whose `Sec-WebSocket-Key` isn't 16 bytes in base64.
+    pub strict_key: bool,
+    /// Host names served. Upgrades for other hosts are refused when any are
+    /// given.
+    pub virtual_hosts: Vec<String>,
+    /// Origins upgrades may come from. Upgrades from others, or without an
+    /// `Origin` header, are refused when any are given.
+    pub origins: Vec<String>,
+    /// Largest data message accepted, in bytes.
+    pub max_message_size: Option<usize>,
+    /// Most frames one message may be split over.
+    pub max_fragments: Option<usize>,
+    /// Longest a fragmented message may take to finish.
+    pub max_reassembly: Option<Duration>,
+    /// Data opcodes accepted per request path.
+    pub allowed_opcodes: Vec<(String, Vec<Data>)>,
+    /// What to do with frames using reserved opcodes.
+    pub reserved: Reserved,
+    /// Request paths answered with only the required 101 headers.
+    pub minimal_response: Vec<String>,
+    /// Request paths whose echoes are followed by the server's timestamps.
+    pub timestamps: Vec<String>,
+    /// Drop messages whose id is among the last this many on the connection.
+    pub dedupe: Option<usize>,
+    /// The id of a message is what comes before this byte.
+    pub dedupe_separator: u8,
+    /// What to do with messages that fail validation.
+    pub reject: Reject,
+    /// Offer CRC32 trailers on data frames, and what to do with frames that
+    /// fail them.
+    pub crc32_trailer: Option<OnMismatch>,
+    /// Speak the mux layer, carrying several channels per connection.
+    pub mux: bool,
+    /// Initial flow control window of each mux channel, in bytes.
+    pub mux_window: u32,
+    /// Most channels the peer may have open at once on one mux connection.
+    pub mux_channels: usize,
+    /// Accept file transfers into this directory.
+    pub receive_dir: Option<PathBuf>,
+    /// Largest file accepted into `receive_dir`.
+    pub receive_max: u64,
+    /// Most handshakes accepted from one address per `handshake_window`.
+    pub handshake_limit_ip: Option<usize>,
+    /// Most handshakes accepted overall per `handshake_window`.
+    pub handshake_limit: Option<usize>,
+    /// Sliding window the handshake limits apply to.
+    pub handshake_window: Duration,
+    /// What to do with connections over a handshake limit.
+    pub over_limit: OverLimit,
+    /// Handshakes per second let in right after start, ramping up to
+    /// `warmup_peak` over `warmup`.
+    pub warmup_rate: Option<u32>,
+    /// Handshakes per second let in at the end of the warm-up, ten times
+    /// `warmup_rate` by default.
+    pub warmup_peak: Option<u32>,
+    /// How long handshakes are paced after start.
+    pub warmup: Duration,
+    /// Close connections with 1012 once they have been open this long.
+    pub max_lifetime: Option<Duration>,
+    /// Most time taken off `max_lifetime` at random, a tenth of it by default.
+    pub lifetime_jitter: Option<Duration>,
+    /// Close codes sent for each kind of error.
+    pub close_policy: ClosePolicy,
+    /// Names of close codes, including the application's own.
+    pub close_codes: CloseCodes,
+    /// Draw random values from a generator seeded with this, to reproduce a
+    /// run.
+    pub seed: Option<u64>,
+    /// Run on a `VirtualClock`, skipping the time the server would spend
+    /// waiting.
+    pub virtual_time: bool,
+    /// Most bytes per second sent to each connection.
+    pub egress_rate: Option<u64>,
+    /// Bytes a connection may be sent in one burst, one second's worth of
+    /// `egress_rate` by default.
+    pub egress_burst: Option<u64>,
+    /// How long a mux writer busy-polls its queue before sleeping.
+    pub writer_spin: Duration,
+    /// Idle time before TCP keepalive probes start.
+    pub keepalive: Option<Duration>,
+    /// Fail writes that make no progress for this long.
+    pub write_timeout: Option<Duration>,
+    /// Ping connections that have been quiet this long.
+    pub ping_interval: Option<Duration>,
+    /// How long a pinged connection may stay silent before it is reaped.
+    pub pong_timeout: Duration,
+    /// Keep per-stage read path timings in this file, in folded stack
+    /// notation.
+    #[cfg(feature = "profile")]
+    pub profile: Option<PathBuf>,
+    /// Keep the heap usage of every connection in this file.
+    #[cfg(feature = "alloc-accounting")]
+    pub memory_report: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addr: String::from("0.0.0.0:3333"),
+            access_log: None,
+            audit_log: None,
+            audit_redact: Vec::new(),
+            audit_hash: false,
+            instance_id: None,
+            affinity_secret: None,
+            affinity_header: false,
+            strict_key: false,
+            virtual_hosts: Vec::new(),
+            origins: Vec::new(),
+            max_message_size: None,
+            max_fragments: None,
+            max_reassembly: None,
+            allowed_opcodes: Vec::new(),
+            minimal_response: Vec::new(),
+            timestamps: Vec::new(),
+            reserved: Reserved::Fail,
+            dedupe: None,
+            dedupe_separator: b':',
+            reject: Reject::Close,
+            crc32_trailer: None,
+            mux: false,
+            mux_window: DEFAULT_WINDOW,
+            mux_channels: DEFAULT_MAX_CHANNELS,
+            receive_dir: None,
+            receive_max: transfer::DEFAULT_MAX_SIZE,
+            handshake_limit_ip: None,
+            handshake_limit: None,
+            handshake_window: Duration::from_secs(10),
+            over_limit: OverLimit::Reply,
+            warmup_rate: None,
+            warmup_peak: None,
+            warmup: Duration::from_secs(30),
+            max_lifetime: None,
+            lifetime_jitter: None,
+            close_policy: ClosePolicy::default(),
+            close_codes: CloseCodes::default(),
+            seed: None,
+            virtual_time: false,
+            egress_rate: None,
+            egress_burst: None,
+            writer_spin: Duration::ZERO,
+            keepalive: None,
+            write_timeout: None,
+            ping_interval: None,
+            pong_timeout: Duration::from_secs(10),
+            #[cfg(feature = "profile")]
+            profile: None,
+            #[cfg(feature = "alloc-accounting")]
+            memory_report: None,
+        }
+    }
+}
+
+impl Config {
+    /// Build a configuration from command line arguments, without the program
+    /// name. The first positional argument is the listen address.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| Error::Config(format!("{arg} needs a value")))
+            };
+            match arg.as_str() {
+                "--access-log" => config.access_log = Some(value()?),
+                "--audit-log" => config.audit_log = Some(value()?),
+                "--audit-redact" => config.audit_redact.push(value()?),
+                "--audit-hash" => config.audit_hash = true,
+                "--instance-id" => config.instance_id = Some(value()?),
+                "--affinity-secret" => config.affinity_secret = Some(value()?),
+                "--affinity-header" => config.affinity_header = true,
+                "--strict-key" => config.strict_key = true,
+                "--virtual-host" => config.virtual_hosts.push(value()?),
+                "--origin" => config.origins.push(value()?),
+                "--mux" => config.mux = true,
+                "--mux-window" => config.mux_window = nonzero(&arg, value()?)?,
+                "--mux-channels" => config.mux_channels = nonzero(&arg, value()?)?,
+                "--receive-dir" => config.receive_dir = Some(PathBuf::from(value()?)),
+                "--receive-max" => config.receive_max = parse(&arg, value()?)?,
+                "--max-message-size" => config.max_message_size = Some(parse(&arg, value()?)?),
+                "--max-fragments" => config.max_fragments = Some(parse(&arg, value()?)?),
+                "--max-reassembly" => config.max_reassembly = Some(seconds(&arg, value()?)?),
+                "--dedupe" => config.dedupe = Some(parse(&arg, value()?)?),
+                "--dedupe-separator" => {
+                    config.dedupe_separator = match value()?.as_bytes() {
+                        &[separator] => separator,
+                        _ => {
+                            return Err(Error::Config(String::from(
+                                "--dedupe-separator takes a single byte",
+                            )))
+                        }
+                    }
+                }
+                "--allow" => config.allowed_opcodes.push(parse_allow(&value()?)?),
+                "--minimal-response" => config.minimal_response.push(value()?),
+                "--timestamps" => config.timestamps.push(value()?),
+                "--handshake-limit-ip" => config.handshake_limit_ip = Some(parse(&arg, value()?)?),
+                "--handshake-limit" => config.handshake_limit = Some(parse(&arg, value()?)?),
+                "--handshake-window" => config.handshake_window = seconds(&arg, value()?)?,
+                "--warmup-rate" => config.warmup_rate = Some(parse(&arg, value()?)?),
+                "--warmup-peak" => config.warmup_peak = Some(parse(&arg, value()?)?),
+                "--warmup" => config.warmup = seconds(&arg, value()?)?,
+                "--max-lifetime" => config.max_lifetime = Some(seconds(&arg, value()?)?),
+                "--lifetime-jitter" => config.lifetime_jitter = Some(seconds(&arg, value()?)?),
+                "--egress-rate" => config.egress_rate = Some(nonzero(&arg, value()?)?),
+                "--egress-burst" => config.egress_burst = Some(parse(&arg, value()?)?),
+                "--writer-spin" => {
+                    config.writer_spin = Duration::from_micros(parse(&arg, value()?)?)
+                }
+                "--keepalive" => config.keepalive = Some(seconds(&arg, value()?)?),
+                "--write-timeout" => config.write_timeout = Some(seconds(&arg, value()?)?),
+                "--ping-interval" => {
+                    config.ping_interval = Some(nonzero(&arg, value()?).map(Duration::from_secs)?)
+                }
+                "--pong-timeout" => config.pong_timeout = seconds(&arg, value()?)?,
+                #[cfg(feature = "profile")]
+                "--profile" => config.profile = Some(PathBuf::from(value()?)),
+                #[cfg(feature = "alloc-accounting")]
+                "--memory-report" => config.memory_report = Some(PathBuf::from(value()?)),
+                "--seed" => config.seed = Some(parse(&arg, value()?)?),
+                "--virtual-time" => config.virtual_time = true,
+                "--close-code" => {
+                    let value = value()?;
+                    let (kind, code) = value.split_once('=').ok_or_else(|| {
+                        Error::Config(format!("--close-code expects KIND=CODE, not {value}"))
+                    })?;
+                    config
+                        .close_policy
+                        .set(kind, parse(&arg, code.to_string())?)?;
+                }
+                "--close-name" => {
+                    let value = value()?;
+                    let (code, name) = value.split_once('=').ok_or_else(|| {
+                        Error::Config(format!("--close-name expects CODE=NAME, not {value}"))
+                    })?;
+                    let code: u16 = parse(&arg, code.to_string())?;
+                    let code = AppCode::new(code).map_err(|_| {
+                        Error::Config(format!("--close-name takes codes 4000 to 4999, not {code}"))
+                    })?;
+                    config.close_codes.register(code, name)?;
+                }
+                "--over-limit" => {
+                    config.over_limit = match value()?.as_str() {
+                        "reset" => OverLimit::Reset,
+                        "reply" => OverLimit::Reply,
+                        other => {
+                            return Err(Error::Config(format!(
+                                "--over-limit must be reset or reply, not {other}"
+                            )))
+                        }
+                    }
+                }
+                "--crc32-trailer" => {
+                    config.crc32_trailer = match value()?.as_str() {
+                        "close" => Some(OnMismatch::Close),
+                        "drop" => Some(OnMismatch::Drop),
+                        other => {
+                            return Err(Error::Config(format!(
+                                "--crc32-trailer must be close or drop, not {other}"
+                            )))
+                        }
+                    }
+                }
+                "--reserved" => {
+                    config.reserved = match value()?.as_str() {
+                        "fail" => Reserved::Fail,
+                        "drop" => Reserved::Drop,
+                        "deliver" => Reserved::Deliver,
+                        other => {
+                            return Err(Error::Config(format!(
+                                "--reserved must be fail, drop or deliver, not {other}"
+                            )))
+                        }
+                    }
+                }
+                "--reject" => {
+                    config.reject = match value()?.as_str() {
+                        "drop" => Reject::Drop,
+                        "reply" => Reject::Reply,
+                        "close" => Reject::Close,
+                        other => {
+                            return Err(Error::Config(format!(
+                                "--reject must be drop, reply or close, not {other}"
+                            )))
+                        }
+                    }
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(Error::Config(format!("unknown option {flag}")))
+                }
+                _ => config.addr = arg,
+            }
+        }
+        Ok(config)
+    }
+
+    /// Checks applied to upgrade requests.
+    pub fn checks(&self) -> Checks {
+        Checks {
+            key: self.strict_key,
+            hosts: self.virtual_hosts.clone(),
+            origins: self.origins.clone(),
+        }
+    }
+
+    /// The inbound message validator described by these settings.
+    pub fn validator(&self) -> Validator {
+        let mut validator = Validator::default();
+        validator.max_size = self.max_message_size;
+        validator.max_fragments = self.max_fragments;
+        validator.max_reassembly = self.max_reassembly;
+        validator.allowed = self.allowed_opcodes.clone();
+        validator.reject = self.reject;
+        validator.reserved = self.reserved;
+        validator.dedupe = self
+            .dedupe
+            .map(|capacity| Dedupe::new(capacity, dedupe::prefix_id(self.dedupe_separator)));
+        validator
+    }
+
+    /// How audit events hide credentials.
+    pub fn redaction(&self) -> Redaction {
+        let mut redaction = Redaction::default();
+        for name in &self.audit_redact {
+            redaction.add(name);
+        }
+        redaction.hash = self.audit_hash;
+        redaction
+    }
+
+    /// Handshake rate limits, when any are set.
+    pub fn throttle(&self) -> Option<Throttle> {
+        if self.handshake_limit_ip.is_none() && self.handshake_limit.is_none() {
+            return None;
+        }
+        Some(Throttle::new(
+            self.handshake_limit_ip,
+            self.handshake_limit,
+            self.handshake_window,
+        ))
+    }
+
+    /// Handshake pacing from `started` on, if a warm-up rate is set.
+    pub fn warmup(&self, started: Instant) -> Option<Warmup> {
+        self.warmup_rate.map(|rate| {
+            let peak = self.warmup_peak.unwrap_or(rate.saturating_mul(10));
+            Warmup::new(rate, peak, self.warmup, started)
+        })
+    }
+
+    /// The connection lifetime cap, if any.
+    pub fn lifetime(&self) -> Option<Lifetime> {
+        self.max_lifetime.map(|max| Lifetime {
+            max,
+            jitter: self.lifetime_jitter.unwrap_or(max / 10),
+        })
+    }
+
+    /// A fresh egress bucket for a new connection, if egress is limited.
+    pub fn egress(&self) -> Option<TokenBucket> {
+        self.egress_rate
+            .map(|rate| TokenBucket::new(rate, self.egress_burst.unwrap_or(rate)))
+    }
+
+    /// Keepalive probes start after `keepalive` and give up after three
+    /// more unanswered ones, spread over the same time again.
+    pub fn keepalive(&self) -> Option<Keepalive> {
+        self.keepalive.map(|idle| Keepalive {
+            idle,
+            interval: idle / 3,
+            count: 3,
+        })
+    }
+
+    /// The clock and random source to run on: the system's, with a seeded
+    /// generator if `seed` is set and a virtual clock with `virtual_time`.
+    pub fn sources(&self) -> Sources {
+        let mut sources = Sources::default();
+        if let Some(seed) = self.seed {
+            sources.rng = Arc::new(SeededRng::new(seed));
+        }
+        if self.virtual_time {
+            sources.clock = Arc::new(VirtualClock::default());
+        }
+        sources
+    }
+
+    /// Affinity settings, when both an instance id and a secret are given.
+    pub fn affinity(&self) -> Option<Affinity> {
+        let carrier = if self.affinity_header {
+            Carrier::Header
+        } else {
+            Carrier::Cookie
+        };
+        match (&self.instance_id, &self.affinity_secret) {
+            (Some(instance), Some(secret)) => {
+                Some(Affinity::new(instance, secret.as_bytes(), carrier))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(option: &str, value: String) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::Config(format!("invalid value for {option}: {value}")))
+}
+
+/// `parse`, refusing 0.
+fn nonzero<T: std::str::FromStr + Default + PartialEq>(option: &str, value: String) -> Result<T> {
+    let parsed = parse(option, value)?;
+    if parsed == T::default() {
+        return Err(Error::Config(format!("{option} must be greater than 0")));
+    }
+    Ok(parsed)
+}
+
+fn seconds(option: &str, value: String) -> Result<Duration> {
+    parse(option, value).map(Duration::from_secs)
+}
+
+/// Parse `PATH=OPCODE[,OPCODE...]`, e.g. `/feed=text,binary`.
+fn parse_allow(value: &str) -> Result<(String, Vec<Data>)> {
+    let (path, opcodes) = value
+        .split_once('=')
+        .ok_or_else(|| Error::Config(format!("--allow expects PATH=OPCODES, not {value}")))?;
+    let opcodes = opcodes
+        .split(',')
+        .map(|opcode| match opcode {
+            "text" => Ok(Data::Text),
+            "binary" => Ok(Data::Binary),
+            other => Err(Error::Config(format!("unknown opcode {other} in --allow"))),
+        })
+        .collect::<Result<_>>()?;
+    Ok((path.to_string(), opcodes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Config> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn mux_window_must_not_be_zero() {
+        assert!(matches!(
+            args(&["--mux-window", "0"]),
+            Err(Error::Config(_))
+        ));
+        assert_eq!(args(&["--mux-window", "1"]).unwrap().mux_window, 1);
+    }
+
+    #[test]
+    fn ping_interval_must_not_be_zero() {
+        assert!(matches!(
+            args(&["--ping-interval", "0"]),
+            Err(Error::Config(_))
+        ));
+        assert_eq!(
+            args(&["--ping-interval", "30"]).unwrap().ping_interval,
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn egress_rate_must_not_be_zero() {
+        assert!(matches!(
+            args(&["--egress-rate", "0"]),
+            Err(Error::Config(_))
+        ));
+        assert_eq!(
+            args(&["--egress-rate", "4096"]).unwrap().egress_rate,
+            Some(4096)
+        );
+    }
+}
@@ -0,0 +1,444 @@
+//! Protocol conformance checks against any echo server
+//!
+//! `run` opens a fresh connection per case, sends frames that exercise the
+//! masking, fragmentation, close code and UTF-8 rules of RFC 6455, and checks
+//! how the server answers. Well-formed messages must be echoed back, and
+//! frames breaking the protocol must make the server fail the connection, by
+//! closing with a fitting code or simply dropping it. A small take on what
+//! the Autobahn test suite covers.
+
+use crate::client::WebSocket;
+use crate::error::{Error, Result};
+use crate::frame::{Control, Data, Frame, OpCode};
+use http::Uri;
+use std::time::Duration;
+
+/// How long a case waits for each reply.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What a case expects back.
+enum Expect {
+    /// These messages, in order: opcode of the first frame and the payload
+    /// of all of them.
+    Replies(Vec<(OpCode, Vec<u8>)>),
+    /// A Close frame with one of these codes, 1005 standing for none.
+    Close(&'static [u16]),
+    /// A Close frame with one of these codes, or the connection dropped.
+    Failure(&'static [u16]),
+}
+
+struct Case {
+    group: &'static str,
+    name: &'static str,
+    frames: Vec<Frame>,
+    /// Whether to send the frames unmasked, as only servers may.
+    unmasked: bool,
+    expect: Expect,
+}
+
+/// The result of one case.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub group: &'static str,
+    pub name: &'static str,
+    /// Why the case failed, if it did.
+    pub failure: Option<String>,
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+fn frame(opcode: OpCode, is_final: bool, payload: &[u8]) -> Frame {
+    let mut frame = Frame::message(payload.to_vec(), opcode);
+    frame.header_mut().is_final = is_final;
+    frame
+}
+
+fn text(is_final: bool, payload: &[u8]) -> Frame {
+    frame(OpCode::Data(Data::Text), is_final, payload)
+}
+
+fn continuation(is_final: bool, payload: &[u8]) -> Frame {
+    frame(OpCode::Data(Data::Continue), is_final, payload)
+}
+
+fn close(payload: &[u8]) -> Frame {
+    frame(OpCode::Control(Control::Close), true, payload)
+}
+
+fn echo(opcode: Data, payload: &[u8]) -> (OpCode, Vec<u8>) {
+    (OpCode::Data(opcode), payload.to_vec())
+}
+
+const PROTOCOL_ERROR: &[u16] = &[1002];
+const INVALID_DATA: &[u16] = &[1007];
+
+fn case(group: &'static str, name: &'static str, frames: Vec<Frame>, expect: Expect) -> Case {
+    Case {
+        group,
+        name,
+        frames,
+        unmasked: false,
+        expect,
+    }
+}
+
+fn cases() -> Vec<Case> {
+    let mut rsv = text(true, b"reserved bits");
+    rsv.header_mut().rsv2 = true;
+    let fragmented_ping = frame(OpCode::Control(Control::Ping), false, b"ping");
+    let mut close_reason = 1000u16.to_be_bytes().to_vec();
+    close_reason.extend_from_slice(b"\xce\xba\xff");
+
+    vec![
+        case(
+            "masking",
+            "masked text is echoed",
+            vec![text(true, b"hello")],
+            Expect::Replies(vec![echo(Data::Text, b"hello")]),
+        ),
+        case(
+            "masking",
+            "masked binary is echoed",
+            vec![frame(OpCode::Data(Data::Binary), true, &[0, 1, 2, 0xff])],
+            Expect::Replies(vec![echo(Data::Binary, &[0, 1, 2, 0xff])]),
+        ),
+        Case {
+            unmasked: true,
+            ..case(
+                "masking",
+                "unmasked frame fails the connection",
+                vec![text(true, b"hello")],
+                Expect::Failure(PROTOCOL_ERROR),
+            )
+        },
+        case(
+            "framing",
+            "reserved opcode fails the connection",
+            vec![frame(OpCode::Data(Data::Reserved(3)), true, b"")],
+            Expect::Failure(PROTOCOL_ERROR),
+        ),
+        case(
+            "framing",
+            "reserved bits without an extension fail the connection",
+            vec![rsv],
+            Expect::Failure(PROTOCOL_ERROR),
+        ),
+        case(
+            "framing",
+            "oversized control frame fails the connection",
+            vec![frame(OpCode::Control(Control::Ping), true, &[b'x'; 126])],
+            Expect::Failure(PROTOCOL_ERROR),
+        ),
+        case(
+            "framing",
+            "ping is answered with a pong",
+            vec![frame(OpCode::Control(Control::Ping), true, b"ping")],
+            Expect::Replies(vec![(OpCode::Control(Control::Pong), b"ping".to_vec())]),
+        ),
+        case(
+            "fragmentation",
+            "fragmented text is reassembled",
+            vec![
+                text(false, b"Hel"),
+                continuation(false, b""),
+                continuation(true, b"lo"),
+            ],
+            Expect::Replies(vec![echo(Data::Text, b"Hello")]),
+        ),
+        case(
+            "fragmentation",
+            "ping between fragments is answered first",
+            vec![
+                text(false, b"Hel"),
+                frame(OpCode::Control(Control::Ping), true, b"ping"),
+                continuation(true, b"lo"),
+            ],
+            Expect::Replies(vec![
+                (OpCode::Control(Control::Pong), b"ping".to_vec()),
+                echo(Data::Text, b"Hello"),
+            ]),
+        ),
+        case(
+            "fragmentation",
+            "continuation without a start fails the connection",
+            vec![continuation(true, b"orphan")],
+            Expect::Failure(PROTOCOL_ERROR),
+        ),
+        case(
+            "fragmentation",
+            "new message inside a fragmented one fails the connection",
+            vec![text(false, b"one"), text(true, b"two")],
+            Expect::Failure(PROTOCOL_ERROR),
+        ),
+        case(
+            "fragmentation",
+            "fragmented control frame fails the connection",
+            vec![fragmented_ping],
+            Expect::Failure(PROTOCOL_ERROR),
+        ),
+        case(
+            "close codes",
+            "close 1000 is answered",
+            vec![Frame::close(1000, "")],
+            Expect::Close(&[1000]),
+        ),
+        case(
+            "close codes",
+            "close without a code is answered",
+            vec![close(b"")],
+            Expect::Close(&[1000, 1005]),
+        ),
+        case(
+            "close codes",
+            "close 3000 is answered",
+            vec![Frame::close(3000, "")],
+            Expect::Close(&[1000, 3000]),
+        ),
+        case(
+            "close codes",
+            "close with a 1-byte payload fails the connection",
+            vec![close(&[3])],
+            Expect::Failure(PROTOCOL_ERROR),
+        ),
+        case(
+            "close codes",
+            "close 1005 fails the connection",
+            vec![Frame::close(1005, "")],
+            Expect::Failure(PROTOCOL_ERROR),
+        ),
+        case(
+            "close codes",
+            "close 999 fails the connection",
+            vec![Frame::close(999, "")],
+            Expect::Failure(PROTOCOL_ERROR),
+        ),
+        case(
+            "close codes",
+            "close 5000 fails the connection",
+            vec![Frame::close(5000, "")],
+            Expect::Failure(PROTOCOL_ERROR),
+        ),
+        case(
+            "close codes",
+            "close reason that is not UTF-8 fails the connection",
+            vec![close(&close_reason)],
+            Expect::Failure(&[1002, 1007]),
+        ),
+        case(
+            "utf-8",
+            "multibyte text is echoed",
+            vec![text(true, "κόσμε €𝄞".as_bytes())],
+            Expect::Replies(vec![echo(Data::Text, "κόσμε €𝄞".as_bytes())]),
+        ),
+        case(
+            "utf-8",
+            "character split across fragments is echoed",
+            vec![text(false, b"\xce"), continuation(true, b"\xba")],
+            Expect::Replies(vec![echo(Data::Text, "κ".as_bytes())]),
+        ),
+        case(
+            "utf-8",
+            "invalid byte fails the connection",
+            vec![text(true, b"abc\xffdef")],
+            Expect::Failure(INVALID_DATA),
+        ),
+        case(
+            "utf-8",
+            "overlong encoding fails the connection",
+            vec![text(true, b"\xc0\xaf")],
+            Expect::Failure(INVALID_DATA),
+        ),
+        case(
+            "utf-8",
+            "surrogate fails the connection",
+            vec![text(true, b"\xed\xa0\x80")],
+            Expect::Failure(INVALID_DATA),
+        ),
+        case(
+            "utf-8",
+            "invalid byte in a later fragment fails the connection",
+            vec![text(false, b"valid"), continuation(true, b"\xff")],
+            Expect::Failure(INVALID_DATA),
+        ),
+    ]
+}
+
+/// Split a `ws://host[:port]/path` URL into the address to connect to and
+/// the path to upgrade.
+fn target(url: &str) -> Result<(String, String)> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|_| Error::Config(format!("invalid URL {url}")))?;
+    if uri.scheme_part().map(|scheme| scheme.as_str()) != Some("ws") {
+        return Err(Error::Config(format!("{url} is not a ws:// URL")));
+    }
+    let host = uri
+        .host()
+        .ok_or_else(|| Error::Config(format!("{url} has no host")))?;
+    let path = uri
+        .path_and_query()
+        .map_or("/", |path| path.as_str())
+        .to_string();
+    Ok((format!("{host}:{}", uri.port_u16().unwrap_or(80)), path))
+}
+
+/// Run every case against the echo server at `url`, a `ws://` URL.
+pub fn run(url: &str) -> Result<Vec<Outcome>> {
+    let (addr, path) = target(url)?;
+    Ok(cases()
+        .into_iter()
+        .map(|case| Outcome {
+            group: case.group,
+            name: case.name,
+            failure: check(&addr, &path, &case).err(),
+        })
+        .collect())
+}
+
+/// The pass/fail matrix for `outcomes`, one line per case, with a count of
+/// the cases that passed at the end.
+pub fn matrix(outcomes: &[Outcome]) -> String {
+    let width = outcomes
+        .iter()
+        .map(|outcome| outcome.name.len())
+        .max()
+        .unwrap_or(0);
+    let mut matrix = String::new();
+    for outcome in outcomes {
+        let result = match &outcome.failure {
+            None => String::from("pass"),
+            Some(failure) => format!("FAIL  {failure}"),
+        };
+        matrix += &format!("{:<14}{:<width$}  {result}\n", outcome.group, outcome.name);
+    }
+    let passed = outcomes.iter().filter(|outcome| outcome.passed()).count();
+    matrix += &format!("{passed} of {} passed\n", outcomes.len());
+    matrix
+}
+
+/// A message or control frame read back, or how the connection ended.
+enum Reply {
+    Message(OpCode, Vec<u8>),
+    /// A Close frame's code, 1005 if it had none.
+    Close(u16),
+    Dropped,
+}
+
+/// Read the next message, reassembling fragments.
+fn reply(socket: &mut WebSocket) -> Result<Reply, String> {
+    let mut message: Option<(OpCode, Vec<u8>)> = None;
+    loop {
+        let frame = match socket.read() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(Reply::Dropped),
+            Err(Error::Io(error))
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(String::from("no reply"))
+            }
+            Err(Error::Io(_)) => return Ok(Reply::Dropped),
+            Err(error) => return Err(format!("unreadable reply: {error}")),
+        };
+        let header = frame.header();
+        match (header.opcode, &mut message) {
+            (OpCode::Control(Control::Close), _) => {
+                let code = frame
+                    .payload()
+                    .get(..2)
+                    .map_or(1005, |code| u16::from_be_bytes([code[0], code[1]]));
+                return Ok(Reply::Close(code));
+            }
+            (OpCode::Control(opcode), _) => {
+                return Ok(Reply::Message(
+                    OpCode::Control(opcode),
+                    frame.into_payload(),
+                ))
+            }
+            (OpCode::Data(Data::Continue), Some((_, payload))) => {
+                payload.extend_from_slice(frame.payload())
+            }
+            (opcode, None) => message = Some((opcode, frame.payload().to_vec())),
+            (_, Some(_)) => return Err(String::from("reply interleaves messages")),
+        }
+        if header.is_final {
+            if let Some((opcode, payload)) = message.take() {
+                return Ok(Reply::Message(opcode, payload));
+            }
+        }
+    }
+}
+
+fn describe(reply: &Reply) -> String {
+    match reply {
+        Reply::Message(opcode, payload) => {
+            format!("got {opcode:?} with {} bytes", payload.len())
+        }
+        Reply::Close(code) => format!("closed with {code}"),
+        Reply::Dropped => String::from("dropped the connection"),
+    }
+}
+
+fn check(addr: &str, path: &str, case: &Case) -> Result<(), String> {
+    let mut socket =
+        WebSocket::connect(addr, path).map_err(|error| format!("can't connect: {error}"))?;
+    socket
+        .set_read_timeout(Some(REPLY_TIMEOUT))
+        .map_err(|error| error.to_string())?;
+    for frame in &case.frames {
+        let sent = if case.unmasked {
+            let mut bytes = Vec::new();
+            frame
+                .clone()
+                .format(&mut bytes)
+                .map_err(|error| error.to_string())?;
+            socket.send_raw(&bytes)
+        } else {
+            socket.send(frame.clone())
+        };
+        // a server failing the connection early may refuse the rest
+        if sent.is_err() {
+            break;
+        }
+    }
+
+    match &case.expect {
+        Expect::Replies(expected) => {
+            for (opcode, payload) in expected {
+                match reply(&mut socket)? {
+                    Reply::Message(got, body) if got == *opcode && body == *payload => {}
+                    Reply::Message(got, body) if got == *opcode => {
+                        return Err(format!(
+                            "{opcode:?} came back with {} bytes instead of {}",
+                            body.len(),
+                            payload.len()
+                        ))
+                    }
+                    other => return Err(format!("expected {opcode:?}, {}", describe(&other))),
+                }
+            }
+            Ok(())
+        }
+        Expect::Close(codes) => match reply(&mut socket)? {
+            Reply::Close(code) if codes.contains(&code) => Ok(()),
+            other => Err(format!(
+                "expected a Close with {codes:?}, {}",
+                describe(&other)
+            )),
+        },
+        Expect::Failure(codes) => match reply(&mut socket)? {
+            Reply::Close(code) if codes.contains(&code) => Ok(()),
+            Reply::Dropped => Ok(()),
+            other => Err(format!(
+                "expected a Close with {codes:?}, {}",
+                describe(&other)
+            )),
+        },
+    }
+}
@@ -0,0 +1,140 @@
+//! Dropping messages a client sent more than once
+
+use crate::frame::{Data, Frame, OpCode};
+use std::collections::{BTreeMap, HashMap};
+
+/// Finds the application's id in a message, if it carries one.
+pub type IdExtractor = Box<dyn Fn(&Frame) -> Option<Vec<u8>> + Send + Sync>;
+
+/// An extractor taking the bytes before the first `separator` as the id, so
+/// `42:hello` has id `42`. Messages without the separator carry no id.
+pub fn prefix_id(separator: u8) -> IdExtractor {
+    Box::new(move |frame| {
+        let payload = frame.payload();
+        let end = payload.iter().position(|&byte| byte == separator)?;
+        Some(payload[..end].to_vec())
+    })
+}
+
+/// Recognizes messages whose id was seen among the last `capacity` ids on
+/// the same connection.
+pub struct Dedupe {
+    capacity: usize,
+    extract: IdExtractor,
+}
+
+impl Dedupe {
+    pub fn new(capacity: usize, extract: IdExtractor) -> Dedupe {
+        Dedupe {
+            capacity: capacity.max(1),
+            extract,
+        }
+    }
+
+    /// Whether `frame` repeats a message already seen in `recent`. Only
+    /// unfragmented data messages are considered, the others always pass.
+    /// The id of a message is remembered, or refreshed if it was already.
+    pub fn is_duplicate(&self, recent: &mut Recent, frame: &Frame) -> bool {
+        let header = frame.header();
+        if !header.is_final || !matches!(header.opcode, OpCode::Data(Data::Text | Data::Binary)) {
+            return false;
+        }
+        match (self.extract)(frame) {
+            Some(id) => recent.touch(id, self.capacity),
+            None => false,
+        }
+    }
+}
+
+/// Ids recently seen on one connection, the least recently seen forgotten
+/// first.
+#[derive(Debug, Default)]
+pub struct Recent {
+    /// When each id was last seen, as a count of ids seen.
+    seen: HashMap<Vec<u8>, u64>,
+    by_age: BTreeMap<u64, Vec<u8>>,
+    clock: u64,
+}
+
+impl Recent {
+    /// Note `id` as just seen, returning whether it already was.
+    fn touch(&mut self, id: Vec<u8>, capacity: usize) -> bool {
+        self.clock += 1;
+        if let Some(last) = self.seen.insert(id.clone(), self.clock) {
+            self.by_age.remove(&last);
+            self.by_age.insert(self.clock, id);
+            return true;
+        }
+        self.by_age.insert(self.clock, id);
+        if self.seen.len() > capacity {
+            if let Some((_, oldest)) = self.by_age.pop_first() {
+                self.seen.remove(&oldest);
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Control;
+
+    fn text(payload: &str) -> Frame {
+        Frame::message(payload.as_bytes().to_vec(), OpCode::Data(Data::Text))
+    }
+
+    #[test]
+    fn prefix_id_takes_what_comes_before_the_separator() {
+        let extract = prefix_id(b':');
+        assert_eq!(extract(&text("42:hello")), Some(b"42".to_vec()));
+        assert_eq!(extract(&text(":hello")), Some(Vec::new()));
+        assert_eq!(extract(&text("hello")), None);
+    }
+
+    #[test]
+    fn repeats_within_the_window_are_duplicates() {
+        let dedupe = Dedupe::new(2, prefix_id(b':'));
+        let mut recent = Recent::default();
+        assert!(!dedupe.is_duplicate(&mut recent, &text("1:a")));
+        assert!(!dedupe.is_duplicate(&mut recent, &text("2:b")));
+        assert!(dedupe.is_duplicate(&mut recent, &text("1:again")));
+        // messages without an id are never dropped
+        assert!(!dedupe.is_duplicate(&mut recent, &text("no id")));
+        assert!(!dedupe.is_duplicate(&mut recent, &text("no id")));
+    }
+
+    #[test]
+    fn the_least_recently_seen_id_is_forgotten_first() {
+        let dedupe = Dedupe::new(2, prefix_id(b':'));
+        let mut recent = Recent::default();
+        dedupe.is_duplicate(&mut recent, &text("1:"));
+        dedupe.is_duplicate(&mut recent, &text("2:"));
+        // seeing 1 again makes 2 the oldest, so 3 pushes 2 out
+        assert!(dedupe.is_duplicate(&mut recent, &text("1:")));
+        assert!(!dedupe.is_duplicate(&mut recent, &text("3:")));
+        assert!(dedupe.is_duplicate(&mut recent, &text("1:")));
+        assert!(!dedupe.is_duplicate(&mut recent, &text("2:")));
+    }
+
+    #[test]
+    fn fragments_and_control_frames_always_pass() {
+        let dedupe = Dedupe::new(8, prefix_id(b':'));
+        let mut recent = Recent::default();
+        let mut fragment = text("1:a");
+        fragment.header_mut().is_final = false;
+        let ping = Frame::message(b"1:a".to_vec(), OpCode::Control(Control::Ping));
+        for _ in 0..2 {
+            assert!(!dedupe.is_duplicate(&mut recent, &fragment));
+            assert!(!dedupe.is_duplicate(&mut recent, &ping));
+        }
+    }
+
+    #[test]
+    fn connections_remember_separately() {
+        let dedupe = Dedupe::new(8, prefix_id(b':'));
+        let (mut first, mut second) = (Recent::default(), Recent::default());
+        assert!(!dedupe.is_duplicate(&mut first, &text("1:a")));
+        assert!(!dedupe.is_duplicate(&mut second, &text("1:a")));
+    }
+}
@@ -0,0 +1,116 @@
+//! Outbound bandwidth shaping
+
+use crate::sim::Clock;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Token bucket over bytes. Writes may overdraw it, so a message bigger than
+/// the burst still goes out, and the debt is paid back by waiting.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+impl TokenBucket {
+    /// Refill at `rate` bytes per second, holding at most `burst` bytes. The
+    /// bucket starts full. A `rate` of 0 is taken as 1, `--egress-rate`
+    /// refuses it outright.
+    pub fn new(rate: u64, burst: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate.max(1) as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last: None,
+        }
+    }
+
+    /// Spend `bytes` at `now`, returning how long to hold off before the next
+    /// write.
+    pub fn spend(&mut self, bytes: usize, now: Instant) -> Duration {
+        if let Some(last) = self.last {
+            let refill = now.saturating_duration_since(last).as_secs_f64() * self.rate;
+            self.tokens = (self.tokens + refill).min(self.burst);
+        }
+        self.last = Some(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// A writer held to a `TokenBucket`, if it has one.
+pub struct Shaped<W> {
+    inner: W,
+    bucket: Option<TokenBucket>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<W> Shaped<W> {
+    pub fn new(inner: W, bucket: Option<TokenBucket>, clock: Arc<dyn Clock>) -> Shaped<W> {
+        Shaped {
+            inner,
+            bucket,
+            clock,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+}
+
+impl<W: Write> Write for Shaped<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        if let Some(bucket) = &mut self.bucket {
+            self.clock.sleep(bucket.spend(size, self.clock.now()));
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::VirtualClock;
+
+    #[test]
+    fn bucket_refills_with_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100, 100);
+        assert_eq!(bucket.spend(100, start), Duration::ZERO);
+        assert_eq!(bucket.spend(50, start), Duration::from_millis(500));
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.spend(50, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn shaping_on_a_virtual_clock_advances_it_instead_of_waiting() {
+        let clock = Arc::new(VirtualClock::default());
+        let start = clock.now();
+        let mut shaped = Shaped::new(
+            Vec::new(),
+            Some(TokenBucket::new(1000, 1000)),
+            clock.clone(),
+        );
+        let started = Instant::now();
+        for _ in 0..10 {
+            shaped.write_all(&[0; 1000]).unwrap();
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        // the first write came out of the burst, the other nine were paced
+        assert_eq!(clock.now() - start, Duration::from_secs(9));
+        assert_eq!(shaped.get_ref().len(), 10_000);
+    }
+}
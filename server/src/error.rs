@@ -1,12 +1,58 @@
 //! Error handling
 
-use std::{result, str, string};
+use std::{io, result, str, string};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("UTF-8 encoding error")]
     Utf8,
+    #[error("Extension error: {0}")]
+    Extension(String),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("HTTP error: {0}")]
+    Http(#[from] http::Error),
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    #[error("Unsupported data: {0}")]
+    Unsupported(String),
+    #[error("Policy violation: {0}")]
+    Policy(String),
+    #[error("Message too big: {0}")]
+    Capacity(String),
+    #[error("Handshake error: {0}")]
+    Handshake(String),
+    #[error("Invalid Sec-WebSocket-Key: {0}")]
+    InvalidKey(String),
+    #[error("Unknown host {0}")]
+    UnknownHost(String),
+    #[error("Origin {0} is not allowed")]
+    ForbiddenOrigin(String),
+    #[error("Control character in {0} header")]
+    ControlCharacter(String),
+    #[error("Invalid affinity token")]
+    InvalidAffinity,
+    #[error("Connection belongs to instance {0}")]
+    WrongInstance(String),
+    #[error("Mux error: {0}")]
+    Mux(String),
+    #[error("Sub-frame error: {0}")]
+    SubFrame(String),
+    #[error("Unsupported sub-frame version {0}")]
+    SubFrameVersion(u8),
+    #[error("RPC error: {0}")]
+    Rpc(String),
+    #[error("Timed out")]
+    Timeout,
+    #[error("Connection went stale, the server stopped answering Pings")]
+    Stale,
+    #[error("Transfer error: {0}")]
+    Transfer(String),
+    #[error("Configuration error: {0}")]
+    Config(String),
+    #[error("Close code {0} is reserved or out of range")]
+    CloseCode(u16),
 }
 
 pub type Result<T, E = Error> = result::Result<T, E>;
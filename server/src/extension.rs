@@ -0,0 +1,236 @@
+//! Extension pipeline
+
+use crate::error::{Error, Result};
+use crate::frame::Frame;
+use http::{Extensions, Request};
+
+/// Opaque per-message data attached by extensions while decoding a frame.
+/// It travels alongside the frame to the application.
+pub type Metadata = Extensions;
+
+/// A negotiated WebSocket extension.
+pub trait Extension: Send {
+    /// Extension token as it appears in `Sec-WebSocket-Extensions`.
+    fn name(&self) -> &str;
+
+    /// Inspect or rewrite an inbound frame. RSV bits are left untouched on the
+    /// header so the extension can decide what they mean, and an extension
+    /// claims a bit by clearing it.
+    fn decode(&mut self, frame: &mut Frame, metadata: &mut Metadata) -> Result<()>;
+
+    /// Inspect or rewrite an outbound frame before it is written.
+    fn encode(&mut self, frame: &mut Frame) -> Result<()>;
+}
+
+/// Whether the client offered the extension `name` in its
+/// `Sec-WebSocket-Extensions` headers. Parameters of the offer are ignored.
+pub fn offered(request: &Request<()>, name: &str) -> bool {
+    request
+        .headers()
+        .get_all("Sec-WebSocket-Extensions")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|offer| offer.split(';').next())
+        .any(|token| token.trim().eq_ignore_ascii_case(name))
+}
+
+/// Fail with `Error::Protocol` if the frame still has an RSV bit set, which
+/// RFC 6455 only allows when a negotiated extension gives it a meaning.
+pub fn unclaimed_bits(frame: &Frame) -> Result<()> {
+    let header = frame.header();
+    match [header.rsv1, header.rsv2, header.rsv3]
+        .iter()
+        .position(|&set| set)
+    {
+        Some(bit) => Err(Error::Protocol(format!(
+            "RSV{} set without an extension to claim it",
+            bit + 1
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The ordered set of extensions negotiated for a connection.
+#[derive(Default)]
+pub struct Pipeline {
+    extensions: Vec<Box<dyn Extension>>,
+}
+
+impl Pipeline {
+    pub fn push(&mut self, extension: Box<dyn Extension>) {
+        self.extensions.push(extension);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+    }
+
+    /// Run inbound frames through the extensions in negotiation order. An RSV
+    /// bit none of them claimed fails the frame, see `unclaimed_bits`.
+    pub fn decode(&mut self, frame: &mut Frame) -> Result<Metadata> {
+        let mut metadata = Metadata::new();
+        for extension in self.extensions.iter_mut() {
+            extension.decode(frame, &mut metadata)?;
+        }
+        unclaimed_bits(frame)?;
+        Ok(metadata)
+    }
+
+    /// Run outbound frames through the extensions in reverse negotiation order.
+    pub fn encode(&mut self, frame: &mut Frame) -> Result<()> {
+        for extension in self.extensions.iter_mut().rev() {
+            extension.encode(frame)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{Data, OpCode};
+    use std::sync::{Arc, Mutex};
+
+    /// Marks messages that arrived with RSV1 set, the way a compression
+    /// extension would.
+    #[derive(Debug, PartialEq)]
+    struct Compressed;
+
+    struct Rsv1;
+
+    impl Extension for Rsv1 {
+        fn name(&self) -> &str {
+            "rsv1"
+        }
+
+        fn decode(&mut self, frame: &mut Frame, metadata: &mut Metadata) -> Result<()> {
+            if frame.header().rsv1 {
+                frame.header_mut().rsv1 = false;
+                metadata.insert(Compressed);
+            }
+            Ok(())
+        }
+
+        fn encode(&mut self, frame: &mut Frame) -> Result<()> {
+            frame.header_mut().rsv1 = true;
+            Ok(())
+        }
+    }
+
+    /// Writes its name to a shared log on every call, or fails decoding.
+    struct Logged {
+        name: &'static str,
+        log: Arc<Mutex<Vec<&'static str>>>,
+        fail: bool,
+    }
+
+    impl Extension for Logged {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn decode(&mut self, _frame: &mut Frame, _metadata: &mut Metadata) -> Result<()> {
+            self.log.lock().unwrap().push(self.name);
+            if self.fail {
+                return Err(Error::Extension(format!("{} failed", self.name)));
+            }
+            Ok(())
+        }
+
+        fn encode(&mut self, _frame: &mut Frame) -> Result<()> {
+            self.log.lock().unwrap().push(self.name);
+            Ok(())
+        }
+    }
+
+    fn logged(log: &Arc<Mutex<Vec<&'static str>>>, names: &[&'static str]) -> Pipeline {
+        let mut pipeline = Pipeline::default();
+        for &name in names {
+            pipeline.push(Box::new(Logged {
+                name,
+                log: log.clone(),
+                fail: name == "failing",
+            }));
+        }
+        pipeline
+    }
+
+    #[test]
+    fn decode_hands_rsv_bits_to_extensions_and_metadata_back() {
+        let mut pipeline = Pipeline::default();
+        pipeline.push(Box::new(Rsv1));
+
+        let mut frame = Frame::message(b"hello".to_vec(), OpCode::Data(Data::Text));
+        frame.header_mut().rsv1 = true;
+        let metadata = pipeline.decode(&mut frame).unwrap();
+        assert_eq!(metadata.get::<Compressed>(), Some(&Compressed));
+        assert!(!frame.header().rsv1);
+
+        let mut frame = Frame::message(b"hello".to_vec(), OpCode::Data(Data::Text));
+        assert!(pipeline
+            .decode(&mut frame)
+            .unwrap()
+            .get::<Compressed>()
+            .is_none());
+    }
+
+    #[test]
+    fn encode_runs_in_reverse_negotiation_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = logged(&log, &["first", "second"]);
+        let mut frame = Frame::message(Vec::new(), OpCode::Data(Data::Binary));
+
+        pipeline.decode(&mut frame).unwrap();
+        pipeline.encode(&mut frame).unwrap();
+        assert_eq!(*log.lock().unwrap(), ["first", "second", "second", "first"]);
+    }
+
+    #[test]
+    fn failing_extension_stops_decoding() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = logged(&log, &["failing", "skipped"]);
+        let mut frame = Frame::message(Vec::new(), OpCode::Data(Data::Binary));
+
+        assert!(matches!(
+            pipeline.decode(&mut frame),
+            Err(Error::Extension(_))
+        ));
+        assert_eq!(*log.lock().unwrap(), ["failing"]);
+    }
+
+    #[test]
+    fn offered_matches_tokens_ignoring_parameters() {
+        let request = Request::get("/")
+            .header(
+                "Sec-WebSocket-Extensions",
+                "permessage-deflate; client_max_window_bits",
+            )
+            .header("Sec-WebSocket-Extensions", "x-crc32-trailer, x-other")
+            .body(())
+            .unwrap();
+        assert!(offered(&request, "permessage-deflate"));
+        assert!(offered(&request, "X-CRC32-Trailer"));
+        assert!(!offered(&request, "client_max_window_bits"));
+    }
+
+    #[test]
+    fn bits_no_extension_claimed_fail_decoding() {
+        let mut pipeline = Pipeline::default();
+        pipeline.push(Box::new(Rsv1));
+        let mut frame = Frame::message(b"hello".to_vec(), OpCode::Data(Data::Text));
+        frame.header_mut().rsv2 = true;
+        assert!(matches!(
+            pipeline.decode(&mut frame),
+            Err(Error::Protocol(message)) if message.contains("RSV2")
+        ));
+
+        // with nothing negotiated, even RSV1 is unclaimed
+        let mut frame = Frame::message(b"hello".to_vec(), OpCode::Data(Data::Text));
+        frame.header_mut().rsv1 = true;
+        assert!(matches!(
+            Pipeline::default().decode(&mut frame),
+            Err(Error::Protocol(_))
+        ));
+    }
+}
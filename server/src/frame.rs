@@ -1,10 +1,19 @@
 // use crate::error::Result;
+use crate::error::Error;
+use crate::profile::{self, Stage};
+use crate::sim::{Rng, SystemRng};
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
 use std::{
     io::{ErrorKind, Read, Write},
     result::Result,
 };
 
+/// The largest payload `Frame::parse` reads.
+pub const MAX_PAYLOAD: usize = 64 * 1024 * 1024;
+
+/// The largest payload a control frame may carry.
+pub const MAX_CONTROL_PAYLOAD: u64 = 125;
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Data {
     /// 0x0 denotes a continuation frame
@@ -141,15 +150,32 @@ impl LengthFormat {
 }
 
 impl FrameHeader {
-    pub(crate) fn set_random_mask(&mut self) {
-        self.mask = Some(rand::random())
+    pub fn set_random_mask(&mut self) {
+        self.set_mask(&SystemRng)
+    }
+
+    /// Mask with four bytes drawn from `rng`.
+    pub fn set_mask(&mut self, rng: &dyn Rng) {
+        let mut mask = [0; 4];
+        rng.fill(&mut mask);
+        self.mask = Some(mask)
     }
 
+    /// Read a frame header and the payload length it announces. `None` means
+    /// the input ended cleanly between frames; once a frame started, running
+    /// out of input before its header is complete is an error.
     pub fn parse(input: &mut impl Read) -> Result<Option<(Self, u64)>, Box<dyn std::error::Error>> {
         let mut head = [0u8; 2];
-        if input.read(&mut head)? != 2 {
-            return Ok(None);
+        loop {
+            match input.read(&mut head[..1]) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
         }
+        // the rest may come in later segments, read calls can stop short
+        input.read_exact(&mut head[1..])?;
         let first = head[0];
         let second = head[1];
 
@@ -166,15 +192,7 @@ impl FrameHeader {
             let length_byte = second & 0b0111_1111;
             let length_length = LengthFormat::for_byte(length_byte).extra_bytes();
             if length_length > 0 {
-                match input.read_uint::<NetworkEndian>(length_length) {
-                    Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
-                        return Ok(None);
-                    }
-                    Err(err) => {
-                        return Err(err.into());
-                    }
-                    Ok(read) => read,
-                }
+                input.read_uint::<NetworkEndian>(length_length)?
             } else {
                 u64::from(length_byte)
             }
@@ -182,11 +200,8 @@ impl FrameHeader {
 
         let mask = if masked {
             let mut mask_bytes = [0u8; 4];
-            if input.read(&mut mask_bytes)? != 4 {
-                return Ok(None);
-            } else {
-                Some(mask_bytes)
-            }
+            input.read_exact(&mut mask_bytes)?;
+            Some(mask_bytes)
         } else {
             None
         };
@@ -208,7 +223,11 @@ impl FrameHeader {
         output: &mut impl Write,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let code: u8 = self.opcode.into();
-        let one = code | if self.is_final { 0x80 } else { 0 };
+        let one = code
+            | if self.is_final { 0x80 } else { 0 }
+            | if self.rsv1 { 0x40 } else { 0 }
+            | if self.rsv2 { 0x20 } else { 0 }
+            | if self.rsv3 { 0x10 } else { 0 };
 
         let length_format = LengthFormat::for_length(length);
 
@@ -260,6 +279,103 @@ impl Frame {
         }
     }
 
+    /// A Close frame carrying a status code and reason.
+    pub fn close(code: u16, reason: &str) -> Frame {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        Frame::message(payload, OpCode::Control(Control::Close))
+    }
+
+    pub fn from_parts(header: FrameHeader, payload: Vec<u8>) -> Frame {
+        Frame { header, payload }
+    }
+
+    /// Read a whole frame, unmasking the payload if the peer masked it.
+    /// Payloads over `MAX_PAYLOAD` are refused.
+    pub fn parse(input: &mut impl Read) -> Result<Option<Frame>, Box<dyn std::error::Error>> {
+        Frame::parse_limited(input, MAX_PAYLOAD)
+    }
+
+    /// `parse`, refusing with `Error::Capacity` any frame whose header claims
+    /// more than `max` payload bytes, before a buffer for it is allocated.
+    /// Control frames that are fragmented or carry more than 125 bytes are
+    /// an `Error::Protocol`.
+    pub fn parse_limited(
+        input: &mut impl Read,
+        max: usize,
+    ) -> Result<Option<Frame>, Box<dyn std::error::Error>> {
+        Frame::read(input, max, false)
+    }
+
+    /// `parse_limited` for a server reading a client, which must mask every
+    /// frame: an unmasked one is an `Error::Protocol`.
+    pub fn parse_masked(
+        input: &mut impl Read,
+        max: usize,
+    ) -> Result<Option<Frame>, Box<dyn std::error::Error>> {
+        Frame::read(input, max, true)
+    }
+
+    fn read(
+        input: &mut impl Read,
+        max: usize,
+        masked: bool,
+    ) -> Result<Option<Frame>, Box<dyn std::error::Error>> {
+        let (header, length) = match FrameHeader::parse(input)? {
+            Some(parsed) => parsed,
+            None => return Ok(None),
+        };
+        if masked && header.mask.is_none() {
+            return Err(Box::new(Error::Protocol(String::from(
+                "unmasked frame from a client",
+            ))));
+        }
+        if let OpCode::Control(_) = header.opcode {
+            if !header.is_final {
+                return Err(Box::new(Error::Protocol(String::from(
+                    "fragmented control frame",
+                ))));
+            }
+            if length > MAX_CONTROL_PAYLOAD {
+                return Err(Box::new(Error::Protocol(format!(
+                    "{length} byte control frame, limit is {MAX_CONTROL_PAYLOAD}"
+                ))));
+            }
+        }
+        if length > max as u64 {
+            return Err(Box::new(Error::Capacity(format!(
+                "frame of {length} bytes exceeds the limit of {max}"
+            ))));
+        }
+
+        let mut payload = vec![0; length as usize];
+        profile::time(Stage::Parse, || input.read_exact(&mut payload))?;
+
+        let mut frame = Frame { header, payload };
+        profile::time(Stage::Unmask, || frame.apply_mask());
+        Ok(Some(frame))
+    }
+
+    pub fn header(&self) -> &FrameHeader {
+        &self.header
+    }
+
+    pub fn header_mut(&mut self) -> &mut FrameHeader {
+        &mut self.header
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn payload_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.payload
+    }
+
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+
     pub(crate) fn apply_mask(&mut self) {
         if let Some(mask) = self.header.mask.take() {
             apply_mask(&mut self.payload, mask)
@@ -273,9 +389,123 @@ impl Frame {
         Ok(())
     }
 
+    /// Encoded size of the frame. A frame always carries at least its header,
+    /// so there is no `is_empty` counterpart.
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         let payload_length = self.payload.len();
         let header_length = self.header.len(payload_length as u64);
         header_length + payload_length
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_length_is_refused_before_reading_the_payload() {
+        // a 64-bit length the peer never intends to send
+        let mut input: &[u8] = &[0x82, 0x7f, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        let error = Frame::parse_limited(&mut input, 1024).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::Capacity(_))
+        ));
+    }
+
+    #[test]
+    fn payload_at_the_limit_is_read() {
+        let mut buffer = Vec::new();
+        Frame::message(vec![7; 16], OpCode::Data(Data::Binary))
+            .format(&mut buffer)
+            .unwrap();
+        let frame = Frame::parse_limited(&mut &buffer[..], 16).unwrap().unwrap();
+        assert_eq!(frame.payload(), [7; 16]);
+        assert!(Frame::parse_limited(&mut &buffer[..], 15).is_err());
+    }
+
+    fn protocol_error(bytes: &[u8], masked: bool) -> bool {
+        let error = Frame::read(&mut &bytes[..], MAX_PAYLOAD, masked).unwrap_err();
+        matches!(error.downcast_ref::<Error>(), Some(Error::Protocol(_)))
+    }
+
+    #[test]
+    fn control_frames_must_be_final_and_short() {
+        let mut buffer = Vec::new();
+        Frame::message(vec![b'x'; 126], OpCode::Control(Control::Ping))
+            .format(&mut buffer)
+            .unwrap();
+        assert!(protocol_error(&buffer, false));
+
+        let mut ping = Frame::message(b"ping".to_vec(), OpCode::Control(Control::Ping));
+        ping.header_mut().is_final = false;
+        let mut buffer = Vec::new();
+        ping.format(&mut buffer).unwrap();
+        assert!(protocol_error(&buffer, false));
+
+        let mut buffer = Vec::new();
+        Frame::message(vec![b'x'; 125], OpCode::Control(Control::Ping))
+            .format(&mut buffer)
+            .unwrap();
+        assert!(Frame::parse(&mut &buffer[..]).unwrap().is_some());
+    }
+
+    #[test]
+    fn clients_must_mask() {
+        let mut buffer = Vec::new();
+        Frame::message(b"hello".to_vec(), OpCode::Data(Data::Text))
+            .format(&mut buffer)
+            .unwrap();
+        assert!(protocol_error(&buffer, true));
+        assert!(Frame::parse(&mut &buffer[..]).unwrap().is_some());
+
+        let mut frame = Frame::message(b"hello".to_vec(), OpCode::Data(Data::Text));
+        frame.header_mut().set_random_mask();
+        let mut buffer = Vec::new();
+        frame.format(&mut buffer).unwrap();
+        let frame = Frame::parse_masked(&mut &buffer[..], MAX_PAYLOAD).unwrap();
+        assert_eq!(frame.unwrap().payload(), b"hello");
+    }
+
+    /// Hands out one byte per read call, like a peer trickling a frame in.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let size = buf.len().min(self.0.len()).min(1);
+            buf[..size].copy_from_slice(&self.0[..size]);
+            self.0 = &self.0[size..];
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn header_arriving_a_byte_at_a_time_is_read_whole() {
+        let mut frame = Frame::message(vec![b'x'; 300], OpCode::Data(Data::Text));
+        frame.header_mut().set_random_mask();
+        let mut buffer = Vec::new();
+        frame.format(&mut buffer).unwrap();
+        let parsed = Frame::parse(&mut Trickle(&buffer)).unwrap().unwrap();
+        assert_eq!(parsed.payload(), [b'x'; 300]);
+    }
+
+    #[test]
+    fn input_ending_between_frames_is_no_frame() {
+        assert!(Frame::parse(&mut &[][..]).unwrap().is_none());
+    }
+
+    #[test]
+    fn input_ending_inside_a_header_is_an_error() {
+        // first byte only, then inside the extended length, then inside the mask
+        for header in [&[0x81][..], &[0x81, 0x7e, 0x01], &[0x81, 0x85, 1, 2]] {
+            let error = Frame::parse(&mut &header[..]).unwrap_err();
+            assert_eq!(
+                error
+                    .downcast_ref::<std::io::Error>()
+                    .map(|error| error.kind()),
+                Some(ErrorKind::UnexpectedEof)
+            );
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! Opening handshake
+
+use crate::error::{Error, Result};
+use http::{Request, StatusCode};
+use sha1::{Digest, Sha1};
+use std::io::{self, ErrorKind, Read, Write};
+
+const MAGIC: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest `Host` header accepted: a 253 character DNS name and a port.
+pub const MAX_HOST: usize = 253 + ":65535".len();
+
+/// Longest `Origin` header accepted: a scheme and a `Host`.
+pub const MAX_ORIGIN: usize = "https://".len() + MAX_HOST;
+
+/// Derive the `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update(MAGIC);
+    base64::encode(hasher.finalize())
+}
+
+/// Read the head of a request into `buffer`, however many reads it takes to
+/// arrive, until the blank line ending it or until `buffer` is full. Returns
+/// the number of bytes read, which may include some past the head.
+pub fn read_head(input: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < buffer.len() && !buffer[..size].windows(4).any(|end| end == b"\r\n\r\n") {
+        match input.read(&mut buffer[size..]) {
+            Ok(0) => break,
+            Ok(read) => size += read,
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(size)
+}
+
+/// Parse the head of an HTTP upgrade request.
+pub fn parse_request(raw: &str) -> Result<Request<()>> {
+    let mut lines = raw.lines();
+    let request_line = lines
+        .next()
+        .ok_or_else(|| Error::Handshake(String::from("empty request")))?;
+    let mut parts = request_line.split_whitespace();
+    let (method, uri) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(uri), Some(_version)) => (method, uri),
+        _ => {
+            return Err(Error::Handshake(format!(
+                "malformed request line: {request_line}"
+            )))
+        }
+    };
+
+    let mut builder = Request::builder();
+    builder.method(method).uri(uri);
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| Error::Handshake(format!("malformed header: {line}")))?;
+        let (name, value) = (name.trim(), value.trim());
+        // names may not even hold a tab, and are escaped so the error can't
+        // smuggle control characters into logs either
+        if name.chars().any(char::is_control) {
+            return Err(Error::ControlCharacter(name.escape_debug().to_string()));
+        }
+        if value.chars().any(|c| c.is_control() && c != '\t') {
+            return Err(Error::ControlCharacter(name.to_string()));
+        }
+        builder.header(name, value);
+    }
+    Ok(builder.body(())?)
+}
+
+/// Checks on upgrade requests beyond what it takes to answer them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Checks {
+    /// Require `Sec-WebSocket-Key` to be 24 base64 characters encoding 16
+    /// bytes, as RFC 6455 has it.
+    pub key: bool,
+    /// Names the `Host` header must carry, with or without a port. Any host
+    /// is accepted when empty.
+    pub hosts: Vec<String>,
+    /// Values the `Origin` header must have, such as
+    /// `https://example.com`. Requests from anywhere, and from non-browser
+    /// clients sending no `Origin`, are accepted when empty.
+    pub origins: Vec<String>,
+}
+
+/// The value of header `name` if there is one, refused if it is longer than
+/// `max` bytes.
+fn bounded<'a>(request: &'a Request<()>, name: &str, max: usize) -> Result<Option<&'a str>> {
+    let Some(value) = request.headers().get(name) else {
+        return Ok(None);
+    };
+    if value.len() > max {
+        return Err(Error::Handshake(format!(
+            "{name} header of {} bytes, limit is {max}",
+            value.len()
+        )));
+    }
+    Ok(Some(value.to_str()?))
+}
+
+impl Checks {
+    pub fn check(&self, request: &Request<()>) -> Result<()> {
+        let host = bounded(request, "Host", MAX_HOST)?;
+        let origin = bounded(request, "Origin", MAX_ORIGIN)?;
+        if !self.origins.is_empty() {
+            let origin = origin.ok_or_else(|| Error::ForbiddenOrigin(String::from("(none)")))?;
+            if !self
+                .origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+            {
+                return Err(Error::ForbiddenOrigin(origin.to_string()));
+            }
+        }
+        if !self.hosts.is_empty() {
+            let host = host.ok_or_else(|| Error::UnknownHost(String::from("(none)")))?;
+            let name = host.rsplit_once(':').map_or(host, |(name, _)| name);
+            if !self.hosts.iter().any(|allowed| {
+                allowed.eq_ignore_ascii_case(host) || allowed.eq_ignore_ascii_case(name)
+            }) {
+                return Err(Error::UnknownHost(host.to_string()));
+            }
+        }
+
+        // draft-76 requests carry no key, `response` insists on it otherwise
+        if let (true, Some(key)) = (self.key, request.headers().get("Sec-WebSocket-Key")) {
+            let key = key.as_bytes();
+            if key.len() != 24 {
+                return Err(Error::InvalidKey(format!(
+                    "{} characters instead of 24",
+                    key.len()
+                )));
+            }
+            match base64::decode(key) {
+                Ok(nonce) if nonce.len() == 16 => {}
+                Ok(nonce) => {
+                    return Err(Error::InvalidKey(format!(
+                        "decodes to {} bytes instead of 16",
+                        nonce.len()
+                    )))
+                }
+                Err(_) => return Err(Error::InvalidKey(String::from("not base64"))),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Response head for an upgrade request. Headers keep the order and casing they
+/// were added with, since some clients are picky about both.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    /// Bytes sent right after the head, which only legacy handshakes use.
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Response {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: Vec<u8>) -> Response {
+        self.body = body;
+        self
+    }
+
+    /// Serialize the response, returning the number of bytes written.
+    pub fn write(&self, output: &mut impl Write) -> Result<usize> {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status.as_str(),
+            self.status.canonical_reason().unwrap_or("")
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        output.write_all(head.as_bytes())?;
+        output.write_all(&self.body)?;
+        Ok(head.len() + self.body.len())
+    }
+}
+
+/// Status to refuse an upgrade with when the handshake fails.
+pub fn rejection_status(error: &Error) -> StatusCode {
+    match error {
+        Error::InvalidAffinity | Error::ForbiddenOrigin(_) => StatusCode::FORBIDDEN,
+        Error::WrongInstance(_) | Error::UnknownHost(_) => StatusCode::MISDIRECTED_REQUEST,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Build the `101 Switching Protocols` response for an upgrade request.
+pub fn response(request: &Request<()>) -> Result<Response> {
+    let key = request
+        .headers()
+        .get("Sec-WebSocket-Key")
+        .ok_or_else(|| Error::Handshake(String::from("Sec-Websocket-Key header not found")))?;
+
+    Ok(minimal(key.as_bytes()).header("Date", "Sat, 28 May 2022 18:12:34 GMT"))
+}
+
+/// The 101 response with only the headers RFC 6455 requires, always in this
+/// order, for clients that can't cope with more. It is 129 bytes long.
+pub fn minimal_response(request: &Request<()>) -> Result<Response> {
+    let key = request
+        .headers()
+        .get("Sec-WebSocket-Key")
+        .ok_or_else(|| Error::Handshake(String::from("Sec-Websocket-Key header not found")))?;
+    Ok(minimal(key.as_bytes()))
+}
+
+fn minimal(key: &[u8]) -> Response {
+    Response::new(StatusCode::SWITCHING_PROTOCOLS)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", accept_key(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heads_are_read_across_segments() {
+        let head = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let (first, second) = head.split_at(20);
+        let mut input = first.chain(second);
+        let mut buffer = [0; 4096];
+        assert_eq!(read_head(&mut input, &mut buffer).unwrap(), head.len());
+        assert_eq!(&buffer[..head.len()], head);
+
+        // a peer sending endless headers fills the buffer and no more
+        let mut input = io::repeat(b'a');
+        assert_eq!(read_head(&mut input, &mut buffer[..64]).unwrap(), 64);
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_6455() {
+        assert_eq!(
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        let request = parse_request("GET /chat HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        assert_eq!(request.uri().path(), "/chat");
+        assert_eq!(request.headers()["Host"], "example.com");
+    }
+
+    #[test]
+    fn control_characters_in_values_are_refused_but_tabs_pass() {
+        let tab = parse_request("GET / HTTP/1.1\r\nX-Note: a\tb\r\n\r\n").unwrap();
+        assert_eq!(tab.headers()["X-Note"], "a\tb");
+        assert!(matches!(
+            parse_request("GET / HTTP/1.1\r\nX-Note: a\x07b\r\n\r\n"),
+            Err(Error::ControlCharacter(name)) if name == "X-Note"
+        ));
+    }
+
+    #[test]
+    fn origins_must_be_allowed_when_any_are_listed() {
+        let checks = Checks {
+            origins: vec![String::from("https://example.com")],
+            ..Checks::default()
+        };
+        let with_origin = |origin: &str| {
+            let raw = format!("GET / HTTP/1.1\r\nOrigin: {origin}\r\n\r\n");
+            checks.check(&parse_request(&raw).unwrap())
+        };
+        assert!(with_origin("https://example.com").is_ok());
+        assert!(with_origin("HTTPS://EXAMPLE.COM").is_ok());
+        assert!(matches!(
+            with_origin("https://evil.example"),
+            Err(Error::ForbiddenOrigin(origin)) if origin == "https://evil.example"
+        ));
+        let none = parse_request("GET / HTTP/1.1\r\n\r\n").unwrap();
+        let refused = checks.check(&none).unwrap_err();
+        assert_eq!(rejection_status(&refused), StatusCode::FORBIDDEN);
+        assert!(Checks::default().check(&none).is_ok());
+    }
+
+    #[test]
+    fn host_and_origin_have_length_limits() {
+        let long = "a".repeat(MAX_HOST);
+        let raw = format!("GET / HTTP/1.1\r\nHost: {long}\r\n\r\n");
+        assert!(Checks::default()
+            .check(&parse_request(&raw).unwrap())
+            .is_ok());
+        for header in [format!("Host: {long}a"), format!("Origin: https://{long}a")] {
+            let raw = format!("GET / HTTP/1.1\r\n{header}\r\n\r\n");
+            let error = Checks::default()
+                .check(&parse_request(&raw).unwrap())
+                .unwrap_err();
+            assert!(matches!(error, Error::Handshake(_)), "{header}");
+        }
+    }
+
+    #[test]
+    fn control_characters_in_names_are_refused() {
+        for name in ["X-\x01Note", "X-\tNote", "X-\rNote"] {
+            let raw = format!("GET / HTTP/1.1\r\n{name}: value\r\n\r\n");
+            assert!(matches!(
+                parse_request(&raw),
+                Err(Error::ControlCharacter(escaped)) if escaped == name.escape_debug().to_string()
+            ));
+        }
+    }
+}
@@ -0,0 +1,38 @@
+//! Timestamps on echoed messages
+//!
+//! On a timestamped path, every echoed data message is followed by a Text
+//! message giving the time the server read it and the time it wrote the
+//! echo, so client SDKs can measure one-way latency and clock skew against
+//! the server's clock. The echo itself goes out unchanged, so clients that
+//! skip the extra message keep working.
+
+use crate::frame::{Data, Frame, OpCode};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// When the server handled one message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    /// When the message was read off the socket.
+    pub received: SystemTime,
+    /// When its echo was written.
+    pub sent: SystemTime,
+}
+
+fn unix_micros(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros())
+}
+
+impl Stamp {
+    /// The message sent after the echo, e.g.
+    /// `{"type":"timestamps","received_us":1700000000000000,"sent_us":1700000000000042}`,
+    /// in microseconds since the Unix epoch.
+    pub fn frame(&self) -> Frame {
+        let payload = format!(
+            r#"{{"type":"timestamps","received_us":{},"sent_us":{}}}"#,
+            unix_micros(self.received),
+            unix_micros(self.sent)
+        );
+        Frame::message(payload.into_bytes(), OpCode::Data(Data::Text))
+    }
+}
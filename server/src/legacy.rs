@@ -0,0 +1,212 @@
+//! Draft-76 (hybi-00) compatibility
+//!
+//! Old clients send `Sec-WebSocket-Key1` and `Sec-WebSocket-Key2` and an
+//! 8 byte body, and expect the MD5 of the three back after the response head.
+//! Messages are UTF-8 text between a `0x00` and a `0xFF` byte, and `0xFF 0x00`
+//! closes the connection.
+
+use crate::error::{Error, Result};
+use crate::handshake::Response;
+use crate::md5::md5;
+use http::{Request, StatusCode};
+use std::io::{BufRead, Read};
+
+/// Sent by either side to close the connection.
+pub const CLOSE: [u8; 2] = [0xFF, 0x00];
+
+/// Longest message the server reads, in bytes.
+pub const MAX_MESSAGE: usize = 1024 * 1024;
+
+/// Whether the request uses the draft-76 handshake rather than RFC 6455.
+pub fn is_hixie76(request: &Request<()>) -> bool {
+    let headers = request.headers();
+    headers.contains_key("Sec-WebSocket-Key1")
+        && headers.contains_key("Sec-WebSocket-Key2")
+        && !headers.contains_key("Sec-WebSocket-Key")
+}
+
+/// The number hidden in a key: its digits divided by its spaces.
+fn key_number(key: &str) -> Result<u32> {
+    let digits: String = key.chars().filter(char::is_ascii_digit).collect();
+    let spaces = key.chars().filter(|&c| c == ' ').count() as u64;
+    let number: u64 = digits
+        .parse()
+        .map_err(|_| Error::Handshake(format!("no number in key {key:?}")))?;
+    if spaces == 0 || !number.is_multiple_of(spaces) {
+        return Err(Error::Handshake(format!("malformed key {key:?}")));
+    }
+    u32::try_from(number / spaces).map_err(|_| Error::Handshake(format!("malformed key {key:?}")))
+}
+
+/// The 16 byte answer to a client's challenge.
+pub fn challenge_response(key1: &str, key2: &str, key3: &[u8; 8]) -> Result<[u8; 16]> {
+    let mut challenge = Vec::with_capacity(16);
+    challenge.extend_from_slice(&key_number(key1)?.to_be_bytes());
+    challenge.extend_from_slice(&key_number(key2)?.to_be_bytes());
+    challenge.extend_from_slice(key3);
+    Ok(md5(&challenge))
+}
+
+/// The 8 byte challenge that follows the request head. `received` is what has
+/// been read so far; the rest comes from `input`.
+pub fn read_key3(received: &[u8], input: &mut impl Read) -> Result<[u8; 8]> {
+    let body = received
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(&[][..], |end| &received[end + 4..]);
+    let mut key3 = [0; 8];
+    let taken = body.len().min(8);
+    key3[..taken].copy_from_slice(&body[..taken]);
+    input.read_exact(&mut key3[taken..])?;
+    Ok(key3)
+}
+
+/// Build the draft-76 `101` response, challenge answer included.
+pub fn response(request: &Request<()>, key3: &[u8; 8]) -> Result<Response> {
+    let header = |name: &str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| Error::Handshake(format!("{name} header not found")))
+    };
+    let answer = challenge_response(
+        header("Sec-WebSocket-Key1")?,
+        header("Sec-WebSocket-Key2")?,
+        key3,
+    )?;
+
+    let mut response = Response::new(StatusCode::SWITCHING_PROTOCOLS)
+        .header("Upgrade", "WebSocket")
+        .header("Connection", "Upgrade");
+    if let Ok(origin) = header("Origin") {
+        response = response.header("Sec-WebSocket-Origin", origin);
+    }
+    response = response.header(
+        "Sec-WebSocket-Location",
+        format!("ws://{}{}", header("Host")?, request.uri().path()),
+    );
+    if let Ok(protocol) = header("Sec-WebSocket-Protocol") {
+        response = response.header("Sec-WebSocket-Protocol", protocol);
+    }
+    Ok(response.body(answer.to_vec()))
+}
+
+/// Frame a text message.
+pub fn encode(text: &str) -> Vec<u8> {
+    let mut message = Vec::with_capacity(text.len() + 2);
+    message.push(0x00);
+    message.extend_from_slice(text.as_bytes());
+    message.push(0xFF);
+    message
+}
+
+/// Read up to the `0xFF` ending a message into `message`, dropping the `0xFF`.
+/// Fails with `Error::Capacity` once more than `max` bytes came without one.
+fn read_terminated(input: &mut impl BufRead, max: usize, message: &mut Vec<u8>) -> Result<()> {
+    input.take(max as u64 + 1).read_until(0xFF, message)?;
+    if message.pop() == Some(0xFF) {
+        return Ok(());
+    }
+    if message.len() >= max {
+        return Err(Error::Capacity(format!(
+            "legacy message exceeds the limit of {max}"
+        )));
+    }
+    Err(Error::Protocol(String::from("message cut short")))
+}
+
+/// Read the next text message of at most `max` bytes. `None` means the peer
+/// closed the connection, with or without the closing bytes. Messages of other
+/// types are skipped, as the draft asks.
+pub fn read_message(input: &mut impl BufRead, max: usize) -> Result<Option<String>> {
+    loop {
+        let mut kind = [0];
+        if input.read(&mut kind)? == 0 {
+            return Ok(None);
+        }
+        match kind[0] {
+            0x00 => {
+                let mut text = Vec::new();
+                read_terminated(input, max, &mut text)?;
+                return Ok(Some(String::from_utf8(text)?));
+            }
+            0xFF => {
+                let mut next = [0];
+                input.read_exact(&mut next)?;
+                if next[0] == 0x00 {
+                    return Ok(None);
+                }
+                return Err(Error::Protocol(format!("unexpected byte {:#04x}", next[0])));
+            }
+            kind if kind & 0x80 != 0 => {
+                // length in 7 bit groups, high bit set on all but the last
+                let mut length: u64 = 0;
+                loop {
+                    let mut byte = [0];
+                    input.read_exact(&mut byte)?;
+                    length = length
+                        .checked_mul(128)
+                        .ok_or_else(|| Error::Protocol(String::from("message too long")))?
+                        + u64::from(byte[0] & 0x7F);
+                    if byte[0] & 0x80 == 0 {
+                        break;
+                    }
+                }
+                std::io::copy(&mut input.by_ref().take(length), &mut std::io::sink())?;
+            }
+            _ => read_terminated(input, max, &mut Vec::new())?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_matches_the_draft_example() {
+        let answer = challenge_response(
+            "18x 6]8vM;54 *(5:  {   U1]8  z [  8",
+            "1_ tx7X d  <  nw  334J702) 7]o}` 0",
+            b"Tm[K T2u",
+        )
+        .unwrap();
+        assert_eq!(&answer, b"fQJ,fN/4F4!~K~MH");
+    }
+
+    #[test]
+    fn messages_are_read_and_other_types_skipped() {
+        let mut input = &b"\x00hello\xff\x80\x03abc\x01junk\xff\x00world\xff\xff\x00"[..];
+        assert_eq!(read_message(&mut input, 16).unwrap().unwrap(), "hello");
+        assert_eq!(read_message(&mut input, 16).unwrap().unwrap(), "world");
+        assert!(read_message(&mut input, 16).unwrap().is_none());
+    }
+
+    #[test]
+    fn message_without_an_end_stops_at_the_limit() {
+        let endless = std::io::repeat(b'a');
+        let mut input = std::io::BufReader::new((&b"\x00"[..]).chain(endless));
+        assert!(matches!(
+            read_message(&mut input, 1024),
+            Err(Error::Capacity(_))
+        ));
+
+        let mut input = &b"\x01skipped forever"[..];
+        assert!(matches!(
+            read_message(&mut input, 4),
+            Err(Error::Capacity(_))
+        ));
+    }
+
+    #[test]
+    fn message_at_the_limit_is_read() {
+        let mut input = &b"\x00abcd\xff"[..];
+        assert_eq!(read_message(&mut input, 4).unwrap().unwrap(), "abcd");
+        let mut input = &b"\x00abc"[..];
+        assert!(matches!(
+            read_message(&mut input, 4),
+            Err(Error::Protocol(_))
+        ));
+    }
+}
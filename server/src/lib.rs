@@ -0,0 +1,36 @@
+pub mod access_log;
+pub mod affinity;
+pub mod audit;
+pub mod checksum;
+pub mod client;
+pub mod close;
+pub mod config;
+pub mod conformance;
+pub mod dedupe;
+pub mod egress;
+pub mod error;
+pub mod extension;
+pub mod frame;
+pub mod handshake;
+pub mod latency;
+#[cfg(feature = "legacy")]
+pub mod legacy;
+pub mod lifetime;
+#[cfg(feature = "legacy")]
+mod md5;
+pub mod memory;
+pub mod mux;
+pub mod pool;
+pub mod profile;
+pub mod reaper;
+pub mod registry;
+pub mod rpc;
+mod sha256;
+pub mod sim;
+pub mod subframe;
+pub mod testing;
+pub mod throttle;
+pub mod trace;
+pub mod transfer;
+pub mod validate;
+pub mod writer;
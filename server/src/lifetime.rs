@@ -0,0 +1,26 @@
+//! Connection lifetime cap
+
+use crate::sim::Rng;
+use std::time::{Duration, Instant};
+
+/// Close code sent when a connection reaches its lifetime, asking the client
+/// to reconnect, likely to another instance.
+pub const SERVICE_RESTART: u16 = 1012;
+
+/// How long connections may stay open. Each connection gets a random amount
+/// of jitter taken off, so connections opened together don't all reconnect
+/// at the same moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lifetime {
+    pub max: Duration,
+    pub jitter: Duration,
+}
+
+impl Lifetime {
+    /// When a connection opened at `started` should be closed, with jitter
+    /// drawn from `rng`.
+    pub fn deadline(&self, started: Instant, rng: &dyn Rng) -> Instant {
+        let jitter = self.jitter.min(self.max).as_millis() as u64;
+        started + self.max - Duration::from_millis(rng.below_or_equal(jitter))
+    }
+}
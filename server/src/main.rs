@@ -1,108 +1,1176 @@
 extern crate base64;
-mod error;
-mod frame;
-use crate::frame::{Data as OpData, Frame, OpCode};
-use frame::{apply_mask, FrameHeader};
-use sha1::{Digest, Sha1};
-use std::io::Cursor;
-use std::io::{Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::str::Lines;
+use http::StatusCode;
+use server::access_log::{AccessLog, AccessRecord};
+use server::affinity::Affinity;
+use server::audit::{AuditLog, Event};
+use server::checksum::{self, Corrupted, Crc32Trailer, OnMismatch};
+use server::close::{self, ClosePolicy};
+use server::config::Config;
+use server::conformance;
+use server::dedupe::Recent;
+use server::egress::{Shaped, TokenBucket};
+use server::error::Error;
+use server::extension::{self, Pipeline};
+use server::frame::{Control, Data as OpData, Frame, OpCode};
+use server::handshake::{self, Checks, Response};
+use server::latency::Stamp;
+use server::lifetime;
+use server::memory::{self, Account};
+use server::mux::{self, Channel, Mux, Role};
+use server::profile::{self, Stage};
+use server::reaper::{self, Liveness, Tracked};
+use server::registry::Registry;
+use server::sim::Clock;
+use server::throttle::{self, OverLimit};
+use server::transfer::{self, Received};
+use server::validate::{self, Fragments, Reject, Reserved, Validator};
+use server::writer::{FrameWriter, Progress};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant};
 
-fn get_accept_key_header(lines: &mut Lines) -> Result<String, String> {
-    let magic_string = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Counts the bytes passing through it, for the access log.
+struct Counter<S> {
+    inner: S,
+    read: u64,
+    written: u64,
+}
 
-    for line in lines {
-        let fixed_line = line.to_string();
-        if fixed_line.to_lowercase().contains("sec-websocket-key") {
-            let (_, key) = fixed_line.split_at(19);
+impl<S> Counter<S> {
+    fn new(inner: S) -> Counter<S> {
+        Counter {
+            inner,
+            read: 0,
+            written: 0,
+        }
+    }
+}
 
-            let mut hasher = Sha1::new();
-            hasher.update(key);
-            hasher.update(magic_string);
-            let sha1 = hasher.finalize();
+impl<S: Read> Read for Counter<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.read += size as u64;
+        Ok(size)
+    }
+}
 
-            let b64 = base64::encode(&sha1);
+impl<S: Write> Write for Counter<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.written += size as u64;
+        Ok(size)
+    }
 
-            let output = format!("Sec-WebSocket-Accept: {b64}");
-            return Ok(output);
-        }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
-    Err(String::from("Sec-Websocket-Key header not found"))
 }
 
-fn handshake_response(mut stream: &TcpStream) {
+/// What the opening handshake settled.
+struct Upgrade {
+    path: String,
+    /// The peer spoke draft-76, so messages are framed with sentinel bytes.
+    legacy: bool,
+    /// CRC32 trailers were negotiated, and what to do with frames failing
+    /// them.
+    checksum: Option<OnMismatch>,
+    /// Follow each echo with the times the message was read and echoed.
+    timestamps: bool,
+}
+
+/// What the opening handshake checks and offers, the same for every
+/// connection.
+struct Handshake {
+    checks: Checks,
+    affinity: Option<Affinity>,
+    /// Accept CRC32 trailers, and what to do with frames failing them.
+    checksum: Option<OnMismatch>,
+    /// Paths answered with only the required 101 headers.
+    minimal: Vec<String>,
+    /// Paths whose echoes are followed by timestamps.
+    timestamps: Vec<String>,
+}
+
+fn handshake_response(
+    mut stream: &TcpStream,
+    settings: &Handshake,
+    audit: Option<&AuditLog>,
+    clock: &dyn Clock,
+    record: &mut AccessRecord,
+) -> Result<Upgrade, String> {
     let mut buffer = [0; 4096];
-    stream.read(&mut buffer).unwrap();
-    let request = String::from_utf8_lossy(&buffer[..]);
-    let mut lines = request.lines();
+    let size = handshake::read_head(&mut stream, &mut buffer).map_err(|e| e.to_string())?;
+    record.bytes_in += size as u64;
+    let request = String::from_utf8_lossy(&buffer[..size]);
     println!("{request}");
-    let accept_key_header = get_accept_key_header(&mut lines).unwrap();
 
-    let headers = [
-        "HTTP/1.1 101 Switching Protocols",
-        "Upgrade: websocket",
-        "Connection: Upgrade",
-        accept_key_header.as_str(),
-        "Date: Sat, 28 May 2022 18:12:34 GMT",
-        "\r\n",
-    ];
-    stream.write(&headers.join("\r\n").into_bytes()).ok();
+    let mut event = Event::new("upgrade", record.peer, clock);
+    let response = handshake::parse_request(&request).and_then(|request| {
+        record.set_request(&request);
+        if let Some(audit) = audit {
+            event.request(&request, audit.redaction());
+        }
+        settings.checks.check(&request)?;
+        if let Some(affinity) = &settings.affinity {
+            let checked = affinity.check(&request);
+            event.set("auth", if checked.is_ok() { "passed" } else { "failed" });
+            checked?;
+        }
+        let path = request.uri().path().to_string();
+        #[cfg(feature = "legacy")]
+        if server::legacy::is_hixie76(&request) {
+            let key3 = server::legacy::read_key3(&buffer[..size], &mut stream)?;
+            record.bytes_in += 8;
+            let response = server::legacy::response(&request, &key3)?;
+            let upgrade = Upgrade {
+                path,
+                legacy: true,
+                checksum: None,
+                timestamps: false,
+            };
+            return Ok((response, upgrade));
+        }
+        let timestamps = settings.timestamps.contains(&path);
+        if settings.minimal.contains(&path) {
+            return Ok((
+                handshake::minimal_response(&request)?,
+                Upgrade {
+                    path,
+                    legacy: false,
+                    checksum: None,
+                    timestamps,
+                },
+            ));
+        }
+        let mut response = handshake::response(&request)?;
+        if let Some(affinity) = &settings.affinity {
+            let (name, value) = affinity.response_header();
+            response = response.header(name, value);
+        }
+        let checksum = settings
+            .checksum
+            .filter(|_| extension::offered(&request, checksum::NAME));
+        if checksum.is_some() {
+            response = response.header("Sec-WebSocket-Extensions", checksum::NAME);
+        }
+        Ok((
+            response,
+            Upgrade {
+                path,
+                legacy: false,
+                checksum,
+                timestamps,
+            },
+        ))
+    });
+    let (response, result) = match response {
+        Ok((response, upgrade)) => (response, Ok(upgrade)),
+        Err(error) => (
+            Response::new(handshake::rejection_status(&error)),
+            Err(error.to_string()),
+        ),
+    };
+
+    record.status = response.status.as_u16();
+    if let Some(audit) = audit {
+        event
+            .set(
+                "outcome",
+                if result.is_ok() {
+                    "accepted"
+                } else {
+                    "rejected"
+                },
+            )
+            .set("status", record.status);
+        if let Err(reason) = &result {
+            event.set("reason", reason.as_str());
+        }
+        audit.write(&event);
+    }
+    record.bytes_out += response.write(&mut stream).map_err(|e| e.to_string())? as u64;
+    result
+}
+
+/// The write half of a connection. A frame the socket didn't take whole stays
+/// pending in the one `FrameWriter`, and goes out ahead of the next frame.
+struct Outbox<W> {
+    output: W,
+    frames: FrameWriter,
 }
 
-fn handle_client(mut stream: TcpStream) {
-    let mut data = [0_u8; 4096];
-    while match stream.read(&mut data) {
-        Ok(size) => {
-            let mut raw: Cursor<Vec<u8>> = Cursor::new(data.into());
+impl<W: Write> Outbox<W> {
+    fn new(output: W) -> Outbox<W> {
+        Outbox {
+            output,
+            frames: FrameWriter::default(),
+        }
+    }
+
+    fn get_ref(&self) -> &W {
+        &self.output
+    }
+
+    /// Send a frame behind whatever is still pending, returning its size.
+    fn send(&mut self, frame: Frame) -> io::Result<u64> {
+        self.resume()?;
+        let size = self.frames.queue(frame);
+        self.frames.flush_to(&mut self.output)?;
+        Ok(size as u64)
+    }
+
+    /// Write what an earlier send left pending. Sockets here block, so
+    /// `Progress::Pending` means a write timeout ran out; it only fails once
+    /// one runs out without the peer taking a single byte.
+    fn resume(&mut self) -> io::Result<()> {
+        let stalled = self.frames.pending();
+        if stalled > 0
+            && self.frames.flush_to(&mut self.output)? == Progress::Pending
+            && self.frames.pending() == stalled
+        {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        Ok(())
+    }
+
+    /// Write everything still pending, for as long as the peer keeps taking it.
+    fn finish(&mut self) -> io::Result<()> {
+        while self.frames.pending() > 0 {
+            self.resume()?;
+        }
+        Ok(())
+    }
+}
 
-            let (header, length) = FrameHeader::parse(&mut raw).unwrap().unwrap();
+/// The status code of a Close frame, if it carries one.
+fn close_code(frame: &Frame) -> Option<u16> {
+    frame
+        .payload()
+        .get(..2)
+        .map(|code| u16::from_be_bytes([code[0], code[1]]))
+}
 
-            let mut payload = Vec::new();
-            payload.resize(length as _, 0);
-            raw.read_exact(&mut payload).unwrap();
+/// Note a Close the server sends on its own initiative, for the audit log.
+fn forced(record: &mut AccessRecord, frame: Frame) -> Frame {
+    if let Some(code) = close_code(&frame) {
+        let reason = String::from_utf8_lossy(&frame.payload()[2..]).into_owned();
+        record.forced_close = Some((code, reason));
+    }
+    frame
+}
 
-            if let Some(mask) = header.mask {
-                apply_mask(&mut payload, mask);
+/// Answer the peer's Close frame, noting its code in `record`. A valid one is
+/// echoed, one breaking the rules of `close::status` is answered with the
+/// close code for its error.
+fn close_reply(record: &mut AccessRecord, close_policy: &ClosePolicy, close: &Frame) -> Frame {
+    record.close_code = close_code(close);
+    match close::status(close) {
+        Ok(code) => close::echo(code),
+        Err(error) => forced(record, close_policy.frame(&error)),
+    }
+}
+
+/// How often silent connections are looked for.
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the profile file is rewritten.
+#[cfg(feature = "profile")]
+const PROFILE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the memory report is rewritten.
+#[cfg(feature = "alloc-accounting")]
+const MEMORY_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the peer gets to answer our Close before the connection is dropped.
+const CLOSE_GRACE: Duration = Duration::from_secs(5);
+
+/// How long a client gets to send its upgrade request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a refused client gets to send its request before the 429 goes
+/// out anyway.
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the rest of a frame may take to arrive once its first byte did.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Per-connection limits, settled when the connection is accepted.
+struct Limits {
+    /// When the connection reaches its lifetime.
+    deadline: Option<Instant>,
+    /// Allowance for bytes sent to the peer.
+    egress: Option<TokenBucket>,
+    /// Ping the peer after it has been quiet this long.
+    ping_interval: Option<Duration>,
+    /// What the reaper judges the connection by.
+    liveness: Arc<Liveness>,
+    clock: Arc<dyn Clock>,
+}
+
+/// What a handler owes the connection before its next read.
+enum Due {
+    /// Nothing yet; wait this long at most for the next frame.
+    Wait(Option<Duration>),
+    Ping,
+    Retire,
+    /// A fragmented message ran out of time to finish.
+    Abandon,
+}
+
+/// Whichever comes first of the lifetime deadline, the next Ping or the end of
+/// the time left to finish a fragmented message, if it is already due, or how
+/// long until it is. `pinged` is when the last Ping went out.
+fn due(limits: &Limits, pinged: Option<Instant>, unfinished: Option<Instant>) -> Due {
+    let now = limits.clock.now();
+    let ping_at = limits.ping_interval.map(|interval| {
+        let heard = limits.liveness.last_heard();
+        pinged.map_or(heard, |pinged| pinged.max(heard)) + interval
+    });
+    if limits.deadline.is_some_and(|deadline| deadline <= now) {
+        return Due::Retire;
+    }
+    if unfinished.is_some_and(|unfinished| unfinished <= now) {
+        return Due::Abandon;
+    }
+    if ping_at.is_some_and(|ping_at| ping_at <= now) {
+        return Due::Ping;
+    }
+    let wake = [limits.deadline, ping_at, unfinished]
+        .into_iter()
+        .flatten()
+        .min();
+    Due::Wait(wake.map(|wake| wake - now))
+}
+
+fn ping() -> Frame {
+    Frame::message(Vec::new(), OpCode::Control(Control::Ping))
+}
+
+/// Wait up to `wait` on `clock`, or for good with `None`, for the next frame
+/// to start, telling whether it did. Deadlines are only looked at between
+/// frames: `Frame::parse` can't resume a frame a timeout cut short, so once a
+/// frame started the rest of it gets `FRAME_TIMEOUT`, and running out of that
+/// is an error like any other.
+fn frame_started(
+    stream: &TcpStream,
+    clock: &dyn Clock,
+    wait: Option<Duration>,
+) -> io::Result<bool> {
+    stream.set_read_timeout(wait.map(|wait| clock.real_timeout(wait)))?;
+    match stream.peek(&mut [0]) {
+        Ok(_) => {
+            stream.set_read_timeout(Some(FRAME_TIMEOUT))?;
+            Ok(true)
+        }
+        Err(error)
+            if matches!(
+                error.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            if let Some(wait) = wait {
+                clock.timed_out(wait);
             }
+            Ok(false)
+        }
+        Err(error) => Err(error),
+    }
+}
 
-            let frame = Frame::message(payload, OpCode::Data(OpData::Text));
+fn handle_client(
+    stream: TcpStream,
+    peer: SocketAddr,
+    upgrade: &Upgrade,
+    validator: &Validator,
+    close_policy: &ClosePolicy,
+    limits: Limits,
+    record: &mut AccessRecord,
+) {
+    let mut extensions = Pipeline::default();
+    if let Some(on_mismatch) = upgrade.checksum {
+        extensions.push(Box::new(Crc32Trailer::new(on_mismatch)));
+    }
+    let mut reader = Counter::new(&stream);
+    let mut writer = Outbox::new(Shaped::new(
+        &stream,
+        limits.egress.clone(),
+        limits.clock.clone(),
+    ));
+    // no frame may carry more than a whole message, plus its trailer
+    let max_payload = validator.max_payload(checksum::TRAILER);
+    // set once we sent Close and only wait for the peer's
+    let mut retiring = false;
+    // when the peer must have answered our Close by
+    let mut grace_ends: Option<Instant> = None;
+    let mut pinged = None;
+    let mut fragments = Fragments::default();
+    let mut recent = Recent::default();
+    loop {
+        let wait = if let Some(grace_ends) = grace_ends {
+            // frames that keep coming don't buy the peer more time
+            let left = grace_ends.saturating_duration_since(limits.clock.now());
+            if left.is_zero() {
+                break;
+            }
+            Some(left)
+        } else {
+            match due(&limits, pinged, fragments.deadline(validator)) {
+                Due::Wait(wait) => wait,
+                Due::Abandon => {
+                    let error = validate::reassembly_timeout();
+                    let mut frame = forced(record, close_policy.frame(&error));
+                    extensions.encode(&mut frame).unwrap();
+                    record.bytes_out += writer.send(frame).unwrap_or(0);
+                    break;
+                }
+                Due::Ping => {
+                    let mut frame = ping();
+                    extensions.encode(&mut frame).unwrap();
+                    match writer.send(frame) {
+                        Ok(size) => record.bytes_out += size,
+                        Err(error) => {
+                            println!("Write to {peer} failed: {error}");
+                            stream.shutdown(Shutdown::Both).ok();
+                            break;
+                        }
+                    }
+                    pinged = Some(limits.clock.now());
+                    continue;
+                }
+                Due::Retire => {
+                    let mut frame = forced(
+                        record,
+                        Frame::close(lifetime::SERVICE_RESTART, "connection lifetime reached"),
+                    );
+                    extensions.encode(&mut frame).unwrap();
+                    record.bytes_out += writer.send(frame).unwrap_or(0);
+                    grace_ends = Some(limits.clock.now() + CLOSE_GRACE);
+                    retiring = true;
+                    Some(CLOSE_GRACE)
+                }
+            }
+        };
+        match frame_started(&stream, &*limits.clock, wait) {
+            Ok(true) => {}
+            Ok(false) if !retiring => continue,
+            // the peer never answered our Close
+            Ok(false) => break,
+            Err(error) => {
+                println!("Read from {peer} failed: {error}");
+                break;
+            }
+        }
+        let mut frame = match Frame::parse_masked(&mut reader, max_payload) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(error) => {
+                println!("An error occurred, terminating connection with {}", peer);
+                if error.downcast_ref::<io::Error>().is_none() {
+                    let error = match error.downcast::<Error>() {
+                        Ok(error) => *error,
+                        Err(error) => Error::Protocol(error.to_string()),
+                    };
+                    writer.send(forced(record, close_policy.frame(&error))).ok();
+                }
+                stream.shutdown(Shutdown::Both).ok();
+                break;
+            }
+        };
+        limits.liveness.heard();
+        let received = upgrade.timestamps.then(|| limits.clock.system_time());
+        let metadata = match profile::time(Stage::Decode, || extensions.decode(&mut frame)) {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                let frame = forced(record, close_policy.frame(&error));
+                record.bytes_out += writer.send(frame).unwrap_or(0);
+                break;
+            }
+        };
+        if metadata.get::<Corrupted>().is_some() {
+            println!("Dropped a corrupted frame from {peer}");
+            continue;
+        }
 
-            let mut out_buffer: Vec<u8> = Vec::new();
-            frame
-                .format(&mut out_buffer)
-                .expect("can't write to vector");
+        if retiring {
+            if frame.header().opcode == OpCode::Control(Control::Close) {
+                record.close_code = close_code(&frame);
+                break;
+            }
+            continue;
+        }
+        // fragment limits close the connection whatever `reject` says, the
+        // rest of the message would be refused anyway
+        let now = limits.clock.now();
+        let frame = match validator.reassemble(&mut fragments, frame, now) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => continue,
+            Err(error) => {
+                let mut frame = forced(record, close_policy.frame(&error));
+                extensions.encode(&mut frame).unwrap();
+                record.bytes_out += writer.send(frame).unwrap_or(0);
+                break;
+            }
+        };
+        let dedupe = validator.dedupe.as_ref();
+        if dedupe.is_some_and(|dedupe| dedupe.is_duplicate(&mut recent, &frame)) {
+            continue;
+        }
+
+        let closing = frame.header().opcode == OpCode::Control(Control::Close);
+        // only echoes of valid data messages get timestamps
+        let mut echoed = false;
+        let reserved = validate::reserved(&frame);
+        let mut frame = if let Some(opcode) = reserved {
+            match validator.reserved {
+                Reserved::Fail => {
+                    let error = Error::Protocol(format!("reserved opcode {opcode:#x}"));
+                    let mut frame = forced(record, close_policy.frame(&error));
+                    extensions.encode(&mut frame).unwrap();
+                    record.bytes_out += writer.send(frame).unwrap_or(0);
+                    break;
+                }
+                Reserved::Drop => {
+                    println!("Dropped a frame with reserved opcode {opcode:#x} from {peer}");
+                    continue;
+                }
+                // the handler gets the payload, the wire never sees the opcode
+                Reserved::Deliver => {
+                    println!("Delivered a frame with reserved opcode {opcode:#x} from {peer}");
+                    Frame::message(frame.into_payload(), OpCode::Data(OpData::Binary))
+                }
+            }
+        } else if closing {
+            close_reply(record, close_policy, &frame)
+        } else if frame.header().opcode == OpCode::Control(Control::Ping) {
+            Frame::message(frame.into_payload(), OpCode::Control(Control::Pong))
+        } else if frame.header().opcode == OpCode::Control(Control::Pong) {
+            continue;
+        } else if let Err(error) =
+            profile::time(Stage::Validate, || validator.check(&upgrade.path, &frame))
+        {
+            match validator.reject {
+                Reject::Drop => continue,
+                Reject::Reply => Frame::message(
+                    format!("invalid message: {error}").into_bytes(),
+                    OpCode::Data(OpData::Text),
+                ),
+                Reject::Close => {
+                    let mut frame = forced(record, close_policy.frame(&error));
+                    extensions.encode(&mut frame).unwrap();
+                    record.bytes_out += writer.send(frame).unwrap_or(0);
+                    break;
+                }
+            }
+        } else {
+            echoed = true;
+            let opcode = frame.header().opcode;
+            Frame::message(frame.into_payload(), opcode)
+        };
+        let sent = profile::time(Stage::Dispatch, || {
+            extensions.encode(&mut frame).unwrap();
+            writer.send(frame)
+        });
+        let stamp = received.filter(|_| echoed).map(|received| Stamp {
+            received,
+            sent: limits.clock.system_time(),
+        });
+        let sent = sent.and_then(|size| match stamp {
+            Some(stamp) => {
+                let mut frame = stamp.frame();
+                extensions.encode(&mut frame).unwrap();
+                Ok(size + writer.send(frame)?)
+            }
+            None => Ok(size),
+        });
+        match sent {
+            Ok(size) => record.bytes_out += size,
+            Err(error) => {
+                println!("Write to {peer} failed: {error}");
+                stream.shutdown(Shutdown::Both).ok();
+                break;
+            }
+        }
+        if closing {
+            break;
+        }
+    }
+    writer.finish().ok();
+    record.bytes_in += reader.read;
+}
+
+/// Echo text messages back to a draft-76 peer until it closes the connection.
+#[cfg(feature = "legacy")]
+fn handle_legacy_client(stream: TcpStream, peer: SocketAddr, record: &mut AccessRecord) {
+    use server::legacy;
+
+    let mut writer = Counter::new(&stream);
+    let mut reader = io::BufReader::new(Counter::new(&stream));
+    loop {
+        match legacy::read_message(&mut reader, legacy::MAX_MESSAGE) {
+            Ok(Some(text)) => {
+                if writer.write_all(&legacy::encode(&text)).is_err() {
+                    break;
+                }
+            }
+            Ok(None) => {
+                writer.write_all(&legacy::CLOSE).ok();
+                break;
+            }
+            Err(error) => {
+                println!("Legacy connection with {peer} failed: {error}");
+                stream.shutdown(Shutdown::Both).ok();
+                break;
+            }
+        }
+    }
+    record.bytes_in += reader.get_ref().read;
+    record.bytes_out += writer.written;
+}
+
+/// Take the next frame off a writer queue. With a non-zero `spin` the writer
+/// polls that long before going to sleep, trading a busy core for not having
+/// to be woken up when a frame arrives soon.
+fn next_frame(queue: &mpsc::Receiver<Frame>, spin: Duration) -> Option<Frame> {
+    if !spin.is_zero() {
+        let started = Instant::now();
+        while started.elapsed() < spin {
+            match queue.try_recv() {
+                Ok(frame) => return Some(frame),
+                Err(mpsc::TryRecvError::Disconnected) => return None,
+                Err(mpsc::TryRecvError::Empty) => std::hint::spin_loop(),
+            }
+        }
+    }
+    queue.recv().ok()
+}
+
+/// How mux connections are served.
+struct MuxSettings {
+    window: u32,
+    max_channels: usize,
+    writer_spin: Duration,
+    /// Applied to each channel's payloads.
+    validator: Arc<Validator>,
+}
+
+/// Echo a mux channel's payloads back on it, validating each as a Binary
+/// message received on `path`.
+fn echo_channel(channel: Channel, validator: &Validator, path: &str) {
+    let mut recent = Recent::default();
+    while let Some(data) = channel.recv() {
+        let message = Frame::message(data, OpCode::Data(OpData::Binary));
+        let dedupe = validator.dedupe.as_ref();
+        if dedupe.is_some_and(|dedupe| dedupe.is_duplicate(&mut recent, &message)) {
+            continue;
+        }
+        let reply = match validator.check(path, &message) {
+            Ok(()) => message.into_payload(),
+            Err(error) => match validator.reject {
+                Reject::Drop => continue,
+                Reject::Reply => format!("invalid message: {error}").into_bytes(),
+                Reject::Close => {
+                    channel.close().ok();
+                    return;
+                }
+            },
+        };
+        if channel.send(&reply).is_err() {
+            break;
+        }
+    }
+}
+
+/// Serve a connection speaking the mux layer. Each channel the peer opens is
+/// echoed on its own thread, and a writer thread drains the shared queue.
+fn handle_mux_client(
+    stream: TcpStream,
+    peer: SocketAddr,
+    path: &str,
+    settings: &MuxSettings,
+    close_policy: &ClosePolicy,
+    limits: Limits,
+    record: &mut AccessRecord,
+) {
+    let writer_stream = match stream.try_clone() {
+        Ok(writer_stream) => writer_stream,
+        Err(_) => return,
+    };
+    let (outbound, queue) = mpsc::sync_channel::<Frame>(mux::QUEUE_LENGTH);
+    let mut writer_stream = Outbox::new(Shaped::new(
+        writer_stream,
+        limits.egress.clone(),
+        limits.clock.clone(),
+    ));
+    let writer_spin = settings.writer_spin;
+    let writer = memory::spawn(move || {
+        let mut sent = 0;
+        while let Some(frame) = next_frame(&queue, writer_spin) {
+            match writer_stream.send(frame) {
+                Ok(size) => sent += size,
+                Err(error) => {
+                    // unblock the reader too, the connection is no use now
+                    println!("Write to {peer} failed: {error}");
+                    writer_stream
+                        .get_ref()
+                        .get_ref()
+                        .shutdown(Shutdown::Both)
+                        .ok();
+                    break;
+                }
+            }
+        }
+        writer_stream.finish().ok();
+        sent
+    });
+
+    let mut mux = Mux::with_window(Role::Server, outbound.clone(), settings.window);
+    mux.set_max_channels(settings.max_channels);
+    // a mux message carries at most one channel payload
+    let max_payload = settings.validator.max_payload(mux::HEADER_LENGTH);
+    let mut reader = Counter::new(&stream);
+    let mut retiring = false;
+    let mut grace_ends: Option<Instant> = None;
+    let mut pinged = None;
+    loop {
+        let wait = if let Some(grace_ends) = grace_ends {
+            let left = grace_ends.saturating_duration_since(limits.clock.now());
+            if left.is_zero() {
+                break;
+            }
+            Some(left)
+        } else {
+            match due(&limits, pinged, None) {
+                Due::Wait(wait) => wait,
+                // there is no fragmented message to run out of time
+                Due::Abandon => unreachable!(),
+                Due::Ping => {
+                    outbound.send(ping()).ok();
+                    pinged = Some(limits.clock.now());
+                    continue;
+                }
+                Due::Retire => {
+                    let frame =
+                        Frame::close(lifetime::SERVICE_RESTART, "connection lifetime reached");
+                    outbound.send(forced(record, frame)).ok();
+                    grace_ends = Some(limits.clock.now() + CLOSE_GRACE);
+                    retiring = true;
+                    Some(CLOSE_GRACE)
+                }
+            }
+        };
+        match frame_started(&stream, &*limits.clock, wait) {
+            Ok(true) => {}
+            Ok(false) if !retiring => continue,
+            Ok(false) => break,
+            Err(error) => {
+                println!("Read from {peer} failed: {error}");
+                break;
+            }
+        }
+        let frame = match Frame::parse_masked(&mut reader, max_payload) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(error) => {
+                println!("An error occurred, terminating connection with {}", peer);
+                if let Ok(error) = error.downcast::<Error>() {
+                    outbound
+                        .send(forced(record, close_policy.frame(&error)))
+                        .ok();
+                    break;
+                }
+                stream.shutdown(Shutdown::Both).ok();
+                break;
+            }
+        };
+        limits.liveness.heard();
+        // mux connections negotiate no extensions
+        if let Err(error) = extension::unclaimed_bits(&frame) {
+            outbound
+                .send(forced(record, close_policy.frame(&error)))
+                .ok();
+            break;
+        }
 
-            stream.write_all(&out_buffer).unwrap();
-            stream.flush().unwrap();
-            true
+        match frame.header().opcode {
+            OpCode::Control(Control::Close) if retiring => {
+                record.close_code = close_code(&frame);
+                break;
+            }
+            _ if retiring => {}
+            OpCode::Control(Control::Close) => {
+                outbound
+                    .send(close_reply(record, close_policy, &frame))
+                    .ok();
+                break;
+            }
+            OpCode::Control(Control::Ping) => {
+                let pong = Frame::message(frame.into_payload(), OpCode::Control(Control::Pong));
+                outbound.send(pong).ok();
+            }
+            OpCode::Control(_) => {}
+            OpCode::Data(OpData::Binary) => {
+                match profile::time(Stage::Dispatch, || mux.receive(frame.payload())) {
+                    Ok(Some(channel)) => {
+                        let validator = settings.validator.clone();
+                        let path = path.to_owned();
+                        memory::spawn(move || echo_channel(channel, &validator, &path));
+                    }
+                    Ok(None) => {}
+                    Err(error) => {
+                        println!("Mux error from {peer}: {error}");
+                        outbound
+                            .send(forced(record, close_policy.frame(&error)))
+                            .ok();
+                        break;
+                    }
+                }
+            }
+            OpCode::Data(_) => {
+                let error =
+                    Error::Unsupported(String::from("mux connections carry Binary messages"));
+                outbound
+                    .send(forced(record, close_policy.frame(&error)))
+                    .ok();
+                break;
+            }
         }
-        Err(_) => {
-            println!(
-                "An error occurred, terminating connection with {}",
-                stream.peer_addr().unwrap()
-            );
-            stream.shutdown(Shutdown::Both).unwrap();
-            false
+    }
+
+    let stats = mux.stats();
+    if stats.stalls() > 0 {
+        println!(
+            "Senders to {peer} waited for credit {} times, {:?} in total",
+            stats.stalls(),
+            stats.stalled_for()
+        );
+    }
+
+    // dropping the mux ends every channel, which in turn lets the writer finish
+    drop(mux);
+    drop(outbound);
+    record.bytes_out += writer.join().unwrap_or(0);
+    record.bytes_in += reader.read;
+}
+
+/// Receive files of at most `max_size` bytes pushed by the peer into `dir`,
+/// one after the other, until it closes the connection.
+fn handle_transfer_client(
+    stream: TcpStream,
+    peer: SocketAddr,
+    dir: &Path,
+    max_size: u64,
+    close_policy: &ClosePolicy,
+    record: &mut AccessRecord,
+) {
+    let mut counter = Counter::new(&stream);
+    let mut outbox = Outbox::new(&stream);
+    loop {
+        match transfer::receive_file(&mut counter, dir, max_size, |_, _| {}) {
+            Ok(Received::File(path)) => println!("Received {} from {peer}", path.display()),
+            Ok(Received::Closed(code)) => {
+                record.close_code = code;
+                outbox.send(close::echo(code)).ok();
+                break;
+            }
+            Err(error) => {
+                println!("Transfer from {peer} failed: {error}");
+                outbox.send(forced(record, close_policy.frame(&error))).ok();
+                break;
+            }
         }
-    } {}
+    }
+    outbox.finish().ok();
+    record.bytes_in += counter.read;
+    record.bytes_out += counter.written;
+}
+
+/// Turn away a connection over a handshake limit.
+fn refuse(mut stream: TcpStream, over_limit: OverLimit, retry_after: Duration) {
+    match over_limit {
+        OverLimit::Reset => throttle::reset(stream),
+        // off the accept thread, a client that sends nothing mustn't hold up
+        // the ones behind it
+        OverLimit::Reply => {
+            thread::spawn(move || {
+                // read the request first, closing with unread data would
+                // reset the connection and lose the response
+                stream.set_read_timeout(Some(REFUSE_TIMEOUT)).ok();
+                let _ = stream.read(&mut [0; 4096]);
+                Response::new(StatusCode::TOO_MANY_REQUESTS)
+                    .header("Retry-After", retry_after.as_secs().to_string())
+                    .header("Connection", "close")
+                    .write(&mut stream)
+                    .ok();
+            });
+        }
+    }
+}
+
+/// `server conformance <URL>`: check another server, print the matrix and
+/// exit with 1 if any case failed.
+fn conformance(url: Option<String>) -> ! {
+    let url = url.unwrap_or_else(|| {
+        eprintln!("conformance needs a ws:// URL");
+        std::process::exit(2);
+    });
+    let outcomes = conformance::run(&url).unwrap_or_else(|error| {
+        eprintln!("{error}");
+        std::process::exit(2);
+    });
+    print!("{}", conformance::matrix(&outcomes));
+    std::process::exit(if outcomes.iter().all(|outcome| outcome.passed()) {
+        0
+    } else {
+        1
+    });
 }
 
 fn main() {
-    let listener = TcpListener::bind("0.0.0.0:3333").unwrap();
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("conformance") {
+        conformance(args.nth(1));
+    }
+    let config = Config::from_args(args).unwrap_or_else(|error| {
+        eprintln!("{error}");
+        std::process::exit(2);
+    });
+    let sources = config.sources();
+    let access_log = config.access_log.as_ref().map(|target| {
+        Arc::new(AccessLog::open(target, sources.clock.clone()).expect("can't open access log"))
+    });
+    let audit_log = config.audit_log.as_ref().map(|target| {
+        Arc::new(AuditLog::open(target, config.redaction()).expect("can't open audit log"))
+    });
+
+    let handshake = Arc::new(Handshake {
+        checks: config.checks(),
+        affinity: config.affinity(),
+        // only the echo handler runs an extension pipeline
+        checksum: config
+            .crc32_trailer
+            .filter(|_| !config.mux && config.receive_dir.is_none()),
+        minimal: config.minimal_response.clone(),
+        timestamps: config.timestamps.clone(),
+    });
+    let validator = Arc::new(config.validator());
+    let mux = config.mux.then(|| {
+        Arc::new(MuxSettings {
+            window: config.mux_window,
+            max_channels: config.mux_channels,
+            writer_spin: config.writer_spin,
+            validator: validator.clone(),
+        })
+    });
+    let receive_dir = config.receive_dir.clone().map(Arc::new);
+    let receive_max = config.receive_max;
+    let mut throttle = config.throttle();
+    let lifetime = config.lifetime();
+    let close_policy = config.close_policy;
+    let close_codes = Arc::new(config.close_codes.clone());
+    // a handle on every upgraded connection, so they can be reached from
+    // outside their own thread
+    let connections: Arc<Registry<Tracked>> = Arc::default();
+    let keepalive = config.keepalive();
+    if let Some(interval) = config.ping_interval {
+        let connections = connections.clone();
+        let audit_log = audit_log.clone();
+        let clock = sources.clock.clone();
+        let limit = interval + config.pong_timeout;
+        thread::spawn(move || loop {
+            thread::sleep(REAP_INTERVAL);
+            for (peer, silent_for) in reaper::reap(&connections, limit) {
+                println!("Reaped {peer}, silent for {silent_for:?}");
+                if let Some(audit) = &audit_log {
+                    audit.write(
+                        Event::new("reaped", peer, &*clock)
+                            .set("silent_ms", silent_for.as_millis() as u64),
+                    );
+                }
+            }
+        });
+    }
+
+    #[cfg(feature = "profile")]
+    if let Some(path) = config.profile.clone() {
+        thread::spawn(move || loop {
+            thread::sleep(PROFILE_INTERVAL);
+            if let Err(error) = std::fs::write(&path, profile::folded()) {
+                println!("Can't write profile to {}: {error}", path.display());
+            }
+        });
+    }
+
+    #[cfg(feature = "alloc-accounting")]
+    if let Some(path) = config.memory_report.clone() {
+        let connections = connections.clone();
+        thread::spawn(move || loop {
+            thread::sleep(MEMORY_REPORT_INTERVAL);
+            if let Err(error) = std::fs::write(&path, memory::report(&connections)) {
+                println!("Can't write memory report to {}: {error}", path.display());
+            }
+        });
+    }
+
+    let listener = TcpListener::bind(&config.addr).unwrap();
     // accept connections and process them, spawning a new thread for each one
-    println!("Server listening on port 3333");
+    println!("Server listening on {}", config.addr);
+    let mut warmup = config.warmup(sources.clock.now());
 
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                println!("New connection: {}", stream.peer_addr().unwrap());
-                handshake_response(&stream);
+                // the peer may already be gone by the time we get here
+                let peer = match stream.peer_addr() {
+                    Ok(peer) => peer,
+                    Err(_) => continue,
+                };
+                if let Some(throttle) = &mut throttle {
+                    if !throttle.admit(peer.ip(), sources.clock.now()) {
+                        if let Some(audit) = &audit_log {
+                            audit.write(
+                                Event::new("refused", peer, &*sources.clock)
+                                    .set("reason", "handshake limit"),
+                            );
+                        }
+                        refuse(stream, config.over_limit, throttle.window());
+                        continue;
+                    }
+                }
+                // the handshake waits for its turn in its own thread, so accepting
+                // goes on meanwhile
+                let mut wait = Duration::ZERO;
+                if let Some(pacing) = &mut warmup {
+                    let now = sources.clock.now();
+                    if pacing.is_over(now) {
+                        println!(
+                            "Warm-up over, {} of {} handshakes deferred for {:?} in total",
+                            pacing.deferred(),
+                            pacing.handshakes(),
+                            pacing.deferred_for()
+                        );
+                        warmup = None;
+                    } else {
+                        wait = pacing.pace(now);
+                    }
+                }
 
+                let handshake = handshake.clone();
+                let access_log = access_log.clone();
+                let audit_log = audit_log.clone();
+                let close_codes = close_codes.clone();
+                let connections = connections.clone();
+                let validator = validator.clone();
+                let mux = mux.clone();
+                let receive_dir = receive_dir.clone();
+                let sources = sources.clone();
+                let egress = config.egress();
+                let ping_interval = config.ping_interval;
+                let write_timeout = config.write_timeout;
                 thread::spawn(move || {
+                    sources.clock.sleep(wait);
+                    println!("New connection: {}", peer);
+                    if let Err(error) = reaper::tune(&stream, keepalive, write_timeout) {
+                        println!("Can't tune socket of {peer}: {error}");
+                    }
+                    let mut record = AccessRecord::new(peer, &*sources.clock);
+                    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok();
+                    let upgrade = match handshake_response(
+                        &stream,
+                        &handshake,
+                        audit_log.as_deref(),
+                        &*sources.clock,
+                        &mut record,
+                    ) {
+                        Ok(upgrade) => upgrade,
+                        Err(error) => {
+                            println!("Handshake failed: {error}");
+                            if let Some(log) = &access_log {
+                                log.write(&record);
+                            }
+                            return;
+                        }
+                    };
+                    // the handlers arm their own timeouts, if any
+                    stream.set_read_timeout(None).ok();
+
+                    let liveness = Arc::new(Liveness::new(sources.clock.clone()));
+                    // only the echo and mux handlers ping and listen for replies
+                    let pinged = !upgrade.legacy && receive_dir.is_none();
+                    let account = Arc::new(Account::default());
+                    let _entered = memory::enter(&account);
+                    let id = stream
+                        .try_clone()
+                        .map(|handle| {
+                            connections.insert(Tracked {
+                                stream: handle,
+                                peer,
+                                liveness: pinged.then(|| liveness.clone()),
+                                memory: account.clone(),
+                            })
+                        })
+                        .ok();
+                    println!("Upgraded {peer}, {} connections open", connections.len());
+                    let limits = Limits {
+                        deadline: lifetime
+                            .map(|lifetime| lifetime.deadline(sources.clock.now(), &*sources.rng)),
+                        egress,
+                        ping_interval,
+                        liveness,
+                        clock: sources.clock.clone(),
+                    };
+
                     // connection succeeded
-                    handle_client(stream)
+                    if upgrade.legacy {
+                        #[cfg(feature = "legacy")]
+                        handle_legacy_client(stream, peer, &mut record);
+                    } else if let Some(dir) = &receive_dir {
+                        handle_transfer_client(
+                            stream,
+                            peer,
+                            dir,
+                            receive_max,
+                            &close_policy,
+                            &mut record,
+                        );
+                    } else if let Some(settings) = &mux {
+                        handle_mux_client(
+                            stream,
+                            peer,
+                            &upgrade.path,
+                            settings,
+                            &close_policy,
+                            limits,
+                            &mut record,
+                        );
+                    } else {
+                        handle_client(
+                            stream,
+                            peer,
+                            &upgrade,
+                            &validator,
+                            &close_policy,
+                            limits,
+                            &mut record,
+                        );
+                    }
+                    if let Some(id) = id {
+                        connections.remove(id);
+                    }
+                    if let (Some(audit), Some((code, reason))) = (&audit_log, &record.forced_close)
+                    {
+                        let mut event = Event::new("forced_close", peer, &*sources.clock);
+                        event.set("code", *code);
+                        if let Some(name) = close_codes.name(*code) {
+                            event.set("name", name);
+                        }
+                        audit.write(event.set("reason", reason.as_str()));
+                    }
+                    if let Some(log) = access_log {
+                        log.write(&record);
+                    }
                 });
             }
             Err(error) => {
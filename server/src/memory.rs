@@ -0,0 +1,244 @@
+//! Heap usage per connection
+//!
+//! With the `alloc-accounting` feature, `Accounting` becomes the global
+//! allocator. Every allocation is charged to the `Account` its thread entered
+//! at the time, and credited back to the same account when freed, on
+//! whichever thread that happens. A connection's buffers, queued frames and
+//! reassembled messages thus count against it even after they were handed to
+//! its writer. Each allocation carries a small header naming its account, so
+//! the feature costs memory as well as time. Without it, accounts stay at
+//! zero.
+
+use crate::reaper::Tracked;
+use crate::registry::{ConnectionId, Registry};
+#[cfg(feature = "alloc-accounting")]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Heap bytes charged to one connection.
+#[derive(Debug, Default)]
+pub struct Account {
+    live: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl Account {
+    /// Bytes allocated under this account and not freed yet.
+    pub fn live(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    /// The most `live` has ever been.
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    #[cfg(feature = "alloc-accounting")]
+    fn charge(&self, size: usize) {
+        let live = self.live.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak.fetch_max(live, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "alloc-accounting")]
+    fn credit(&self, size: usize) {
+        self.live.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+thread_local! {
+    // a plain pointer without a destructor, so the allocator can still read
+    // it while the thread is torn down
+    static CURRENT: Cell<*const Account> = const { Cell::new(ptr::null()) };
+}
+
+/// Charges the calling thread's allocations to an account until dropped.
+pub struct Entered {
+    _account: Arc<Account>,
+    previous: *const Account,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+/// Charge the calling thread's allocations to `account`, until the returned
+/// guard is dropped.
+pub fn enter(account: &Arc<Account>) -> Entered {
+    let account = account.clone();
+    let previous = CURRENT.with(|current| current.replace(Arc::as_ptr(&account)));
+    Entered {
+        _account: account,
+        previous,
+    }
+}
+
+/// The account the calling thread is charging, if any.
+pub fn current() -> Option<Arc<Account>> {
+    let account = CURRENT.with(Cell::get);
+    (!account.is_null()).then(|| {
+        // SAFETY: the pointer came from `Arc::as_ptr` in `enter`, and the
+        // `Entered` guard holds a strong reference for as long as it is set,
+        // so the count we add is backed by a live `Arc`
+        unsafe {
+            Arc::increment_strong_count(account);
+            Arc::from_raw(account)
+        }
+    })
+}
+
+/// Spawn a thread charging the same account as the calling thread, for the
+/// helpers of a connection.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let account = current();
+    thread::spawn(move || {
+        let _entered = account.as_ref().map(enter);
+        f()
+    })
+}
+
+/// Heap usage of one connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub id: ConnectionId,
+    pub peer: SocketAddr,
+    /// Bytes the connection holds right now.
+    pub heap: usize,
+    pub peak_heap: usize,
+}
+
+/// Heap usage of every registered connection, the heaviest first.
+pub fn stats(connections: &Registry<Tracked>) -> Vec<ConnectionStats> {
+    let mut stats: Vec<_> = connections
+        .ids()
+        .into_iter()
+        .filter_map(|id| {
+            connections.with(id, |connection| ConnectionStats {
+                id,
+                peer: connection.peer,
+                heap: connection.memory.live(),
+                peak_heap: connection.memory.peak(),
+            })
+        })
+        .collect();
+    stats.sort_unstable_by(|a, b| b.heap.cmp(&a.heap).then(a.id.cmp(&b.id)));
+    stats
+}
+
+/// `stats` as text, one `<id> <peer> <bytes> <peak bytes>` line per
+/// connection.
+pub fn report(connections: &Registry<Tracked>) -> String {
+    stats(connections)
+        .iter()
+        .map(|stats| {
+            format!(
+                "{} {} {} {}\n",
+                stats.id, stats.peer, stats.heap, stats.peak_heap
+            )
+        })
+        .collect()
+}
+
+/// The system allocator, charging every allocation to the account of the
+/// thread making it.
+#[cfg(feature = "alloc-accounting")]
+pub struct Accounting;
+
+#[cfg(feature = "alloc-accounting")]
+#[global_allocator]
+static ALLOCATOR: Accounting = Accounting;
+
+/// Room in front of each allocation for its account, keeping the allocation
+/// aligned as asked.
+#[cfg(feature = "alloc-accounting")]
+fn padded(layout: Layout) -> Option<(Layout, usize)> {
+    let offset = layout.align().max(2 * std::mem::size_of::<usize>());
+    let size = layout.size().checked_add(offset)?;
+    Some((Layout::from_size_align(size, offset).ok()?, offset))
+}
+
+#[cfg(feature = "alloc-accounting")]
+unsafe impl GlobalAlloc for Accounting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some((padded, offset)) = padded(layout) else {
+            return ptr::null_mut();
+        };
+        // SAFETY: `padded` has a non-zero size, as it includes the header
+        let base = System.alloc(padded);
+        if base.is_null() {
+            return base;
+        }
+        let account = CURRENT.try_with(Cell::get).unwrap_or(ptr::null());
+        if !account.is_null() {
+            // SAFETY: a non-null `CURRENT` is backed by the `Entered` guard's
+            // strong reference. Every allocation keeps its account alive
+            // until it is freed.
+            Arc::increment_strong_count(account);
+            (*account).charge(layout.size());
+        }
+        // SAFETY: `offset` is within the `padded` block and at least two
+        // words, and a multiple of the pointer alignment, so the header slot
+        // just before `allocation` is in bounds and aligned
+        let allocation = base.add(offset);
+        allocation.cast::<*const Account>().sub(1).write(account);
+        allocation
+    }
+
+    unsafe fn dealloc(&self, allocation: *mut u8, layout: Layout) {
+        let (padded, offset) = padded(layout).expect("layout was padded when allocated");
+        // SAFETY: `allocation` came from `alloc` with the same `layout`, which
+        // wrote the account pointer just before it
+        let account = allocation.cast::<*const Account>().sub(1).read();
+        if !account.is_null() {
+            // SAFETY: `alloc` took a strong reference for this allocation,
+            // released here and nowhere else
+            (*account).credit(layout.size());
+            Arc::decrement_strong_count(account);
+        }
+        // SAFETY: the block `alloc` got from `System`, with the same layout
+        System.dealloc(allocation.sub(offset), padded);
+    }
+}
+
+#[cfg(all(test, feature = "alloc-accounting"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_are_charged_and_credited_from_any_thread() {
+        let account = Arc::new(Account::default());
+        let buffer = {
+            let _entered = enter(&account);
+            vec![0u8; 4096]
+        };
+        assert_eq!(account.live(), 4096);
+
+        // freed by a thread charging no account
+        thread::spawn(move || drop(buffer)).join().unwrap();
+        assert_eq!(account.live(), 0);
+        assert_eq!(account.peak(), 4096);
+    }
+
+    #[test]
+    fn spawned_helpers_charge_the_same_account() {
+        let account = Arc::new(Account::default());
+        let _entered = enter(&account);
+        let (inherited, buffer) = spawn(|| (current(), vec![0u8; 1 << 20])).join().unwrap();
+        assert!(Arc::ptr_eq(&inherited.unwrap(), &account));
+        assert!(account.live() >= 1 << 20);
+
+        let live = account.live();
+        drop(buffer);
+        assert_eq!(account.live(), live - (1 << 20));
+    }
+}
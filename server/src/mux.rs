@@ -0,0 +1,546 @@
+//! Logical channels multiplexed over one connection
+
+use crate::error::{Error, Result};
+use crate::frame::{Data, Frame, OpCode};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Every mux message is a Binary message laid out as a kind byte, a big endian
+/// `u32` channel id, and for `DATA` the channel payload. `WINDOW` carries a
+/// big endian `u32` credit increment.
+const OPEN: u8 = 0;
+const DATA: u8 = 1;
+const CLOSE: u8 = 2;
+const WINDOW: u8 = 3;
+pub const HEADER_LENGTH: usize = 5;
+
+/// Bytes either side may send on a fresh channel before waiting for credit.
+/// Both ends must agree on it.
+pub const DEFAULT_WINDOW: u32 = 65_536;
+
+/// Channels the peer may have open at once on one connection.
+pub const DEFAULT_MAX_CHANNELS: usize = 256;
+
+/// Length of the queues between a connection and its channels: frames waiting
+/// for the writer, and payloads waiting for each channel's reader. Senders to
+/// a full writer queue wait; a channel whose queue fills up is closed, so the
+/// connection's reader never waits on one slow channel.
+pub const QUEUE_LENGTH: usize = 1024;
+
+/// Which end of the connection we are. Clients open odd channel ids and
+/// servers even ones, so both sides can open channels without clashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+fn message(kind: u8, id: u32, data: &[u8]) -> Frame {
+    let mut payload = Vec::with_capacity(HEADER_LENGTH + data.len());
+    payload.push(kind);
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.extend_from_slice(data);
+    Frame::message(payload, OpCode::Data(Data::Binary))
+}
+
+/// How long senders spent waiting for credit.
+#[derive(Debug, Default)]
+pub struct FlowStats {
+    stalls: AtomicU64,
+    stalled_nanos: AtomicU64,
+}
+
+impl FlowStats {
+    /// Number of sends that had to wait for the peer to grant credit,
+    /// counted as soon as they start waiting.
+    pub fn stalls(&self) -> u64 {
+        self.stalls.load(Ordering::Relaxed)
+    }
+
+    /// Total time senders spent waiting for credit.
+    pub fn stalled_for(&self) -> Duration {
+        Duration::from_nanos(self.stalled_nanos.load(Ordering::Relaxed))
+    }
+}
+
+/// Credit-based flow control for one channel.
+struct Window {
+    state: Mutex<WindowState>,
+    changed: Condvar,
+}
+
+struct WindowState {
+    /// Bytes we may still send before the peer grants more.
+    send: u32,
+    /// Bytes the peer may still send before we grant more.
+    receive: u32,
+    closed: bool,
+}
+
+impl Window {
+    fn new(size: u32) -> Window {
+        Window {
+            state: Mutex::new(WindowState {
+                send: size,
+                receive: size,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Take up to `wanted` bytes of send credit, waiting while there is none.
+    fn take(&self, wanted: usize, stats: &FlowStats) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if wanted > 0 && state.send == 0 && !state.closed {
+            let started = Instant::now();
+            // under the lock, so credit granted after this is seen
+            stats.stalls.fetch_add(1, Ordering::Relaxed);
+            while state.send == 0 && !state.closed {
+                state = self.changed.wait(state).unwrap();
+            }
+            stats.stalled_nanos.fetch_add(
+                u64::try_from(started.elapsed().as_nanos()).unwrap_or(u64::MAX),
+                Ordering::Relaxed,
+            );
+        }
+        if state.closed {
+            return Err(Error::Mux(String::from("channel is closed")));
+        }
+        // a `wanted` too big for a u32 is more than any window
+        let taken = u32::try_from(wanted).map_or(state.send, |wanted| wanted.min(state.send));
+        state.send -= taken;
+        Ok(taken as usize)
+    }
+
+    fn grant(&self, increment: u32) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.send = state
+            .send
+            .checked_add(increment)
+            .ok_or_else(|| Error::Mux(String::from("send window overflow")))?;
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+}
+
+struct Entry {
+    inbound: SyncSender<Vec<u8>>,
+    window: Arc<Window>,
+}
+
+/// Channel bookkeeping for one connection. Inbound mux messages are fed to
+/// `receive`; everything to be written is pushed to the `outbound` queue.
+pub struct Mux {
+    role: Role,
+    next_id: u32,
+    window: u32,
+    max_channels: usize,
+    channels: HashMap<u32, Entry>,
+    outbound: SyncSender<Frame>,
+    stats: Arc<FlowStats>,
+}
+
+impl Mux {
+    pub fn new(role: Role, outbound: SyncSender<Frame>) -> Mux {
+        Mux::with_window(role, outbound, DEFAULT_WINDOW)
+    }
+
+    /// A mux whose channels start with `window` bytes of credit each way.
+    pub fn with_window(role: Role, outbound: SyncSender<Frame>, window: u32) -> Mux {
+        Mux {
+            role,
+            next_id: if role == Role::Client { 1 } else { 2 },
+            window,
+            max_channels: DEFAULT_MAX_CHANNELS,
+            channels: HashMap::new(),
+            outbound,
+            stats: Arc::default(),
+        }
+    }
+
+    /// Refuse channels the peer opens beyond `max` open ones.
+    pub fn set_max_channels(&mut self, max: usize) {
+        self.max_channels = max;
+    }
+
+    pub fn stats(&self) -> &FlowStats {
+        &self.stats
+    }
+
+    /// Open a channel from this side.
+    pub fn open(&mut self) -> Result<Channel> {
+        let id = self.next_id;
+        self.next_id = self
+            .next_id
+            .checked_add(2)
+            .ok_or_else(|| Error::Mux(String::from("channel ids exhausted")))?;
+        self.send(message(OPEN, id, &[]))?;
+        Ok(self.register(id))
+    }
+
+    /// Handle one inbound mux message. Returns the channel the peer opened, if
+    /// that is what the message did.
+    pub fn receive(&mut self, payload: &[u8]) -> Result<Option<Channel>> {
+        if payload.len() < HEADER_LENGTH {
+            return Err(Error::Mux(format!("{} byte mux message", payload.len())));
+        }
+        let kind = payload[0];
+        let id = u32::from_be_bytes(payload[1..HEADER_LENGTH].try_into().unwrap());
+        let data = &payload[HEADER_LENGTH..];
+
+        match kind {
+            OPEN => {
+                // channels closed from their handles since the last open
+                self.channels.retain(|_, entry| !entry.window.is_closed());
+                let peer_parity = if self.role == Role::Client { 0 } else { 1 };
+                if id % 2 != peer_parity || self.channels.contains_key(&id) {
+                    return Err(Error::Mux(format!("peer can't open channel {id}")));
+                }
+                if self.channels.len() >= self.max_channels {
+                    return Err(Error::Mux(format!(
+                        "peer opened more than {} channels",
+                        self.max_channels
+                    )));
+                }
+                Ok(Some(self.register(id)))
+            }
+            DATA => {
+                // a channel closed on our side may still see data in flight
+                if let Some(entry) = self.channels.get(&id) {
+                    {
+                        let mut state = entry.window.state.lock().unwrap();
+                        state.receive = u32::try_from(data.len())
+                            .ok()
+                            .and_then(|length| state.receive.checked_sub(length))
+                            .ok_or_else(|| {
+                                Error::Mux(format!("peer overran the window of channel {id}"))
+                            })?;
+                    }
+                    match entry.inbound.try_send(data.to_vec()) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(_)) => {
+                            self.remove(id);
+                            self.send(message(CLOSE, id, &[]))?;
+                        }
+                        Err(TrySendError::Disconnected(_)) => self.remove(id),
+                    }
+                }
+                Ok(None)
+            }
+            CLOSE => {
+                self.remove(id);
+                Ok(None)
+            }
+            WINDOW => {
+                let increment = data
+                    .try_into()
+                    .map(u32::from_be_bytes)
+                    .map_err(|_| Error::Mux(format!("{} byte window update", data.len())))?;
+                if let Some(entry) = self.channels.get(&id) {
+                    entry.window.grant(increment)?;
+                }
+                Ok(None)
+            }
+            kind => Err(Error::Mux(format!("unknown mux message kind {kind}"))),
+        }
+    }
+
+    /// Number of channels currently open, not counting those closed on this
+    /// side.
+    pub fn len(&self) -> usize {
+        self.channels
+            .values()
+            .filter(|entry| !entry.window.is_closed())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn register(&mut self, id: u32) -> Channel {
+        let (inbound_sender, inbound) = sync_channel(QUEUE_LENGTH);
+        let window = Arc::new(Window::new(self.window));
+        self.channels.insert(
+            id,
+            Entry {
+                inbound: inbound_sender,
+                window: window.clone(),
+            },
+        );
+        Channel {
+            id,
+            inbound,
+            outbound: self.outbound.clone(),
+            window,
+            stats: self.stats.clone(),
+        }
+    }
+
+    fn remove(&mut self, id: u32) {
+        if let Some(entry) = self.channels.remove(&id) {
+            entry.window.close();
+        }
+    }
+
+    fn send(&self, frame: Frame) -> Result<()> {
+        self.outbound
+            .send(frame)
+            .map_err(|_| Error::Mux(String::from("connection writer is gone")))
+    }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        // wake up senders still waiting for credit
+        for entry in self.channels.values() {
+            entry.window.close();
+        }
+    }
+}
+
+/// One logical stream. Handles can be moved to their own thread.
+pub struct Channel {
+    id: u32,
+    inbound: Receiver<Vec<u8>>,
+    outbound: SyncSender<Frame>,
+    window: Arc<Window>,
+    stats: Arc<FlowStats>,
+}
+
+impl Channel {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Send a payload, split into as many messages as the peer's window
+    /// requires. Blocks while the peer has granted no credit.
+    pub fn send(&self, data: &[u8]) -> Result<()> {
+        let mut rest = data;
+        loop {
+            let credit = self.window.take(rest.len(), &self.stats)?;
+            let (chunk, tail) = rest.split_at(credit);
+            self.outbound
+                .send(message(DATA, self.id, chunk))
+                .map_err(|_| Error::Mux(String::from("connection writer is gone")))?;
+            rest = tail;
+            if rest.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Wait for the next payload, giving its size back to the peer as credit.
+    /// Returns `None` once the peer closed the channel or the connection went
+    /// away.
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        let data = self.inbound.recv().ok()?;
+        let consumed = u32::try_from(data.len())
+            .expect("payloads were checked against a u32 window on arrival");
+        if consumed > 0 {
+            self.window.state.lock().unwrap().receive += consumed;
+            self.outbound
+                .send(message(WINDOW, self.id, &consumed.to_be_bytes()))
+                .ok();
+        }
+        Some(data)
+    }
+
+    /// Close the channel on both sides. It stops counting towards the mux's
+    /// channel limit at once, without waiting for the peer.
+    pub fn close(self) -> Result<()> {
+        self.window.close();
+        self.outbound
+            .send(message(CLOSE, self.id, &[]))
+            .map_err(|_| Error::Mux(String::from("connection writer is gone")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn end(role: Role) -> (Mux, Receiver<Frame>) {
+        let (outbound, queue) = sync_channel(QUEUE_LENGTH);
+        (Mux::new(role, outbound), queue)
+    }
+
+    /// Hand everything `queue` holds to `to`, returning the channels it
+    /// opened.
+    fn pump(queue: &Receiver<Frame>, to: &mut Mux) -> Result<Vec<Channel>> {
+        let mut opened = Vec::new();
+        while let Ok(frame) = queue.try_recv() {
+            opened.extend(to.receive(frame.payload())?);
+        }
+        Ok(opened)
+    }
+
+    #[test]
+    fn data_crosses_and_credit_comes_back() {
+        let (mut client, to_server) = end(Role::Client);
+        let (mut server, to_client) = end(Role::Server);
+
+        let sender = client.open().unwrap();
+        assert_eq!(sender.id(), 1);
+        sender.send(b"hello").unwrap();
+        let receiver = pump(&to_server, &mut server).unwrap().pop().unwrap();
+        assert_eq!(receiver.recv().unwrap(), b"hello");
+
+        // the window update gives the five bytes back
+        pump(&to_client, &mut client).unwrap();
+        assert_eq!(sender.window.state.lock().unwrap().send, DEFAULT_WINDOW);
+    }
+
+    /// Wait until a sender is blocked on credit from `mux`'s peer.
+    fn wait_for_stall(mux: &Mux) {
+        while mux.stats().stalls() == 0 {
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn sends_wait_for_credit_and_are_split_to_fit_it() {
+        let (outbound, queue) = sync_channel(QUEUE_LENGTH);
+        let mut client = Mux::with_window(Role::Client, outbound, 4);
+        let channel = client.open().unwrap();
+        let sender = std::thread::spawn(move || channel.send(b"abcdef"));
+        wait_for_stall(&client);
+        client
+            .receive(&message(WINDOW, 1, &2u32.to_be_bytes()).into_payload())
+            .unwrap();
+        sender.join().unwrap().unwrap();
+
+        let data: Vec<_> = queue
+            .try_iter()
+            .skip(1)
+            .map(|frame| frame.payload()[HEADER_LENGTH..].to_vec())
+            .collect();
+        assert_eq!(data, [b"abcd".to_vec(), b"ef".to_vec()]);
+        assert_eq!(client.stats().stalls(), 1);
+    }
+
+    #[test]
+    fn peer_must_use_its_own_channel_ids() {
+        let (mut server, _queue) = end(Role::Server);
+        assert!(server
+            .receive(&message(OPEN, 2, &[]).into_payload())
+            .is_err());
+        assert!(server
+            .receive(&message(OPEN, 1, &[]).into_payload())
+            .is_ok());
+        assert!(server
+            .receive(&message(OPEN, 1, &[]).into_payload())
+            .is_err());
+    }
+
+    #[test]
+    fn channels_beyond_the_limit_are_refused() {
+        let (mut server, _queue) = end(Role::Server);
+        server.set_max_channels(2);
+        for id in [1, 3] {
+            server
+                .receive(&message(OPEN, id, &[]).into_payload())
+                .unwrap();
+        }
+        assert!(matches!(
+            server.receive(&message(OPEN, 5, &[]).into_payload()),
+            Err(Error::Mux(_))
+        ));
+
+        // closing one makes room again
+        server
+            .receive(&message(CLOSE, 1, &[]).into_payload())
+            .unwrap();
+        server
+            .receive(&message(OPEN, 5, &[]).into_payload())
+            .unwrap();
+    }
+
+    #[test]
+    fn channels_closed_locally_stop_counting_at_once() {
+        let (mut server, queue) = end(Role::Server);
+        server.set_max_channels(1);
+        let channel = server
+            .receive(&message(OPEN, 1, &[]).into_payload())
+            .unwrap()
+            .unwrap();
+        assert_eq!(server.len(), 1);
+
+        channel.close().unwrap();
+        assert!(server.is_empty());
+        assert_eq!(queue.try_recv().unwrap().payload()[0], CLOSE);
+        server
+            .receive(&message(OPEN, 3, &[]).into_payload())
+            .unwrap();
+        assert_eq!(server.len(), 1);
+    }
+
+    #[test]
+    fn overrunning_the_window_is_an_error() {
+        let (outbound, _queue) = sync_channel(QUEUE_LENGTH);
+        let mut server = Mux::with_window(Role::Server, outbound, 4);
+        let _channel = server
+            .receive(&message(OPEN, 1, &[]).into_payload())
+            .unwrap();
+        assert!(server
+            .receive(&message(DATA, 1, b"abcd").into_payload())
+            .is_ok());
+        assert!(server
+            .receive(&message(DATA, 1, b"e").into_payload())
+            .is_err());
+    }
+
+    #[test]
+    fn channel_that_falls_behind_is_closed() {
+        let (mut server, queue) = end(Role::Server);
+        let channel = server
+            .receive(&message(OPEN, 1, &[]).into_payload())
+            .unwrap()
+            .unwrap();
+        for _ in 0..=QUEUE_LENGTH {
+            server
+                .receive(&message(DATA, 1, b"x").into_payload())
+                .unwrap();
+        }
+        let close = queue.try_recv().unwrap();
+        assert_eq!(close.payload(), message(CLOSE, 1, &[]).payload());
+        assert!(server.is_empty());
+
+        // what was queued is still delivered, then the channel ends
+        assert_eq!(channel.inbound.try_iter().count(), QUEUE_LENGTH);
+    }
+
+    #[test]
+    fn short_and_unknown_messages_are_errors() {
+        let (mut server, _queue) = end(Role::Server);
+        assert!(server.receive(&[DATA, 0, 0]).is_err());
+        assert!(server.receive(&message(9, 1, &[]).into_payload()).is_err());
+        assert!(server
+            .receive(&message(WINDOW, 1, &[1]).into_payload())
+            .is_err());
+    }
+
+    #[test]
+    fn closing_the_mux_wakes_senders_without_credit() {
+        let (outbound, _queue) = sync_channel(QUEUE_LENGTH);
+        let mut client = Mux::with_window(Role::Client, outbound, 0);
+        let channel = client.open().unwrap();
+        let sender = std::thread::spawn(move || channel.send(b"stuck"));
+        wait_for_stall(&client);
+        drop(client);
+        assert!(sender.join().unwrap().is_err());
+    }
+}
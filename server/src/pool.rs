@@ -0,0 +1,154 @@
+//! Pool of outbound client connections
+
+use crate::client::WebSocket;
+use crate::error::Result;
+use crate::frame::Frame;
+use crate::sim::Sources;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// Idle connections kept around for reuse; extra ones are closed.
+    pub max_idle: usize,
+    /// Connections older than this are closed instead of reused.
+    pub max_lifetime: Duration,
+    /// How long a checked out connection may take to answer its health ping.
+    pub ping_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_idle: 8,
+            max_lifetime: Duration::from_secs(30 * 60),
+            ping_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+struct Idle {
+    socket: WebSocket,
+    created: Instant,
+}
+
+/// Reuses client connections to one endpoint so bursts of requests don't pay
+/// for a handshake each time.
+pub struct Pool {
+    addr: String,
+    path: String,
+    config: PoolConfig,
+    sources: Sources,
+    idle: Mutex<Vec<Idle>>,
+}
+
+impl Pool {
+    pub fn new(addr: &str, path: &str, config: PoolConfig) -> Arc<Pool> {
+        Pool::with_sources(addr, path, config, Sources::default())
+    }
+
+    /// Like `new`, timing lifetimes with the given clock and handing the
+    /// random source to every connection.
+    pub fn with_sources(addr: &str, path: &str, config: PoolConfig, sources: Sources) -> Arc<Pool> {
+        Arc::new(Pool {
+            addr: addr.to_string(),
+            path: path.to_string(),
+            config,
+            sources,
+            idle: Mutex::default(),
+        })
+    }
+
+    fn expired(&self, created: Instant) -> bool {
+        self.sources.clock.now().duration_since(created) >= self.config.max_lifetime
+    }
+
+    /// Check out a connection: an idle one that still answers a Ping, or a
+    /// fresh one.
+    pub fn get(self: &Arc<Self>) -> Result<PooledWebSocket> {
+        loop {
+            let idle = self.idle.lock().unwrap().pop();
+            let Some(mut idle) = idle else { break };
+            if self.expired(idle.created) {
+                discard(idle.socket);
+                continue;
+            }
+            if idle.socket.ping(self.config.ping_timeout).is_ok() {
+                return Ok(PooledWebSocket {
+                    socket: Some(idle.socket),
+                    created: idle.created,
+                    pool: self.clone(),
+                });
+            }
+        }
+
+        Ok(PooledWebSocket {
+            socket: Some(WebSocket::connect_with(
+                &self.addr,
+                &self.path,
+                self.sources.clone(),
+            )?),
+            created: self.sources.clock.now(),
+            pool: self.clone(),
+        })
+    }
+
+    /// Number of idle connections waiting for reuse.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    fn release(&self, socket: WebSocket, created: Instant) {
+        if socket.is_broken() {
+            return;
+        }
+        if self.expired(created) {
+            discard(socket);
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.config.max_idle {
+            idle.push(Idle { socket, created });
+        } else {
+            drop(idle);
+            discard(socket);
+        }
+    }
+}
+
+/// Close a working connection the pool has no use for, telling the server
+/// rather than just dropping the TCP connection.
+fn discard(mut socket: WebSocket) {
+    socket.send(Frame::close(1000, "")).ok();
+}
+
+/// A connection checked out of a `Pool`; it goes back when dropped unless it
+/// broke while in use.
+pub struct PooledWebSocket {
+    socket: Option<WebSocket>,
+    created: Instant,
+    pool: Arc<Pool>,
+}
+
+impl Deref for PooledWebSocket {
+    type Target = WebSocket;
+
+    fn deref(&self) -> &WebSocket {
+        self.socket.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledWebSocket {
+    fn deref_mut(&mut self) -> &mut WebSocket {
+        self.socket.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledWebSocket {
+    fn drop(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            self.pool.release(socket, self.created);
+        }
+    }
+}
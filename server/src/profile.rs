@@ -0,0 +1,143 @@
+//! Timing of the stages of the read path
+//!
+//! Scopes only measure anything when the `profile` feature is enabled, and
+//! cost nothing otherwise. They measure wall time, so a stage that blocks on
+//! the socket counts the wait too.
+
+#[cfg(feature = "profile")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+#[cfg(feature = "profile")]
+use std::time::Instant;
+
+/// A stage every inbound message goes through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Reading the payload once the frame header arrived.
+    Parse,
+    /// Removing the client's mask.
+    Unmask,
+    /// Running the extension pipeline, where decompression would happen.
+    Decode,
+    /// Checking the message against the `Validator`.
+    Validate,
+    /// Handling the message and sending the reply.
+    Dispatch,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [
+        Stage::Parse,
+        Stage::Unmask,
+        Stage::Decode,
+        Stage::Validate,
+        Stage::Dispatch,
+    ];
+
+    /// The stage as a stack in folded notation, the input flame graph tools
+    /// take.
+    pub fn stack(self) -> &'static str {
+        match self {
+            Stage::Parse => "read;parse",
+            Stage::Unmask => "read;unmask",
+            Stage::Decode => "read;decode",
+            Stage::Validate => "read;validate",
+            Stage::Dispatch => "read;dispatch",
+        }
+    }
+}
+
+#[cfg(feature = "profile")]
+struct Totals {
+    count: AtomicU64,
+    nanos: AtomicU64,
+}
+
+#[cfg(feature = "profile")]
+static TOTALS: [Totals; Stage::ALL.len()] = [const {
+    Totals {
+        count: AtomicU64::new(0),
+        nanos: AtomicU64::new(0),
+    }
+}; Stage::ALL.len()];
+
+/// Time spent in a stage so far, across all connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StageStats {
+    pub stage: Stage,
+    /// Times the stage was entered.
+    pub count: u64,
+    pub total: Duration,
+}
+
+/// Adds the time until it is dropped to its stage.
+#[must_use]
+pub struct Scope {
+    #[cfg(feature = "profile")]
+    stage: Stage,
+    #[cfg(feature = "profile")]
+    started: Instant,
+}
+
+#[cfg(feature = "profile")]
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let totals = &TOTALS[self.stage as usize];
+        totals.count.fetch_add(1, Ordering::Relaxed);
+        totals
+            .nanos
+            .fetch_add(self.started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+#[inline]
+#[cfg_attr(not(feature = "profile"), allow(unused_variables))]
+pub fn scope(stage: Stage) -> Scope {
+    Scope {
+        #[cfg(feature = "profile")]
+        stage,
+        #[cfg(feature = "profile")]
+        started: Instant::now(),
+    }
+}
+
+/// Run `f`, adding the time it takes to `stage`.
+#[inline]
+pub fn time<T>(stage: Stage, f: impl FnOnce() -> T) -> T {
+    let _scope = scope(stage);
+    f()
+}
+
+/// Totals of every stage. All zero unless the `profile` feature is enabled.
+pub fn snapshot() -> Vec<StageStats> {
+    Stage::ALL
+        .iter()
+        .map(|&stage| {
+            #[cfg(feature = "profile")]
+            let (count, total) = {
+                let totals = &TOTALS[stage as usize];
+                (
+                    totals.count.load(Ordering::Relaxed),
+                    Duration::from_nanos(totals.nanos.load(Ordering::Relaxed)),
+                )
+            };
+            #[cfg(not(feature = "profile"))]
+            let (count, total) = (0, Duration::ZERO);
+            StageStats {
+                stage,
+                count,
+                total,
+            }
+        })
+        .collect()
+}
+
+/// The totals in folded stack notation, one `stack microseconds` line per
+/// stage that was entered, ready for `flamegraph.pl` or `inferno`.
+pub fn folded() -> String {
+    snapshot()
+        .iter()
+        .filter(|stats| stats.count > 0)
+        .map(|stats| format!("{} {}\n", stats.stage.stack(), stats.total.as_micros()))
+        .collect()
+}
@@ -0,0 +1,235 @@
+//! Dead connection detection
+//!
+//! A peer behind a NAT that dropped its mapping never answers again, and
+//! writes to it can sit in the kernel for minutes. TCP keepalive and a bound
+//! on unacknowledged writes let the kernel notice, and connections that stay
+//! silent past their Pong deadline are evicted by `reap`.
+
+use crate::memory::Account;
+use crate::registry::Registry;
+use crate::sim::Clock;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// TCP keepalive timing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Idle time before the first probe.
+    pub idle: Duration,
+    /// Time between unanswered probes.
+    pub interval: Duration,
+    /// Unanswered probes before the connection is dropped.
+    pub count: u32,
+}
+
+#[cfg(unix)]
+fn set_option(
+    stream: &TcpStream,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the descriptor is open for as long as `stream` lives and the
+    // options set here all take a C int
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn clamp_secs(duration: Duration) -> i32 {
+    duration.as_secs().clamp(1, i32::MAX as u64) as i32
+}
+
+/// Turn on keepalive probes and make writes that can't make progress for
+/// `write_timeout` fail instead of blocking. Keepalive timing and the bound on
+/// unacknowledged data are only tuned on Linux.
+pub fn tune(
+    stream: &TcpStream,
+    keepalive: Option<Keepalive>,
+    write_timeout: Option<Duration>,
+) -> io::Result<()> {
+    if let Some(write_timeout) = write_timeout {
+        stream.set_write_timeout(Some(write_timeout))?;
+        #[cfg(target_os = "linux")]
+        set_option(
+            stream,
+            libc::IPPROTO_TCP,
+            libc::TCP_USER_TIMEOUT,
+            write_timeout.as_millis().min(i32::MAX as u128) as i32,
+        )?;
+    }
+    #[cfg(unix)]
+    if let Some(keepalive) = keepalive {
+        set_option(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        #[cfg(target_os = "linux")]
+        {
+            set_option(
+                stream,
+                libc::IPPROTO_TCP,
+                libc::TCP_KEEPIDLE,
+                clamp_secs(keepalive.idle),
+            )?;
+            set_option(
+                stream,
+                libc::IPPROTO_TCP,
+                libc::TCP_KEEPINTVL,
+                clamp_secs(keepalive.interval),
+            )?;
+            set_option(
+                stream,
+                libc::IPPROTO_TCP,
+                libc::TCP_KEEPCNT,
+                keepalive.count.max(1) as i32,
+            )?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = keepalive;
+    Ok(())
+}
+
+/// When a connection was last heard from.
+pub struct Liveness {
+    last_heard: Mutex<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Liveness {
+    pub fn new(clock: Arc<dyn Clock>) -> Liveness {
+        Liveness {
+            last_heard: Mutex::new(clock.now()),
+            clock,
+        }
+    }
+
+    /// Record that a frame arrived.
+    pub fn heard(&self) {
+        *self.last_heard.lock().unwrap() = self.clock.now();
+    }
+
+    pub fn last_heard(&self) -> Instant {
+        *self.last_heard.lock().unwrap()
+    }
+
+    pub fn silent_for(&self) -> Duration {
+        self.clock
+            .now()
+            .saturating_duration_since(self.last_heard())
+    }
+}
+
+/// A registered connection, as the reaper sees it.
+pub struct Tracked {
+    pub stream: TcpStream,
+    pub peer: SocketAddr,
+    /// Only connections whose handler pings the peer and records what it
+    /// hears can be judged by their silence; the others are never reaped.
+    pub liveness: Option<Arc<Liveness>>,
+    /// Heap the connection holds, see `memory`.
+    pub memory: Arc<Account>,
+}
+
+/// Evict every connection silent for longer than `limit`: shut its socket
+/// down, which ends its handler, and drop it from the registry. Returns the
+/// evicted peers and how long each had been silent.
+pub fn reap(registry: &Registry<Tracked>, limit: Duration) -> Vec<(SocketAddr, Duration)> {
+    let silent = Mutex::new(Vec::new());
+    registry.for_each(|id, connection| {
+        let silent_for = connection
+            .liveness
+            .as_ref()
+            .map(|liveness| liveness.silent_for());
+        if silent_for.is_some_and(|silent_for| silent_for > limit) {
+            silent.lock().unwrap().push(id);
+        }
+    });
+
+    silent
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .filter_map(|id| registry.remove(id))
+        .map(|connection| {
+            connection.stream.shutdown(Shutdown::Both).ok();
+            let silent_for = connection
+                .liveness
+                .map_or(Duration::ZERO, |liveness| liveness.silent_for());
+            (connection.peer, silent_for)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::VirtualClock;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    /// A connected pair: the server's end, tracked, and the peer's end.
+    fn tracked(liveness: Option<Arc<Liveness>>) -> (Tracked, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        let tracked = Tracked {
+            stream,
+            peer: addr,
+            liveness,
+            memory: Arc::default(),
+        };
+        (tracked, peer)
+    }
+
+    #[test]
+    fn silent_connections_are_evicted_and_shut_down() {
+        let clock = Arc::new(VirtualClock::default());
+        let liveness = Arc::new(Liveness::new(clock.clone()));
+        let registry = Registry::default();
+        let (connection, mut peer) = tracked(Some(liveness.clone()));
+        let addr = connection.peer;
+        registry.insert(connection);
+
+        clock.advance(Duration::from_secs(30));
+        liveness.heard();
+        clock.advance(Duration::from_secs(30));
+        assert!(reap(&registry, Duration::from_secs(30)).is_empty());
+        assert_eq!(registry.len(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            reap(&registry, Duration::from_secs(30)),
+            [(addr, Duration::from_secs(31))]
+        );
+        assert_eq!(registry.len(), 0);
+        // the shutdown reaches the peer as the end of the stream
+        assert_eq!(peer.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn connections_without_liveness_are_never_reaped() {
+        let clock = Arc::new(VirtualClock::default());
+        let registry = Registry::default();
+        let (connection, _peer) = tracked(None);
+        registry.insert(connection);
+
+        clock.advance(Duration::from_secs(24 * 3600));
+        assert!(reap(&registry, Duration::ZERO).is_empty());
+        assert_eq!(registry.len(), 1);
+    }
+}
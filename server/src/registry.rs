@@ -0,0 +1,138 @@
+//! Registry of live connections
+//!
+//! Every upgraded connection is registered here, for the reaper to scan, the
+//! memory report to list and the test server to address. Connections are
+//! spread over shards by a hash of their id, each behind its own lock, so
+//! handlers registering and removing themselves don't queue behind a scan of
+//! tens of thousands of connections.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+pub type ConnectionId = u64;
+
+pub const DEFAULT_SHARDS: usize = 64;
+
+pub struct Registry<T> {
+    shards: Vec<Mutex<HashMap<ConnectionId, T>>>,
+    next_id: AtomicU64,
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Registry::new(DEFAULT_SHARDS)
+    }
+}
+
+impl<T> Registry<T> {
+    pub fn new(shards: usize) -> Registry<T> {
+        Registry {
+            shards: (0..shards.max(1)).map(|_| Mutex::default()).collect(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, id: ConnectionId) -> &Mutex<HashMap<ConnectionId, T>> {
+        // Fibonacci hashing, so consecutive ids land on different shards
+        let hash = id.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+        &self.shards[hash as usize % self.shards.len()]
+    }
+
+    /// Register a connection, returning its id.
+    pub fn insert(&self, connection: T) -> ConnectionId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.shard(id).lock().unwrap().insert(id, connection);
+        id
+    }
+
+    pub fn remove(&self, id: ConnectionId) -> Option<T> {
+        self.shard(id).lock().unwrap().remove(&id)
+    }
+
+    /// Run `f` on one connection, if it is still registered.
+    pub fn with<R>(&self, id: ConnectionId, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.shard(id).lock().unwrap().get(&id).map(f)
+    }
+
+    /// Ids of every registered connection, in no particular order.
+    pub fn ids(&self) -> Vec<ConnectionId> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.lock().unwrap().keys().copied().collect::<Vec<_>>())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Run `f` on every connection. Shards are visited by several threads at
+    /// once, and each shard is only locked while its own connections are
+    /// visited.
+    pub fn for_each(&self, f: impl Fn(ConnectionId, &T) + Sync)
+    where
+        T: Send,
+    {
+        let workers = thread::available_parallelism().map_or(1, |workers| workers.get());
+        let per_worker = self.shards.len().div_ceil(workers);
+        thread::scope(|scope| {
+            for shards in self.shards.chunks(per_worker) {
+                let f = &f;
+                scope.spawn(move || {
+                    for shard in shards {
+                        for (&id, connection) in shard.lock().unwrap().iter() {
+                            f(id, connection);
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_come_and_go_by_id() {
+        let registry = Registry::new(4);
+        let first = registry.insert("first");
+        let second = registry.insert("second");
+        assert_ne!(first, second);
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.with(second, |name| name.len()), Some(6));
+
+        assert_eq!(registry.remove(first), Some("first"));
+        assert_eq!(registry.remove(first), None);
+        assert_eq!(registry.with(first, |name| name.len()), None);
+        assert_eq!(registry.ids(), vec![second]);
+    }
+
+    #[test]
+    fn for_each_visits_every_shard() {
+        let registry = Registry::new(8);
+        let ids: Vec<_> = (0..100).map(|n| registry.insert(n)).collect();
+        let seen = Mutex::new(Vec::new());
+        registry.for_each(|id, &n| seen.lock().unwrap().push((id, n)));
+        let mut seen = seen.into_inner().unwrap();
+        seen.sort();
+        assert_eq!(seen, ids.into_iter().zip(0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn zero_shards_still_make_one() {
+        let registry = Registry::new(0);
+        registry.insert(());
+        assert!(!registry.is_empty());
+    }
+}
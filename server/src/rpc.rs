@@ -0,0 +1,450 @@
+//! Request/response over Binary messages, matched by correlation id
+
+use crate::error::{Error, Result};
+use crate::frame::{Data, Frame, OpCode};
+use crate::sim::{Clock, SystemClock};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Every RPC message is a kind byte, a big endian `u64` correlation id and the
+/// body.
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+const HEADER_LENGTH: usize = 9;
+
+fn message(kind: u8, id: u64, body: &[u8]) -> Frame {
+    let mut payload = Vec::with_capacity(HEADER_LENGTH + body.len());
+    payload.push(kind);
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.extend_from_slice(body);
+    Frame::message(payload, OpCode::Data(Data::Binary))
+}
+
+/// A request from the peer, to be answered with `Rpc::respond`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    id: u64,
+    pub body: Vec<u8>,
+}
+
+struct Slot {
+    state: Mutex<SlotState>,
+    ready: Condvar,
+    deadline: Instant,
+}
+
+#[derive(Default)]
+struct SlotState {
+    outcome: Option<Result<Vec<u8>>>,
+    waker: Option<Waker>,
+    alarm: bool,
+}
+
+impl Slot {
+    fn complete(&self, outcome: Result<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        if state.outcome.is_none() {
+            state.outcome = Some(outcome);
+        }
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        self.ready.notify_all();
+    }
+
+    fn wake(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.alarm = false;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Wakes polled requests once their deadline has passed. One thread serves
+/// every `Rpc` in the process; it starts when a request is first polled.
+struct Timer {
+    alarms: Mutex<Vec<Alarm>>,
+    changed: Condvar,
+}
+
+struct Alarm {
+    at: Instant,
+    left: Duration,
+    clock: Arc<dyn Clock>,
+    slot: Weak<Slot>,
+}
+
+fn timer() -> &'static Timer {
+    static TIMER: OnceLock<Timer> = OnceLock::new();
+    TIMER.get_or_init(|| {
+        thread::spawn(|| timer().run());
+        Timer {
+            alarms: Mutex::default(),
+            changed: Condvar::new(),
+        }
+    })
+}
+
+impl Timer {
+    fn set(&self, alarm: Alarm) {
+        self.alarms.lock().unwrap().push(alarm);
+        self.changed.notify_one();
+    }
+
+    fn run(&self) {
+        let mut alarms = self.alarms.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let (due, later) = std::mem::take(&mut *alarms)
+                .into_iter()
+                .partition::<Vec<_>, _>(|alarm| alarm.at <= now);
+            *alarms = later;
+            if !due.is_empty() {
+                drop(alarms);
+                for alarm in due {
+                    if let Some(slot) = alarm.slot.upgrade() {
+                        alarm.clock.timed_out(alarm.left);
+                        slot.wake();
+                    }
+                }
+                alarms = self.alarms.lock().unwrap();
+                continue;
+            }
+            alarms = match alarms.iter().map(|alarm| alarm.at).min() {
+                Some(at) => self.changed.wait_timeout(alarms, at - now).unwrap().0,
+                None => self.changed.wait(alarms).unwrap(),
+            };
+        }
+    }
+}
+
+struct Inner {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, Arc<Slot>>>,
+    outbound: SyncSender<Frame>,
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl Inner {
+    fn expire(&self, now: Instant) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, slot| {
+            let overdue = slot.deadline <= now;
+            if overdue {
+                slot.complete(Err(Error::Timeout));
+            }
+            !overdue
+        });
+    }
+}
+
+/// Pending-request bookkeeping for one connection. Inbound Binary messages are
+/// fed to `receive`; requests and responses are pushed to the bounded
+/// `outbound` queue, blocking while it is full. Requests time out while their
+/// caller waits or polls; overdue ones are dropped from the table on the next
+/// `request`.
+pub struct Rpc {
+    inner: Arc<Inner>,
+}
+
+impl Rpc {
+    /// Requests fail with `Error::Timeout` when no response arrives within
+    /// `timeout`.
+    pub fn new(outbound: SyncSender<Frame>, timeout: Duration) -> Rpc {
+        Rpc::with_clock(outbound, timeout, Arc::new(SystemClock))
+    }
+
+    /// Like `new`, measuring timeouts on `clock`.
+    pub fn with_clock(
+        outbound: SyncSender<Frame>,
+        timeout: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Rpc {
+        Rpc {
+            inner: Arc::new(Inner {
+                next_id: AtomicU64::new(0),
+                pending: Mutex::default(),
+                outbound,
+                timeout,
+                clock,
+            }),
+        }
+    }
+
+    /// Send a request. The returned handle can be awaited or waited on.
+    pub fn request(&self, body: &[u8]) -> Result<Pending> {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = self.inner.clock.now();
+        self.inner.expire(now);
+        let slot = Arc::new(Slot {
+            state: Mutex::default(),
+            ready: Condvar::new(),
+            deadline: now + self.inner.timeout,
+        });
+        self.inner.pending.lock().unwrap().insert(id, slot.clone());
+
+        if self
+            .inner
+            .outbound
+            .send(message(REQUEST, id, body))
+            .is_err()
+        {
+            self.inner.pending.lock().unwrap().remove(&id);
+            return Err(Error::Rpc(String::from("connection writer is gone")));
+        }
+        Ok(Pending {
+            slot,
+            clock: self.inner.clock.clone(),
+        })
+    }
+
+    /// Answer a request from the peer.
+    pub fn respond(&self, request: &Request, body: &[u8]) -> Result<()> {
+        self.inner
+            .outbound
+            .send(message(RESPONSE, request.id, body))
+            .map_err(|_| Error::Rpc(String::from("connection writer is gone")))
+    }
+
+    /// Handle one inbound RPC message. Responses complete their pending
+    /// request; requests from the peer are returned to be answered.
+    pub fn receive(&self, payload: &[u8]) -> Result<Option<Request>> {
+        if payload.len() < HEADER_LENGTH {
+            return Err(Error::Rpc(format!("{} byte RPC message", payload.len())));
+        }
+        let id = u64::from_be_bytes(payload[1..HEADER_LENGTH].try_into().unwrap());
+        let body = payload[HEADER_LENGTH..].to_vec();
+
+        match payload[0] {
+            REQUEST => Ok(Some(Request { id, body })),
+            RESPONSE => {
+                // a response to a request that already timed out is dropped
+                if let Some(slot) = self.inner.pending.lock().unwrap().remove(&id) {
+                    slot.complete(Ok(body));
+                }
+                Ok(None)
+            }
+            kind => Err(Error::Rpc(format!("unknown RPC message kind {kind}"))),
+        }
+    }
+}
+
+impl Drop for Rpc {
+    fn drop(&mut self) {
+        for (_, slot) in self.inner.pending.lock().unwrap().drain() {
+            slot.complete(Err(Error::Rpc(String::from("connection closed"))));
+        }
+    }
+}
+
+/// A request waiting for its response.
+pub struct Pending {
+    slot: Arc<Slot>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Pending {
+    /// Block until the response arrives or the request times out.
+    pub fn wait(self) -> Result<Vec<u8>> {
+        let mut state = self.slot.state.lock().unwrap();
+        loop {
+            if let Some(outcome) = state.outcome.take() {
+                return outcome;
+            }
+            let now = self.clock.now();
+            if now >= self.slot.deadline {
+                return Err(Error::Timeout);
+            }
+            let left = self.slot.deadline - now;
+            let (next, waited) = self
+                .slot
+                .ready
+                .wait_timeout(state, self.clock.real_timeout(left))
+                .unwrap();
+            if waited.timed_out() {
+                self.clock.timed_out(left);
+            }
+            state = next;
+        }
+    }
+}
+
+impl Future for Pending {
+    type Output = Result<Vec<u8>>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.slot.state.lock().unwrap();
+        if let Some(outcome) = state.outcome.take() {
+            return Poll::Ready(outcome);
+        }
+        let now = self.clock.now();
+        if now >= self.slot.deadline {
+            return Poll::Ready(Err(Error::Timeout));
+        }
+        state.waker = Some(context.waker().clone());
+        if !state.alarm {
+            state.alarm = true;
+            let left = self.slot.deadline - now;
+            timer().set(Alarm {
+                at: Instant::now() + self.clock.real_timeout(left),
+                left,
+                clock: self.clock.clone(),
+                slot: Arc::downgrade(&self.slot),
+            });
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::VirtualClock;
+    use std::sync::mpsc;
+    use std::task::Wake;
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut context = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    fn parts(frame: Frame) -> (u8, u64, Vec<u8>) {
+        let payload = frame.into_payload();
+        let id = u64::from_be_bytes(payload[1..HEADER_LENGTH].try_into().unwrap());
+        (payload[0], id, payload[HEADER_LENGTH..].to_vec())
+    }
+
+    #[test]
+    fn responses_complete_their_request() {
+        let (outbound, sent) = mpsc::sync_channel(4);
+        let rpc = Rpc::new(outbound, Duration::from_secs(60));
+        let first = rpc.request(b"one").unwrap();
+        let second = rpc.request(b"two").unwrap();
+        let (kind, first_id, body) = parts(sent.recv().unwrap());
+        assert_eq!((kind, body.as_slice()), (REQUEST, &b"one"[..]));
+        let (_, second_id, _) = parts(sent.recv().unwrap());
+        assert_ne!(first_id, second_id);
+
+        // answered out of order, each lands on its own request
+        let answer = |id, body: &[u8]| message(RESPONSE, id, body).into_payload();
+        assert_eq!(rpc.receive(&answer(second_id, b"2")).unwrap(), None);
+        assert_eq!(rpc.receive(&answer(first_id, b"1")).unwrap(), None);
+        assert_eq!(second.wait().unwrap(), b"2");
+        assert_eq!(first.wait().unwrap(), b"1");
+    }
+
+    #[test]
+    fn requests_from_the_peer_are_answered_with_their_id() {
+        let (outbound, sent) = mpsc::sync_channel(4);
+        let rpc = Rpc::new(outbound, Duration::from_secs(60));
+        let request = rpc
+            .receive(&message(REQUEST, 7, b"ping").into_payload())
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.body, b"ping");
+        rpc.respond(&request, b"pong").unwrap();
+        assert_eq!(parts(sent.recv().unwrap()), (RESPONSE, 7, b"pong".to_vec()));
+    }
+
+    #[test]
+    fn unanswered_requests_time_out_on_the_clock() {
+        let (outbound, _sent) = mpsc::sync_channel(4);
+        let clock = Arc::new(VirtualClock::default());
+        let rpc = Rpc::with_clock(outbound, Duration::from_secs(30), clock.clone());
+        let pending = rpc.request(b"hello").unwrap();
+        clock.advance(Duration::from_secs(31));
+        assert!(matches!(pending.wait(), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn awaited_requests_time_out_on_the_clock() {
+        let (outbound, _sent) = mpsc::sync_channel(4);
+        let clock = Arc::new(VirtualClock::default());
+        let rpc = Rpc::with_clock(outbound, Duration::from_secs(30), clock.clone());
+        let started = clock.now();
+        let pending = rpc.request(b"hello").unwrap();
+        assert!(matches!(block_on(pending), Err(Error::Timeout)));
+        assert!(clock.now() - started >= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn overdue_requests_leave_the_table() {
+        let (outbound, _sent) = mpsc::sync_channel(4);
+        let clock = Arc::new(VirtualClock::default());
+        let rpc = Rpc::with_clock(outbound, Duration::from_secs(30), clock.clone());
+        let overdue = rpc.request(b"one").unwrap();
+        clock.advance(Duration::from_secs(31));
+        let _fresh = rpc.request(b"two").unwrap();
+        assert_eq!(rpc.inner.pending.lock().unwrap().len(), 1);
+        assert!(matches!(overdue.wait(), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn a_full_queue_holds_requests_back() {
+        let (outbound, sent) = mpsc::sync_channel(1);
+        let rpc = Arc::new(Rpc::new(outbound, Duration::from_secs(60)));
+        let (done, finished) = mpsc::channel();
+        let sender = rpc.clone();
+        thread::spawn(move || {
+            let first = sender.request(b"one").unwrap();
+            let second = sender.request(b"two").unwrap();
+            done.send((first, second)).unwrap();
+        });
+        assert!(finished.recv_timeout(Duration::from_millis(50)).is_err());
+        assert_eq!(parts(sent.recv().unwrap()).2, b"one");
+        assert_eq!(parts(sent.recv().unwrap()).2, b"two");
+        finished.recv().unwrap();
+    }
+
+    #[test]
+    fn malformed_messages_are_errors() {
+        let (outbound, _sent) = mpsc::sync_channel(4);
+        let rpc = Rpc::new(outbound, Duration::from_secs(60));
+        assert!(matches!(rpc.receive(&[RESPONSE, 0, 0]), Err(Error::Rpc(_))));
+        assert!(matches!(
+            rpc.receive(&message(9, 0, b"").into_payload()),
+            Err(Error::Rpc(_))
+        ));
+    }
+
+    #[test]
+    fn dropping_the_rpc_fails_what_is_still_pending() {
+        let (outbound, _sent) = mpsc::sync_channel(4);
+        let rpc = Rpc::new(outbound, Duration::from_secs(60));
+        let pending = rpc.request(b"hello").unwrap();
+        drop(rpc);
+        assert!(matches!(pending.wait(), Err(Error::Rpc(_))));
+    }
+
+    #[test]
+    fn requests_fail_once_the_writer_is_gone() {
+        let (outbound, sent) = mpsc::sync_channel(4);
+        drop(sent);
+        let rpc = Rpc::new(outbound, Duration::from_secs(60));
+        assert!(matches!(rpc.request(b"hello"), Err(Error::Rpc(_))));
+    }
+}
@@ -0,0 +1,256 @@
+//! Sources of time and randomness
+//!
+//! Everything that reads the clock or draws random bytes takes them from a
+//! `Sources`, so tests can run on a seeded generator and a clock that only
+//! moves when told to, and a failure seen once can be replayed.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Longest a blocking wait on a `VirtualClock` really takes.
+const VIRTUAL_WAIT: Duration = Duration::from_millis(10);
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// The wall clock time matching `now`, for timestamps that leave the
+    /// process.
+    fn system_time(&self) -> SystemTime;
+
+    /// Let `duration` pass.
+    fn sleep(&self, duration: Duration);
+
+    /// How long to really block on something, such as a socket read, that
+    /// this clock expects within `timeout`.
+    fn real_timeout(&self, timeout: Duration) -> Duration {
+        timeout
+    }
+
+    /// A wait of `real_timeout(timeout)` ran out.
+    fn timed_out(&self, _timeout: Duration) {}
+}
+
+pub trait Rng: Send + Sync {
+    fn fill(&self, bytes: &mut [u8]);
+
+    fn next_u64(&self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// A value in `0..=max`. The slight bias of the modulo doesn't matter for
+    /// jitter.
+    fn below_or_equal(&self, max: u64) -> u64 {
+        match max.checked_add(1) {
+            Some(bound) => self.next_u64() % bound,
+            None => self.next_u64(),
+        }
+    }
+}
+
+/// The real clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// A clock that stands still until `advance` is called, or something waits
+/// on it. Sleeping advances it at once, and a blocking wait really takes at
+/// most `VIRTUAL_WAIT`, advancing it by the whole timeout if it runs out. Idle
+/// time is skipped, so pings, lifetimes and rate limits play out as fast as
+/// the work in between allows.
+pub struct VirtualClock {
+    start: Instant,
+    /// The wall clock time at `start`.
+    epoch: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+/// Where a `VirtualClock`'s wall clock starts unless told otherwise, as time
+/// since the Unix epoch: 2000-01-01T00:00:00Z, so seeded runs log the same
+/// times.
+pub const VIRTUAL_EPOCH: Duration = Duration::from_secs(946_684_800);
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        VirtualClock::starting_at(UNIX_EPOCH + VIRTUAL_EPOCH)
+    }
+}
+
+impl VirtualClock {
+    /// A clock whose wall clock time starts at `epoch`.
+    pub fn starting_at(epoch: SystemTime) -> VirtualClock {
+        VirtualClock {
+            start: Instant::now(),
+            epoch,
+            elapsed: Mutex::default(),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.epoch + *self.elapsed.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    fn real_timeout(&self, timeout: Duration) -> Duration {
+        timeout.min(VIRTUAL_WAIT)
+    }
+
+    fn timed_out(&self, timeout: Duration) {
+        self.advance(timeout);
+    }
+}
+
+/// The operating system's random source, through `rand`.
+pub struct SystemRng;
+
+impl Rng for SystemRng {
+    fn fill(&self, bytes: &mut [u8]) {
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), bytes)
+    }
+}
+
+/// A deterministic generator (SplitMix64). Not for anything that must be
+/// unpredictable, which is fine for masks and handshake keys in tests.
+pub struct SeededRng {
+    state: AtomicU64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        SeededRng {
+            state: AtomicU64::new(seed),
+        }
+    }
+}
+
+impl Rng for SeededRng {
+    fn fill(&self, bytes: &mut [u8]) {
+        const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+        for chunk in bytes.chunks_mut(8) {
+            let mut z = self
+                .state
+                .fetch_add(GAMMA, Ordering::Relaxed)
+                .wrapping_add(GAMMA);
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^= z >> 31;
+            chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+/// The clock and random source a server or client runs on.
+#[derive(Clone)]
+pub struct Sources {
+    pub clock: Arc<dyn Clock>,
+    pub rng: Arc<dyn Rng>,
+}
+
+impl Default for Sources {
+    fn default() -> Self {
+        Sources {
+            clock: Arc::new(SystemClock),
+            rng: Arc::new(SystemRng),
+        }
+    }
+}
+
+impl Sources {
+    /// Seeded randomness on a virtual clock. The clock is returned as well so
+    /// the caller can move time forward.
+    pub fn simulated(seed: u64) -> (Sources, Arc<VirtualClock>) {
+        let clock = Arc::new(VirtualClock::default());
+        let sources = Sources {
+            clock: clock.clone(),
+            rng: Arc::new(SeededRng::new(seed)),
+        };
+        (sources, clock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_clock_moves_only_when_told_or_waited_on() {
+        let clock = VirtualClock::default();
+        let (start, epoch) = (clock.now(), clock.system_time());
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(1));
+        clock.sleep(Duration::from_secs(2));
+        clock.timed_out(Duration::from_secs(3));
+        assert_eq!(clock.now() - start, Duration::from_secs(6));
+        assert_eq!(
+            clock.system_time().duration_since(epoch).unwrap(),
+            Duration::from_secs(6)
+        );
+    }
+
+    #[test]
+    fn virtual_wall_clock_starts_at_a_fixed_time() {
+        let first = VirtualClock::default();
+        thread::sleep(Duration::from_millis(5));
+        let second = VirtualClock::default();
+        assert_eq!(first.system_time(), second.system_time());
+        assert_eq!(first.system_time(), UNIX_EPOCH + VIRTUAL_EPOCH);
+
+        let epoch = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(VirtualClock::starting_at(epoch).system_time(), epoch);
+    }
+
+    #[test]
+    fn virtual_waits_are_short_in_real_time() {
+        let clock = VirtualClock::default();
+        assert_eq!(clock.real_timeout(Duration::from_secs(60)), VIRTUAL_WAIT);
+        assert_eq!(
+            clock.real_timeout(Duration::from_millis(1)),
+            Duration::from_millis(1)
+        );
+        assert_eq!(
+            SystemClock.real_timeout(Duration::from_secs(60)),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn seeded_generators_repeat() {
+        let (a, b) = (SeededRng::new(7), SeededRng::new(7));
+        let mut bytes = [[0; 13]; 2];
+        a.fill(&mut bytes[0]);
+        b.fill(&mut bytes[1]);
+        assert_eq!(bytes[0], bytes[1]);
+        assert_ne!(SeededRng::new(8).next_u64(), SeededRng::new(7).next_u64());
+        assert!((0..100).all(|_| a.below_or_equal(3) <= 3));
+    }
+}
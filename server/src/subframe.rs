@@ -0,0 +1,151 @@
+//! Length-prefixed sub-records inside Binary messages
+
+use crate::error::{Error, Result};
+use crate::frame::{Data, Frame, OpCode};
+use byteorder::{NetworkEndian, WriteBytesExt};
+
+/// Layout of a message: one version byte, then each record as a big endian
+/// `u32` length followed by that many bytes.
+pub const VERSION: u8 = 1;
+
+const LENGTH_BYTES: usize = 4;
+
+/// Codec for several logical records carried by one Binary message.
+pub struct SubFrame;
+
+impl SubFrame {
+    /// Pack records into a single message payload.
+    pub fn encode<'a>(records: impl IntoIterator<Item = &'a [u8]>) -> Result<Vec<u8>> {
+        let mut output = vec![VERSION];
+        for record in records {
+            let length = u32::try_from(record.len())
+                .map_err(|_| Error::SubFrame(format!("record of {} bytes", record.len())))?;
+            output.write_u32::<NetworkEndian>(length)?;
+            output.extend_from_slice(record);
+        }
+        Ok(output)
+    }
+
+    /// Iterate over the records of a message payload without copying them.
+    pub fn decode_iter(payload: &[u8]) -> Result<SubFrames<'_>> {
+        match payload.split_first() {
+            Some((&VERSION, rest)) => Ok(SubFrames { rest }),
+            Some((&version, _)) => Err(Error::SubFrameVersion(version)),
+            None => Err(Error::SubFrame(String::from("empty message"))),
+        }
+    }
+}
+
+/// Records of a sub-framed message. Stops after the first error.
+pub struct SubFrames<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for SubFrames<'a> {
+    type Item = Result<&'a [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let record = match self.rest.split_at_checked(LENGTH_BYTES) {
+            Some((length, rest)) => {
+                let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+                rest.split_at_checked(length)
+            }
+            None => None,
+        };
+        match record {
+            Some((record, rest)) => {
+                self.rest = rest;
+                Some(Ok(record))
+            }
+            None => {
+                let remaining = self.rest.len();
+                self.rest = &[];
+                Some(Err(Error::SubFrame(format!(
+                    "truncated record, {remaining} bytes left"
+                ))))
+            }
+        }
+    }
+}
+
+impl Frame {
+    /// A Binary message carrying several records.
+    pub fn sub_framed<'a>(records: impl IntoIterator<Item = &'a [u8]>) -> Result<Frame> {
+        Ok(Frame::message(
+            SubFrame::encode(records)?,
+            OpCode::Data(Data::Binary),
+        ))
+    }
+
+    /// The records carried by a sub-framed Binary message.
+    pub fn sub_frames(&self) -> Result<SubFrames<'_>> {
+        if self.header().opcode != OpCode::Data(Data::Binary) {
+            return Err(Error::SubFrame(String::from("not a Binary message")));
+        }
+        SubFrame::decode_iter(self.payload())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let records: [&[u8]; 3] = [b"first", b"", b"third"];
+        let payload = SubFrame::encode(records).unwrap();
+        assert_eq!(&payload[..6], &[VERSION, 0, 0, 0, 5, b'f']);
+        let decoded: Vec<_> = SubFrame::decode_iter(&payload)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(decoded, records);
+    }
+
+    #[test]
+    fn a_message_of_no_records_is_just_the_version() {
+        let payload = SubFrame::encode([]).unwrap();
+        assert_eq!(payload, [VERSION]);
+        assert_eq!(SubFrame::decode_iter(&payload).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn unknown_versions_and_empty_messages_are_refused() {
+        assert!(matches!(
+            SubFrame::decode_iter(&[2, 0, 0, 0, 0]),
+            Err(Error::SubFrameVersion(2))
+        ));
+        assert!(matches!(
+            SubFrame::decode_iter(&[]),
+            Err(Error::SubFrame(_))
+        ));
+    }
+
+    #[test]
+    fn truncated_records_end_the_iteration_with_an_error() {
+        // a whole record, then one claiming 9 bytes with only 2 left
+        let payload = [VERSION, 0, 0, 0, 1, b'a', 0, 0, 0, 9, b'b', b'c'];
+        let mut records = SubFrame::decode_iter(&payload).unwrap();
+        assert_eq!(records.next().unwrap().unwrap(), b"a");
+        assert!(matches!(records.next(), Some(Err(Error::SubFrame(_)))));
+        assert!(records.next().is_none());
+
+        // not even a whole length
+        let mut records = SubFrame::decode_iter(&[VERSION, 0, 0]).unwrap();
+        assert!(matches!(records.next(), Some(Err(Error::SubFrame(_)))));
+    }
+
+    #[test]
+    fn only_binary_messages_carry_sub_frames() {
+        let records: [&[u8]; 1] = [b"x"];
+        let frame = Frame::sub_framed(records).unwrap();
+        assert_eq!(frame.header().opcode, OpCode::Data(Data::Binary));
+        assert_eq!(frame.sub_frames().unwrap().count(), 1);
+
+        let text = Frame::message(frame.into_payload(), OpCode::Data(Data::Text));
+        assert!(matches!(text.sub_frames(), Err(Error::SubFrame(_))));
+    }
+}
@@ -0,0 +1,328 @@
+//! In-process server for testing client code
+//!
+//! `ws_test_server()` starts an echo server on an ephemeral loopback port in
+//! the calling process. The `TestServer` it returns can push frames to and
+//! close any connection, and keeps every data message clients sent. Frames
+//! are parsed, reassembled and validated, closes answered and idle clients
+//! pinged with the same code and `Config` settings as the server binary. The
+//! server stops when it is dropped.
+
+use crate::close::{self, ClosePolicy};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::extension;
+use crate::frame::{Control, Data, Frame, OpCode};
+use crate::handshake::{self, Checks, Response};
+use crate::reaper::Liveness;
+use crate::registry::{ConnectionId, Registry};
+use crate::sim::SystemClock;
+use crate::validate::{self, Fragments, Reserved, Validator};
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Start a test server with the default configuration.
+pub fn ws_test_server() -> TestServer {
+    TestServer::start(Config::default()).expect("can't start test server")
+}
+
+/// A server running inside the test process, stopped when dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+/// State the acceptor, the connections and the handle share.
+struct Shared {
+    checks: Checks,
+    validator: Validator,
+    close_policy: ClosePolicy,
+    /// Ping clients after they have been quiet this long.
+    ping_interval: Option<Duration>,
+    connections: Registry<Connection>,
+    received: Mutex<Vec<(ConnectionId, Frame)>>,
+    arrived: Condvar,
+    stopping: AtomicBool,
+}
+
+struct Connection {
+    stream: TcpStream,
+    outbound: mpsc::Sender<Frame>,
+}
+
+impl TestServer {
+    /// Start a server with the handshake checks, validation and close codes
+    /// `config` describes. It listens on an ephemeral loopback port, whatever
+    /// `config.addr` says.
+    pub fn start(config: Config) -> Result<TestServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            checks: config.checks(),
+            validator: config.validator(),
+            close_policy: config.close_policy,
+            ping_interval: config.ping_interval,
+            connections: Registry::default(),
+            received: Mutex::default(),
+            arrived: Condvar::new(),
+            stopping: AtomicBool::new(false),
+        });
+        let acceptor = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if shared.stopping.load(Ordering::Acquire) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        let shared = shared.clone();
+                        thread::spawn(move || serve(stream, &shared));
+                    }
+                }
+            })
+        };
+        Ok(TestServer {
+            addr,
+            shared,
+            acceptor: Some(acceptor),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Ids of the connections currently open, oldest first.
+    pub fn connections(&self) -> Vec<ConnectionId> {
+        let mut ids = self.shared.connections.ids();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Push a frame to a connection.
+    pub fn send(&self, id: ConnectionId, frame: Frame) -> Result<()> {
+        self.shared
+            .connections
+            .with(id, |connection| connection.outbound.send(frame).is_ok())
+            .filter(|sent| *sent)
+            .map(|_| ())
+            .ok_or_else(|| Error::Io(io::ErrorKind::NotConnected.into()))
+    }
+
+    /// Close a connection with `code`, as if the server decided to.
+    pub fn close(&self, id: ConnectionId, code: u16, reason: &str) -> Result<()> {
+        self.send(id, Frame::close(code, reason))
+    }
+
+    /// Every data message received so far, in arrival order, fragmented ones
+    /// put back together.
+    pub fn received(&self) -> Vec<(ConnectionId, Frame)> {
+        self.shared.received.lock().unwrap().clone()
+    }
+
+    /// Wait up to `timeout` for at least `count` data messages to have arrived,
+    /// returning all of them. Fails with `Error::Timeout` if too few came.
+    pub fn wait_for(&self, count: usize, timeout: Duration) -> Result<Vec<(ConnectionId, Frame)>> {
+        let deadline = Instant::now() + timeout;
+        let mut received = self.shared.received.lock().unwrap();
+        while received.len() < count {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(Error::Timeout);
+            }
+            received = self.shared.arrived.wait_timeout(received, left).unwrap().0;
+        }
+        Ok(received.clone())
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shared.stopping.store(true, Ordering::Release);
+        // wake the acceptor up so it sees it has to stop
+        TcpStream::connect(self.addr).ok();
+        for id in self.shared.connections.ids() {
+            self.shared.connections.with(id, |connection| {
+                connection.stream.shutdown(Shutdown::Both).ok()
+            });
+        }
+        if let Some(acceptor) = self.acceptor.take() {
+            acceptor.join().ok();
+        }
+    }
+}
+
+/// Read the upgrade request, refusing it if it fails `checks`. Returns the
+/// response to accept it with and the path it asked for otherwise.
+fn upgrade(mut stream: &TcpStream, checks: &Checks) -> Result<Option<(Response, String)>> {
+    let mut buffer = [0; 4096];
+    let size = handshake::read_head(&mut stream, &mut buffer)?;
+    let request = String::from_utf8_lossy(&buffer[..size]);
+    let response = handshake::parse_request(&request).and_then(|request| {
+        checks.check(&request)?;
+        Ok((
+            handshake::response(&request)?,
+            request.uri().path().to_string(),
+        ))
+    });
+    match response {
+        Ok(accepted) => Ok(Some(accepted)),
+        Err(error) => {
+            Response::new(handshake::rejection_status(&error)).write(&mut stream)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Ping the client whenever it has been quiet for `interval`, until `stop`
+/// is dropped or the connection is gone.
+fn keep_pinging(
+    outbound: mpsc::Sender<Frame>,
+    liveness: &Liveness,
+    interval: Duration,
+    stop: mpsc::Receiver<()>,
+) {
+    let mut pinged: Option<Instant> = None;
+    loop {
+        let heard = liveness.last_heard();
+        let due = pinged.map_or(heard, |pinged| pinged.max(heard)) + interval;
+        let now = Instant::now();
+        if due <= now {
+            let ping = Frame::message(Vec::new(), OpCode::Control(Control::Ping));
+            if outbound.send(ping).is_err() {
+                return;
+            }
+            pinged = Some(now);
+            continue;
+        }
+        if stop.recv_timeout(due - now) != Err(RecvTimeoutError::Timeout) {
+            return;
+        }
+    }
+}
+
+/// Echo data messages back, keeping a copy of each, until the connection
+/// closes. Whatever the server binary fails the connection for closes it here
+/// too, with the code `close_policy` gives.
+fn serve(stream: TcpStream, shared: &Shared) {
+    let (response, path) = match upgrade(&stream, &shared.checks) {
+        Ok(Some(accepted)) => accepted,
+        _ => return,
+    };
+    let (writer_stream, reader_stream) = match (stream.try_clone(), stream.try_clone()) {
+        (Ok(writer_stream), Ok(reader_stream)) => (writer_stream, reader_stream),
+        _ => return,
+    };
+    let (outbound, queue) = mpsc::channel::<Frame>();
+    // registered before the client hears back, so it is listed as soon as
+    // its handshake completes
+    let id = shared.connections.insert(Connection {
+        stream,
+        outbound: outbound.clone(),
+    });
+    if response.write(&mut &reader_stream).is_err() {
+        shared.connections.remove(id);
+        return;
+    }
+    let writer = thread::spawn(move || write_frames(writer_stream, queue));
+    let liveness = Arc::new(Liveness::new(Arc::new(SystemClock)));
+    let (stop, stopped) = mpsc::channel();
+    let pinger = shared.ping_interval.map(|interval| {
+        let (outbound, liveness) = (outbound.clone(), liveness.clone());
+        thread::spawn(move || keep_pinging(outbound, &liveness, interval, stopped))
+    });
+
+    let fail = |error: &Error| outbound.send(shared.close_policy.frame(error)).ok();
+    let validator = &shared.validator;
+    let max_payload = validator.max_payload(0);
+    let mut fragments = Fragments::default();
+    let mut reader = io::BufReader::new(reader_stream);
+    loop {
+        let frame = match Frame::parse_masked(&mut reader, max_payload) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(error) => {
+                if let Ok(error) = error.downcast::<Error>() {
+                    fail(&error);
+                }
+                break;
+            }
+        };
+        liveness.heard();
+        let frame = extension::unclaimed_bits(&frame)
+            .and_then(|()| validator.reassemble(&mut fragments, frame, Instant::now()));
+        let frame = match frame {
+            Ok(Some(frame)) => frame,
+            Ok(None) => continue,
+            Err(error) => {
+                fail(&error);
+                break;
+            }
+        };
+        let reply = match frame.header().opcode {
+            _ if validate::reserved(&frame).is_some() => match validator.reserved {
+                Reserved::Fail => {
+                    let opcode = u8::from(frame.header().opcode);
+                    fail(&Error::Protocol(format!("reserved opcode {opcode:#x}")));
+                    break;
+                }
+                Reserved::Drop => continue,
+                Reserved::Deliver => {
+                    Frame::message(frame.into_payload(), OpCode::Data(Data::Binary))
+                }
+            },
+            OpCode::Control(Control::Close) => {
+                match close::status(&frame) {
+                    Ok(code) => outbound.send(close::echo(code)).ok(),
+                    Err(error) => fail(&error),
+                };
+                break;
+            }
+            OpCode::Control(Control::Ping) => {
+                Frame::message(frame.into_payload(), OpCode::Control(Control::Pong))
+            }
+            OpCode::Control(_) => continue,
+            OpCode::Data(_) => {
+                if let Err(error) = validator.check(&path, &frame) {
+                    fail(&error);
+                    break;
+                }
+                shared.received.lock().unwrap().push((id, frame.clone()));
+                shared.arrived.notify_all();
+                let opcode = frame.header().opcode;
+                Frame::message(frame.into_payload(), opcode)
+            }
+        };
+        outbound.send(reply).ok();
+    }
+
+    if let Some(connection) = shared.connections.remove(id) {
+        connection.stream.shutdown(Shutdown::Read).ok();
+    }
+    drop(stop);
+    if let Some(pinger) = pinger {
+        pinger.join().ok();
+    }
+    drop(outbound);
+    writer.join().ok();
+}
+
+/// Write queued frames until the queue is dropped or a Close went out.
+fn write_frames(mut stream: TcpStream, queue: mpsc::Receiver<Frame>) {
+    for frame in queue {
+        let closing = frame.header().opcode == OpCode::Control(Control::Close);
+        let mut buffer = Vec::new();
+        if frame.format(&mut buffer).is_err() || stream.write_all(&buffer).is_err() {
+            break;
+        }
+        if closing {
+            break;
+        }
+    }
+    stream.shutdown(Shutdown::Write).ok();
+}
@@ -0,0 +1,247 @@
+//! Handshake rate limiting
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+/// What to do with a connection over the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverLimit {
+    /// Reset the connection without reading the request.
+    Reset,
+    /// Answer `429 Too Many Requests`.
+    Reply,
+}
+
+/// Sliding window limits on new handshakes, per peer address and overall.
+/// Only admitted handshakes count, so a client that keeps retrying is let in
+/// again as soon as its earlier handshakes leave the window.
+pub struct Throttle {
+    per_ip: Option<usize>,
+    global: Option<usize>,
+    window: Duration,
+    peers: HashMap<IpAddr, VecDeque<Instant>>,
+    all: VecDeque<Instant>,
+    last_sweep: Option<Instant>,
+}
+
+fn expire(times: &mut VecDeque<Instant>, cutoff: Option<Instant>) {
+    while matches!((times.front(), cutoff), (Some(time), Some(cutoff)) if *time <= cutoff) {
+        times.pop_front();
+    }
+}
+
+impl Throttle {
+    pub fn new(per_ip: Option<usize>, global: Option<usize>, window: Duration) -> Throttle {
+        Throttle {
+            per_ip,
+            global,
+            window,
+            peers: HashMap::new(),
+            all: VecDeque::new(),
+            last_sweep: None,
+        }
+    }
+
+    /// Whether a handshake from `ip` may go ahead at `now`. Admitted
+    /// handshakes are counted.
+    pub fn admit(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let cutoff = now.checked_sub(self.window);
+        let last_sweep = *self.last_sweep.get_or_insert(now);
+        if now.duration_since(last_sweep) >= self.window {
+            // forget peers that went quiet
+            self.peers.retain(|_, times| {
+                expire(times, cutoff);
+                !times.is_empty()
+            });
+            self.last_sweep = Some(now);
+        }
+
+        expire(&mut self.all, cutoff);
+        if self.global.is_some_and(|limit| self.all.len() >= limit) {
+            return false;
+        }
+        if let Some(limit) = self.per_ip {
+            let times = self.peers.entry(ip).or_default();
+            expire(times, cutoff);
+            if times.len() >= limit {
+                return false;
+            }
+            times.push_back(now);
+        }
+        if self.global.is_some() {
+            self.all.push_back(now);
+        }
+        true
+    }
+
+    /// Length of the sliding window, the longest a refused client has to wait.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+}
+
+/// Paces handshakes while the server warms up, so clients reconnecting all at
+/// once after a restart are let in gradually. The pace ramps linearly from
+/// `initial` to `peak` handshakes per second over `duration`, after which
+/// handshakes are no longer paced.
+pub struct Warmup {
+    initial: f64,
+    peak: f64,
+    duration: Duration,
+    started: Instant,
+    /// Earliest the next handshake may go ahead.
+    next: Instant,
+    handshakes: u64,
+    deferred: u64,
+    deferred_for: Duration,
+}
+
+impl Warmup {
+    pub fn new(initial: u32, peak: u32, duration: Duration, started: Instant) -> Warmup {
+        Warmup {
+            initial: initial.max(1).into(),
+            peak: peak.max(initial).max(1).into(),
+            duration,
+            started,
+            next: started,
+            handshakes: 0,
+            deferred: 0,
+            deferred_for: Duration::ZERO,
+        }
+    }
+
+    pub fn is_over(&self, now: Instant) -> bool {
+        now.duration_since(self.started) >= self.duration
+    }
+
+    /// How long the handshake arriving at `now` has to wait for its turn.
+    pub fn pace(&mut self, now: Instant) -> Duration {
+        if self.is_over(now) {
+            return Duration::ZERO;
+        }
+        self.handshakes += 1;
+        let turn = self.next.max(now);
+        let progress =
+            turn.duration_since(self.started).as_secs_f64() / self.duration.as_secs_f64();
+        let rate = self.initial + (self.peak - self.initial) * progress.min(1.0);
+        self.next = turn + Duration::from_secs_f64(1.0 / rate);
+
+        let wait = turn - now;
+        if !wait.is_zero() {
+            self.deferred += 1;
+            self.deferred_for += wait;
+        }
+        wait
+    }
+
+    /// Handshakes paced so far.
+    pub fn handshakes(&self) -> u64 {
+        self.handshakes
+    }
+
+    /// Handshakes that had to wait for their turn.
+    pub fn deferred(&self) -> u64 {
+        self.deferred
+    }
+
+    /// Total time handshakes were held back, one after the other.
+    pub fn deferred_for(&self) -> Duration {
+        self.deferred_for
+    }
+}
+
+/// Close the connection with a TCP reset rather than an orderly shutdown, so
+/// the server keeps no socket in `TIME_WAIT` for it.
+pub fn reset(stream: TcpStream) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        // SAFETY: the descriptor is open for as long as `stream` lives and
+        // `linger` is the type SO_LINGER expects
+        unsafe {
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_LINGER,
+                &linger as *const libc::linger as *const libc::c_void,
+                std::mem::size_of::<libc::linger>() as libc::socklen_t,
+            );
+        }
+    }
+    drop(stream);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const ALICE: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const BOB: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn per_address_limit_only_holds_back_that_address() {
+        let mut throttle = Throttle::new(Some(2), None, Duration::from_secs(10));
+        let now = Instant::now();
+        assert!(throttle.admit(ALICE, now));
+        assert!(throttle.admit(ALICE, now));
+        assert!(!throttle.admit(ALICE, now));
+        assert!(throttle.admit(BOB, now));
+    }
+
+    #[test]
+    fn global_limit_holds_back_everyone() {
+        let mut throttle = Throttle::new(None, Some(2), Duration::from_secs(10));
+        let now = Instant::now();
+        assert!(throttle.admit(ALICE, now));
+        assert!(throttle.admit(BOB, now));
+        assert!(!throttle.admit(ALICE, now));
+        assert!(!throttle.admit(BOB, now));
+    }
+
+    #[test]
+    fn handshakes_leave_the_window_as_it_slides() {
+        let window = Duration::from_secs(10);
+        let mut throttle = Throttle::new(Some(1), Some(1), window);
+        let start = Instant::now();
+        assert!(throttle.admit(ALICE, start));
+        assert!(!throttle.admit(ALICE, start + window - Duration::from_millis(1)));
+        assert!(throttle.admit(ALICE, start + window));
+    }
+
+    #[test]
+    fn refused_handshakes_are_not_counted() {
+        let window = Duration::from_secs(10);
+        let mut throttle = Throttle::new(Some(1), None, window);
+        let start = Instant::now();
+        assert!(throttle.admit(ALICE, start));
+        // retrying while refused doesn't push the next admission back
+        for second in 1..10 {
+            assert!(!throttle.admit(ALICE, start + Duration::from_secs(second)));
+        }
+        assert!(throttle.admit(ALICE, start + window));
+    }
+
+    #[test]
+    fn quiet_peers_are_forgotten() {
+        let window = Duration::from_secs(10);
+        let mut throttle = Throttle::new(Some(1), None, window);
+        let start = Instant::now();
+        assert!(throttle.admit(ALICE, start));
+        assert!(throttle.admit(BOB, start + window * 2));
+        assert_eq!(throttle.peers.len(), 1);
+    }
+
+    #[test]
+    fn no_limits_admit_everything() {
+        let mut throttle = Throttle::new(None, None, Duration::from_secs(10));
+        let now = Instant::now();
+        assert!((0..1000).all(|_| throttle.admit(ALICE, now)));
+    }
+}
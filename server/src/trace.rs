@@ -0,0 +1,133 @@
+//! W3C Trace Context propagation
+
+use http::Request;
+
+/// Trace context carried by the upgrade request, per
+/// <https://www.w3.org/TR/trace-context/>.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub version: u8,
+    pub trace_id: String,
+    pub parent_id: String,
+    pub flags: u8,
+    /// Vendor specific `tracestate`, passed along untouched.
+    pub state: Option<String>,
+}
+
+impl TraceContext {
+    /// Extract the trace context from the request headers. An invalid
+    /// `traceparent` is ignored together with its `tracestate`, as the
+    /// specification requires.
+    pub fn from_request(request: &Request<()>) -> Option<TraceContext> {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let mut context = TraceContext::parse(header("traceparent")?)?;
+        context.state = header("tracestate")
+            .map(str::trim)
+            .filter(|state| !state.is_empty())
+            .map(String::from);
+        Some(context)
+    }
+
+    /// Parse a `traceparent` header value.
+    pub fn parse(traceparent: &str) -> Option<TraceContext> {
+        let traceparent = traceparent.trim();
+        let mut fields = traceparent.split('-');
+        let version = fields.next().filter(|field| is_hex(field, 2))?;
+        let trace_id = fields.next().filter(|field| is_hex(field, 32))?;
+        let parent_id = fields.next().filter(|field| is_hex(field, 16))?;
+        let flags = fields.next().filter(|field| is_hex(field, 2))?;
+
+        let version = u8::from_str_radix(version, 16).ok()?;
+        // version 00 has exactly four fields, later versions may append more
+        if version == 0xff || (version == 0 && fields.next().is_some()) {
+            return None;
+        }
+        if trace_id.bytes().all(|b| b == b'0') || parent_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+
+        Some(TraceContext {
+            version,
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+            flags: u8::from_str_radix(flags, 16).ok()?,
+            state: None,
+        })
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+}
+
+/// Lowercase hex of exactly `length` characters.
+fn is_hex(field: &str, length: usize) -> bool {
+    field.len() == length
+        && field
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parses_the_specification_example() {
+        let context = TraceContext::parse(TRACEPARENT).unwrap();
+        assert_eq!(context.version, 0);
+        assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.parent_id, "00f067aa0ba902b7");
+        assert!(context.is_sampled());
+        assert_eq!(context.state, None);
+    }
+
+    #[test]
+    fn refuses_malformed_values() {
+        for traceparent in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ] {
+            assert_eq!(TraceContext::parse(traceparent), None, "{traceparent}");
+        }
+    }
+
+    #[test]
+    fn later_versions_may_append_fields() {
+        let traceparent = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra";
+        let context = TraceContext::parse(traceparent).unwrap();
+        assert_eq!(context.version, 1);
+        assert!(!context.is_sampled());
+    }
+
+    #[test]
+    fn tracestate_only_comes_with_a_valid_traceparent() {
+        let request = Request::get("/")
+            .header("traceparent", TRACEPARENT)
+            .header("tracestate", " vendor=value ")
+            .body(())
+            .unwrap();
+        let context = TraceContext::from_request(&request).unwrap();
+        assert_eq!(context.state.as_deref(), Some("vendor=value"));
+
+        let request = Request::get("/")
+            .header("traceparent", "garbage")
+            .header("tracestate", "vendor=value")
+            .body(())
+            .unwrap();
+        assert_eq!(TraceContext::from_request(&request), None);
+    }
+}
@@ -0,0 +1,484 @@
+//! File transfer over Binary messages
+//!
+//! The sender offers a file, the receiver answers with the offset it already
+//! holds, the sender streams the rest in chunks and finishes with the SHA-256
+//! of the whole file, which the receiver checks before keeping it.
+
+use crate::close;
+use crate::error::{Error, Result};
+use crate::frame::{Control, Data, Frame, OpCode};
+use crate::sha256::Sha256;
+use crate::sim::Rng;
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// `OFFER`: u64 size, then the file name.
+const OFFER: u8 = 0;
+/// `ACCEPT`: u64 offset to resume from.
+const ACCEPT: u8 = 1;
+/// `CHUNK`: file bytes.
+const CHUNK: u8 = 2;
+/// `END`: SHA-256 of the whole file.
+const END: u8 = 3;
+/// `DONE`: empty, the file was verified and kept.
+const DONE: u8 = 4;
+/// `FAILED`: the reason, as text.
+const FAILED: u8 = 5;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest file `receive_file` accepts unless told otherwise.
+pub const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Partial files a transfer in this process is writing to.
+static CLAIMED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// A partial file reserved for one transfer, released when dropped.
+struct Claim(PathBuf);
+
+impl Claim {
+    /// The partial file for `name` in `dir`: `<name>.part`, where a transfer
+    /// resumes from, unless another transfer is writing to it, then
+    /// `<name>.1.part`, `<name>.2.part` and so on.
+    fn partial(dir: &Path, name: &str) -> Claim {
+        let mut claimed = CLAIMED.lock().unwrap();
+        let path = (0..)
+            .map(|n| match n {
+                0 => dir.join(format!("{name}.part")),
+                n => dir.join(format!("{name}.{n}.part")),
+            })
+            .find(|path| !claimed.contains(path))
+            .unwrap();
+        claimed.insert(path.clone());
+        Claim(path)
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        CLAIMED.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// `name` with ` (n)` before its extension, for n > 0.
+fn numbered(name: &str, n: u32) -> String {
+    if n == 0 {
+        return name.to_string();
+    }
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{stem} ({n}).{extension}"),
+        _ => format!("{name} ({n})"),
+    }
+}
+
+/// Copy `partial` to `target`, failing with `AlreadyExists` rather than
+/// replace a file there.
+fn copy_new(partial: &Path, target: &Path) -> io::Result<()> {
+    let mut output = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(target)?;
+    let copied = io::copy(&mut File::open(partial)?, &mut output).and_then(|_| output.sync_all());
+    if copied.is_err() {
+        fs::remove_file(target).ok();
+    }
+    copied
+}
+
+/// Put `partial` at `target` unless something is there already: hard linked,
+/// or copied on filesystems without hard links.
+fn link_new(partial: &Path, target: &Path) -> io::Result<()> {
+    // unlike a rename, linking fails rather than replace the target
+    match fs::hard_link(partial, target) {
+        Err(error) if error.kind() != ErrorKind::AlreadyExists => {
+            // FAT and some network mounts refuse links with EPERM or
+            // EOPNOTSUPP, whatever the target
+            copy_new(partial, target)
+        }
+        linked => linked,
+    }
+}
+
+/// Move `partial` to `name` in `dir`, or to a numbered variant of it if a file
+/// by that name exists, never replacing one. Returns where it went.
+fn keep(partial: &Path, dir: &Path, name: &str) -> Result<PathBuf> {
+    for n in 0.. {
+        let target = dir.join(numbered(name, n));
+        match link_new(partial, &target) {
+            Ok(()) => {
+                fs::remove_file(partial)?;
+                return Ok(target);
+            }
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error.into()),
+        }
+    }
+    unreachable!()
+}
+
+/// How a call to `receive_file` ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Received {
+    /// A file was received and verified.
+    File(PathBuf),
+    /// The peer closed the connection, with this close code if any. A partial
+    /// file is kept so the transfer can be resumed.
+    Closed(Option<u16>),
+}
+
+fn message(kind: u8, body: &[u8]) -> Frame {
+    let mut payload = Vec::with_capacity(1 + body.len());
+    payload.push(kind);
+    payload.extend_from_slice(body);
+    Frame::message(payload, OpCode::Data(Data::Binary))
+}
+
+/// Write a frame, masked with bytes from `rng` if one is given.
+fn write_frame(stream: &mut impl Write, mut frame: Frame, rng: Option<&dyn Rng>) -> Result<()> {
+    if let Some(rng) = rng {
+        frame.header_mut().set_mask(rng);
+    }
+    let mut buffer = Vec::new();
+    frame
+        .format(&mut buffer)
+        .map_err(|error| Error::Transfer(error.to_string()))?;
+    stream.write_all(&buffer)?;
+    Ok(())
+}
+
+/// Read the next transfer message, skipping control frames other than Close.
+/// A Close frame or the connection going away yields the close code, if any,
+/// and a Close breaking the rules of `close::status` its error.
+fn read_message(stream: &mut impl Read) -> Result<std::result::Result<Frame, Option<u16>>> {
+    loop {
+        let frame = match Frame::parse(stream) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(Err(None)),
+            Err(error) => return Err(Error::Transfer(error.to_string())),
+        };
+        match frame.header().opcode {
+            OpCode::Control(Control::Close) => return Ok(Err(close::status(&frame)?)),
+            OpCode::Control(_) => continue,
+            OpCode::Data(Data::Binary) if !frame.payload().is_empty() => return Ok(Ok(frame)),
+            _ => return Err(Error::Transfer(String::from("expected a transfer message"))),
+        }
+    }
+}
+
+fn read_u64(body: &[u8]) -> Result<u64> {
+    body.get(..8)
+        .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| Error::Transfer(String::from("truncated message")))
+}
+
+fn file_digest(path: &Path) -> Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::default();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let size = file.read(&mut buffer)?;
+        if size == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buffer[..size]);
+    }
+}
+
+/// Push a file to the peer, resuming where a previous attempt stopped.
+/// `progress` is called with the bytes the receiver holds and the file size.
+/// Frames are masked with bytes from `rng`, as a client must.
+pub fn send_file(
+    stream: &mut (impl Read + Write),
+    path: &Path,
+    rng: &dyn Rng,
+    mut progress: impl FnMut(u64, u64),
+) -> Result<()> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::Transfer(format!("no file name in {}", path.display())))?;
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let digest = file_digest(path)?;
+
+    let mut offer = size.to_be_bytes().to_vec();
+    offer.extend_from_slice(name.as_bytes());
+    write_frame(stream, message(OFFER, &offer), Some(rng))?;
+
+    let reply = read_message(stream)?
+        .map_err(|_| Error::Transfer(String::from("receiver closed the connection")))?;
+    let mut offset = match reply.payload().split_first() {
+        Some((&ACCEPT, body)) => read_u64(body)?,
+        Some((&FAILED, reason)) => {
+            return Err(Error::Transfer(
+                String::from_utf8_lossy(reason).into_owned(),
+            ))
+        }
+        _ => return Err(Error::Transfer(String::from("expected ACCEPT"))),
+    };
+    progress(offset, size);
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        write_frame(stream, message(CHUNK, &buffer[..read]), Some(rng))?;
+        offset += read as u64;
+        progress(offset, size);
+    }
+    write_frame(stream, message(END, &digest), Some(rng))?;
+
+    let reply = read_message(stream)?
+        .map_err(|_| Error::Transfer(String::from("receiver closed the connection")))?;
+    match reply.payload().split_first() {
+        Some((&DONE, _)) => Ok(()),
+        Some((&FAILED, reason)) => Err(Error::Transfer(
+            String::from_utf8_lossy(reason).into_owned(),
+        )),
+        _ => Err(Error::Transfer(String::from("expected DONE"))),
+    }
+}
+
+/// Receive one file of at most `max_size` bytes into `dir`. Data lands in
+/// `<name>.part` until the digest checks out, so an interrupted transfer
+/// resumes from what is on disk. Concurrent transfers of the same name each
+/// get their own partial file, and a file that exists already is never
+/// replaced: the new one is kept as `<stem> (1).<extension>` and so on.
+/// `progress` is called with the bytes held and the file size.
+pub fn receive_file(
+    stream: &mut (impl Read + Write),
+    dir: &Path,
+    max_size: u64,
+    mut progress: impl FnMut(u64, u64),
+) -> Result<Received> {
+    let offer = match read_message(stream)? {
+        Ok(offer) => offer,
+        Err(code) => return Ok(Received::Closed(code)),
+    };
+    let body = match offer.payload().split_first() {
+        Some((&OFFER, body)) => body,
+        _ => return Err(Error::Transfer(String::from("expected OFFER"))),
+    };
+    let size = read_u64(body)?;
+    let name = std::str::from_utf8(&body[8..])?;
+    // never let the peer pick a path outside `dir`
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        let reason = format!("refusing file name {name:?}");
+        write_frame(stream, message(FAILED, reason.as_bytes()), None)?;
+        return Err(Error::Transfer(reason));
+    }
+    if size > max_size {
+        let reason = format!("file of {size} bytes exceeds the limit of {max_size}");
+        write_frame(stream, message(FAILED, reason.as_bytes()), None)?;
+        return Err(Error::Transfer(reason));
+    }
+
+    let claim = Claim::partial(dir, name);
+    let partial = &claim.0;
+    let mut file = OpenOptions::new().create(true).append(true).open(partial)?;
+    let mut offset = file.metadata()?.len();
+    if offset > size {
+        // left over from a different file with the same name
+        file.set_len(0)?;
+        offset = 0;
+    }
+    write_frame(stream, message(ACCEPT, &offset.to_be_bytes()), None)?;
+    progress(offset, size);
+
+    loop {
+        let frame = match read_message(stream)? {
+            Ok(frame) => frame,
+            Err(code) => return Ok(Received::Closed(code)),
+        };
+        match frame.payload().split_first() {
+            Some((&CHUNK, data)) => {
+                if offset + data.len() as u64 > size {
+                    let reason = String::from("more data than offered");
+                    write_frame(stream, message(FAILED, reason.as_bytes()), None)?;
+                    return Err(Error::Transfer(reason));
+                }
+                file.write_all(data)?;
+                offset += data.len() as u64;
+                progress(offset, size);
+            }
+            Some((&END, digest)) => {
+                file.flush()?;
+                drop(file);
+                if offset != size || digest != file_digest(partial)? {
+                    // start over next time rather than resume from bad data
+                    fs::remove_file(partial)?;
+                    let reason = String::from("SHA-256 mismatch");
+                    write_frame(stream, message(FAILED, reason.as_bytes()), None)?;
+                    return Err(Error::Transfer(reason));
+                }
+                let target = keep(partial, dir, name)?;
+                write_frame(stream, message(DONE, &[]), None)?;
+                return Ok(Received::File(target));
+            }
+            _ => return Err(Error::Transfer(String::from("expected CHUNK or END"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SystemRng;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// An empty directory of its own for each test.
+    fn scratch(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("transfer-{}-{test}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Send `path` to a receiver storing into `dir`, returning what both sides
+    /// made of it: for the sender, the offset the receiver resumed from.
+    fn push(path: &Path, dir: &Path, max_size: u64) -> (Result<u64>, Result<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let path = path.to_path_buf();
+        let sender = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut resumed = None;
+            send_file(&mut stream, &path, &SystemRng, |held, _| {
+                resumed.get_or_insert(held);
+            })
+            .map(|()| resumed.unwrap())
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        let received = receive_file(&mut stream, dir, max_size, |_, _| {});
+        (sender.join().unwrap(), received)
+    }
+
+    #[test]
+    fn existing_files_are_never_replaced() {
+        let source = scratch("source");
+        let dir = scratch("existing");
+        let path = source.join("report.pdf");
+        fs::write(&path, b"first").unwrap();
+        let (sent, received) = push(&path, &dir, DEFAULT_MAX_SIZE);
+        sent.unwrap();
+        assert_eq!(received.unwrap(), Received::File(dir.join("report.pdf")));
+
+        fs::write(&path, b"second").unwrap();
+        let (sent, received) = push(&path, &dir, DEFAULT_MAX_SIZE);
+        sent.unwrap();
+        assert_eq!(
+            received.unwrap(),
+            Received::File(dir.join("report (1).pdf"))
+        );
+        assert_eq!(fs::read(dir.join("report.pdf")).unwrap(), b"first");
+        assert_eq!(fs::read(dir.join("report (1).pdf")).unwrap(), b"second");
+        assert!(!dir.join("report.pdf.part").exists());
+    }
+
+    #[test]
+    fn transfers_resume_from_the_partial_file() {
+        let source = scratch("resume-source");
+        let dir = scratch("resume");
+        let path = source.join("greeting");
+        fs::write(&path, b"hello world").unwrap();
+        fs::write(dir.join("greeting.part"), b"hello ").unwrap();
+
+        let (sent, received) = push(&path, &dir, DEFAULT_MAX_SIZE);
+        assert_eq!(sent.unwrap(), 6);
+        assert_eq!(received.unwrap(), Received::File(dir.join("greeting")));
+        assert_eq!(fs::read(dir.join("greeting")).unwrap(), b"hello world");
+        assert!(!dir.join("greeting.part").exists());
+    }
+
+    #[test]
+    fn digest_mismatch_discards_the_partial_file() {
+        let source = scratch("mismatch-source");
+        let dir = scratch("mismatch");
+        let path = source.join("greeting");
+        fs::write(&path, b"hello world").unwrap();
+        // resumed from, but not the start of this file
+        fs::write(dir.join("greeting.part"), b"HELLO ").unwrap();
+
+        let (sent, received) = push(&path, &dir, DEFAULT_MAX_SIZE);
+        assert!(matches!(sent, Err(Error::Transfer(reason)) if reason == "SHA-256 mismatch"));
+        assert!(received.is_err());
+        assert!(!dir.join("greeting.part").exists());
+        assert!(!dir.join("greeting").exists());
+    }
+
+    #[test]
+    fn names_outside_the_directory_are_refused() {
+        let dir = scratch("names");
+        for name in ["", ".", "..", "a/b", "..\\b"] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let sender = thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                let mut offer = 5u64.to_be_bytes().to_vec();
+                offer.extend_from_slice(name.as_bytes());
+                write_frame(&mut stream, message(OFFER, &offer), Some(&SystemRng)).unwrap();
+                read_message(&mut stream).unwrap().unwrap().into_payload()
+            });
+            let (mut stream, _) = listener.accept().unwrap();
+            let received = receive_file(&mut stream, &dir, DEFAULT_MAX_SIZE, |_, _| {});
+            assert!(matches!(received, Err(Error::Transfer(_))), "{name:?}");
+            assert_eq!(sender.join().unwrap()[0], FAILED, "{name:?}");
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn copies_never_replace_a_file() {
+        let dir = scratch("copy");
+        let partial = dir.join("data.part");
+        fs::write(&partial, b"new").unwrap();
+        copy_new(&partial, &dir.join("data")).unwrap();
+        assert_eq!(fs::read(dir.join("data")).unwrap(), b"new");
+
+        fs::write(dir.join("taken"), b"old").unwrap();
+        let error = copy_new(&partial, &dir.join("taken")).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(dir.join("taken")).unwrap(), b"old");
+    }
+
+    #[test]
+    fn offers_over_the_limit_are_refused() {
+        let source = scratch("big-source");
+        let dir = scratch("big");
+        let path = source.join("big");
+        fs::write(&path, [0; 100]).unwrap();
+        let (sent, received) = push(&path, &dir, 99);
+        assert!(matches!(sent, Err(Error::Transfer(reason)) if reason.contains("limit")));
+        assert!(received.is_err());
+        assert!(!dir.join("big.part").exists());
+    }
+
+    #[test]
+    fn concurrent_transfers_of_a_name_get_their_own_partial_file() {
+        let dir = Path::new("/nonexistent");
+        let first = Claim::partial(dir, "same");
+        let second = Claim::partial(dir, "same");
+        assert_eq!(first.0, dir.join("same.part"));
+        assert_eq!(second.0, dir.join("same.1.part"));
+
+        // once the first is done, the next transfer resumes from its file
+        drop(first);
+        assert_eq!(Claim::partial(dir, "same").0, dir.join("same.part"));
+    }
+
+    #[test]
+    fn numbered_names_keep_their_extension() {
+        assert_eq!(numbered("report.pdf", 0), "report.pdf");
+        assert_eq!(numbered("report.pdf", 2), "report (2).pdf");
+        assert_eq!(numbered("archive.tar.gz", 1), "archive.tar (1).gz");
+        assert_eq!(numbered("README", 1), "README (1)");
+        assert_eq!(numbered(".profile", 1), ".profile (1)");
+    }
+}
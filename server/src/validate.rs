@@ -0,0 +1,516 @@
+//! Inbound message validation
+
+use crate::dedupe::Dedupe;
+use crate::error::{Error, Result};
+use crate::frame::{Control, Data, Frame, OpCode, MAX_PAYLOAD};
+use std::time::{Duration, Instant};
+
+/// A caller supplied check, e.g. a JSON schema. Returns the reason on failure.
+pub type Check = Box<dyn Fn(&Frame) -> Result<(), String> + Send + Sync>;
+
+/// What to do with a message that fails validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reject {
+    /// Silently discard the message.
+    Drop,
+    /// Tell the peer what was wrong with a text message and carry on.
+    Reply,
+    /// Close the connection, with the code the `ClosePolicy` gives the error.
+    Close,
+}
+
+/// What to do with frames using an opcode RFC 6455 reserves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reserved {
+    /// Fail the connection with a protocol error, as the RFC asks.
+    Fail,
+    /// Drop the frame and log it.
+    Drop,
+    /// Log the frame and hand it to the handler, for experimental protocols.
+    /// Handlers must not send the reserved opcode back.
+    Deliver,
+}
+
+/// The opcode of a frame if it is a reserved one.
+pub fn reserved(frame: &Frame) -> Option<u8> {
+    match frame.header().opcode {
+        opcode @ (OpCode::Data(Data::Reserved(_)) | OpCode::Control(Control::Reserved(_))) => {
+            Some(opcode.into())
+        }
+        _ => None,
+    }
+}
+
+/// Checks run on every data message before the handler sees it.
+pub struct Validator {
+    /// Largest data message, whether it comes in one frame or several.
+    pub max_size: Option<usize>,
+    /// Most frames one message may be split over.
+    pub max_fragments: Option<usize>,
+    /// Longest a message may stay unfinished.
+    pub max_reassembly: Option<Duration>,
+    /// Data opcodes accepted per request path. Paths not listed accept any.
+    pub allowed: Vec<(String, Vec<Data>)>,
+    pub reject: Reject,
+    /// What to do with frames using reserved opcodes.
+    pub reserved: Reserved,
+    /// Silently drop messages the client already sent.
+    pub dedupe: Option<Dedupe>,
+    checks: Vec<Check>,
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Validator {
+            max_size: None,
+            max_fragments: None,
+            max_reassembly: None,
+            allowed: Vec::new(),
+            reject: Reject::Close,
+            reserved: Reserved::Fail,
+            dedupe: None,
+            checks: Vec::new(),
+        }
+    }
+}
+
+/// The error for a fragmented message that didn't finish in time.
+pub fn reassembly_timeout() -> Error {
+    Error::Policy(String::from("message took too long to reassemble"))
+}
+
+/// Pass a whole message on, unless it is Text that isn't UTF-8.
+fn utf8(message: Frame) -> Result<Frame> {
+    match message.header().opcode {
+        OpCode::Data(Data::Text) if std::str::from_utf8(message.payload()).is_err() => {
+            Err(Error::Utf8)
+        }
+        _ => Ok(message),
+    }
+}
+
+/// The message a connection is receiving in fragments, if any.
+#[derive(Debug, Default)]
+pub struct Fragments {
+    /// When its first frame arrived.
+    started: Option<Instant>,
+    count: usize,
+    /// The first frame, with the payloads of the continuations so far
+    /// appended.
+    message: Option<Frame>,
+}
+
+impl Fragments {
+    /// When the unfinished message runs out of time under `max_reassembly`.
+    pub fn deadline(&self, validator: &Validator) -> Option<Instant> {
+        Some(self.started? + validator.max_reassembly?)
+    }
+}
+
+impl Validator {
+    pub fn add_check(&mut self, check: Check) {
+        self.checks.push(check);
+    }
+
+    /// The most payload one frame may carry: a whole message, plus the
+    /// `overhead` bytes framing inside the payload adds to it.
+    pub fn max_payload(&self, overhead: usize) -> usize {
+        self.max_size
+            .map_or(MAX_PAYLOAD, |max| max.saturating_add(overhead))
+    }
+
+    /// Validate a whole data message received on `path`, fragmented or not.
+    /// Control frames are not messages and always pass. Oversized messages
+    /// fail with `Error::Capacity`, anything else with `Error::Policy`.
+    pub fn check(&self, path: &str, frame: &Frame) -> Result<()> {
+        let data = match frame.header().opcode {
+            OpCode::Data(data) => data,
+            OpCode::Control(_) => return Ok(()),
+        };
+
+        if let Some(max_size) = self.max_size {
+            if frame.payload().len() > max_size {
+                return Err(Error::Capacity(format!(
+                    "message of {} bytes exceeds the limit of {max_size}",
+                    frame.payload().len()
+                )));
+            }
+        }
+
+        let allowed = self
+            .allowed
+            .iter()
+            .find(|(route, _)| route == path)
+            .map(|(_, opcodes)| opcodes);
+        if let Some(allowed) = allowed {
+            if !allowed.contains(&data) {
+                return Err(Error::Policy(format!(
+                    "{data:?} messages are not allowed on {path}"
+                )));
+            }
+        }
+
+        self.checks
+            .iter()
+            .try_for_each(|check| check(frame))
+            .map_err(Error::Policy)
+    }
+
+    /// Put fragmented messages back together, checking each against the
+    /// fragment count, size and reassembly time limits as it grows. Returns
+    /// whole messages, which unfragmented ones already are, and `None` while
+    /// the last frame of one is still to come. Control frames and frames with
+    /// reserved opcodes come back as they are.
+    ///
+    /// A message that takes too long or too many frames fails with
+    /// `Error::Policy`, one that grows too big with `Error::Capacity`. A
+    /// continuation with no message to continue, or a new message before the
+    /// last one finished, fails with `Error::Protocol`, and a whole Text
+    /// message that isn't UTF-8 with `Error::Utf8`.
+    pub fn reassemble(
+        &self,
+        fragments: &mut Fragments,
+        frame: Frame,
+        now: Instant,
+    ) -> Result<Option<Frame>> {
+        let header = frame.header();
+        match header.opcode {
+            OpCode::Control(_) | OpCode::Data(Data::Reserved(_)) => return Ok(Some(frame)),
+            OpCode::Data(Data::Continue) if fragments.message.is_none() => {
+                return Err(Error::Protocol(String::from(
+                    "continuation frame with no message to continue",
+                )))
+            }
+            OpCode::Data(Data::Continue) => {}
+            OpCode::Data(_) if fragments.message.is_some() => {
+                return Err(Error::Protocol(String::from(
+                    "new message before the fragmented one finished",
+                )))
+            }
+            OpCode::Data(_) if header.is_final => return utf8(frame).map(Some),
+            OpCode::Data(_) => {
+                *fragments = Fragments {
+                    started: Some(now),
+                    count: 1,
+                    message: Some(frame),
+                };
+                return self.check_fragments(fragments, now).map(|()| None);
+            }
+        }
+
+        let is_final = header.is_final;
+        let message = fragments.message.as_mut().expect("message in progress");
+        message.payload_mut().extend_from_slice(frame.payload());
+        fragments.count += 1;
+        self.check_fragments(fragments, now)?;
+        if !is_final {
+            return Ok(None);
+        }
+        let mut message = fragments.message.take().expect("message in progress");
+        message.header_mut().is_final = true;
+        *fragments = Fragments::default();
+        utf8(message).map(Some)
+    }
+
+    /// Hold the message in `fragments` to the limits on fragmented messages.
+    fn check_fragments(&self, fragments: &Fragments, now: Instant) -> Result<()> {
+        if let Some(max_fragments) = self.max_fragments {
+            if fragments.count > max_fragments {
+                return Err(Error::Policy(format!(
+                    "message split over more than {max_fragments} fragments"
+                )));
+            }
+        }
+        // without a limit of its own, a message may still not outgrow a frame
+        let max_size = self.max_size.unwrap_or(MAX_PAYLOAD);
+        let size = fragments
+            .message
+            .as_ref()
+            .map_or(0, |message| message.payload().len());
+        if size > max_size {
+            return Err(Error::Capacity(format!(
+                "fragmented message of {size} bytes so far exceeds the limit of {max_size}"
+            )));
+        }
+        if fragments
+            .deadline(self)
+            .is_some_and(|deadline| deadline < now)
+        {
+            return Err(reassembly_timeout());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(opcode: Data, is_final: bool, payload: &[u8]) -> Frame {
+        let mut frame = Frame::message(payload.to_vec(), OpCode::Data(opcode));
+        frame.header_mut().is_final = is_final;
+        frame
+    }
+
+    fn text_only() -> Validator {
+        Validator {
+            allowed: vec![(String::from("/chat"), vec![Data::Text])],
+            ..Validator::default()
+        }
+    }
+
+    /// Feed `frames` in, returning the whole messages that came out.
+    fn reassembled(validator: &Validator, frames: Vec<Frame>) -> Result<Vec<Frame>> {
+        let mut fragments = Fragments::default();
+        let now = Instant::now();
+        let mut messages = Vec::new();
+        for frame in frames {
+            messages.extend(validator.reassemble(&mut fragments, frame, now)?);
+        }
+        Ok(messages)
+    }
+
+    #[test]
+    fn opcodes_are_only_limited_on_listed_paths() {
+        let validator = text_only();
+        let binary = fragment(Data::Binary, true, b"");
+        assert!(validator
+            .check("/chat", &fragment(Data::Text, true, b""))
+            .is_ok());
+        assert!(matches!(
+            validator.check("/chat", &binary),
+            Err(Error::Policy(_))
+        ));
+        assert!(validator.check("/other", &binary).is_ok());
+    }
+
+    #[test]
+    fn oversized_messages_fail_with_capacity() {
+        let validator = Validator {
+            max_size: Some(4),
+            ..Validator::default()
+        };
+        assert!(validator
+            .check("/", &fragment(Data::Text, true, b"four"))
+            .is_ok());
+        assert!(matches!(
+            validator.check("/", &fragment(Data::Text, true, b"five!")),
+            Err(Error::Capacity(_))
+        ));
+    }
+
+    #[test]
+    fn checks_fail_with_their_reason() {
+        let mut validator = Validator::default();
+        validator.add_check(Box::new(|frame: &Frame| {
+            if frame.payload().starts_with(b"{") {
+                Ok(())
+            } else {
+                Err(String::from("not JSON"))
+            }
+        }));
+        assert!(validator
+            .check("/", &fragment(Data::Text, true, b"{}"))
+            .is_ok());
+        match validator.check("/", &fragment(Data::Text, true, b"[]")) {
+            Err(Error::Policy(reason)) => assert_eq!(reason, "not JSON"),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn fragments_come_out_as_one_message_with_the_first_opcode() {
+        let messages = reassembled(
+            &Validator::default(),
+            vec![
+                fragment(Data::Binary, false, b"He"),
+                fragment(Data::Continue, false, b""),
+                fragment(Data::Continue, true, b"llo"),
+                fragment(Data::Text, true, b"whole"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            messages,
+            [
+                fragment(Data::Binary, true, b"Hello"),
+                fragment(Data::Text, true, b"whole")
+            ]
+        );
+    }
+
+    #[test]
+    fn reassembled_messages_are_checked_as_a_whole() {
+        let mut validator = text_only();
+        validator.add_check(Box::new(|frame: &Frame| match frame.payload() {
+            b"{}" => Ok(()),
+            _ => Err(String::from("not JSON")),
+        }));
+        let messages = reassembled(
+            &validator,
+            vec![
+                fragment(Data::Text, false, b"{"),
+                fragment(Data::Continue, true, b"}"),
+                fragment(Data::Binary, false, b"{"),
+                fragment(Data::Continue, true, b"}"),
+            ],
+        )
+        .unwrap();
+        // the JSON passes although neither half does, and the binary message
+        // is refused whole, its continuation along with its first frame
+        assert!(validator.check("/chat", &messages[0]).is_ok());
+        assert!(matches!(
+            validator.check("/chat", &messages[1]),
+            Err(Error::Policy(_))
+        ));
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[test]
+    fn fragments_are_counted_and_sized_per_message() {
+        let validator = Validator {
+            max_fragments: Some(2),
+            max_size: Some(6),
+            ..Validator::default()
+        };
+        assert!(reassembled(
+            &validator,
+            vec![
+                fragment(Data::Text, false, b"abc"),
+                fragment(Data::Continue, true, b"def"),
+                fragment(Data::Text, false, b"abc"),
+                fragment(Data::Continue, true, b"d"),
+            ]
+        )
+        .is_ok());
+        assert!(matches!(
+            reassembled(
+                &validator,
+                vec![
+                    fragment(Data::Text, false, b"a"),
+                    fragment(Data::Continue, false, b"b"),
+                    fragment(Data::Continue, true, b"c"),
+                ]
+            ),
+            Err(Error::Policy(_))
+        ));
+        assert!(matches!(
+            reassembled(
+                &validator,
+                vec![
+                    fragment(Data::Binary, false, b"abcd"),
+                    fragment(Data::Continue, true, b"efg"),
+                ]
+            ),
+            Err(Error::Capacity(_))
+        ));
+    }
+
+    #[test]
+    fn unfinished_messages_run_out_of_time() {
+        let validator = Validator {
+            max_reassembly: Some(Duration::from_secs(5)),
+            ..Validator::default()
+        };
+        let start = Instant::now();
+        let mut fragments = Fragments::default();
+        assert_eq!(fragments.deadline(&validator), None);
+        let frame = fragment(Data::Text, false, b"a");
+        assert!(validator
+            .reassemble(&mut fragments, frame, start)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            fragments.deadline(&validator),
+            Some(start + Duration::from_secs(5))
+        );
+        let frame = fragment(Data::Continue, true, b"b");
+        let late = start + Duration::from_secs(6);
+        assert!(matches!(
+            validator.reassemble(&mut fragments, frame, late),
+            Err(Error::Policy(_))
+        ));
+    }
+
+    #[test]
+    fn continuation_without_a_message_is_a_protocol_error() {
+        assert!(matches!(
+            reassembled(
+                &Validator::default(),
+                vec![fragment(Data::Continue, true, b"orphan")]
+            ),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn new_message_mid_message_is_a_protocol_error() {
+        for second in [
+            fragment(Data::Binary, false, b"b"),
+            fragment(Data::Text, true, b"t"),
+        ] {
+            assert!(matches!(
+                reassembled(
+                    &Validator::default(),
+                    vec![fragment(Data::Text, false, b"a"), second]
+                ),
+                Err(Error::Protocol(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn control_frames_may_interleave_a_fragmented_message() {
+        let ping = Frame::message(Vec::new(), OpCode::Control(Control::Ping));
+        let messages = reassembled(
+            &Validator::default(),
+            vec![
+                fragment(Data::Text, false, b"a"),
+                ping.clone(),
+                fragment(Data::Continue, true, b"b"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(messages, [ping, fragment(Data::Text, true, b"ab")]);
+    }
+
+    #[test]
+    fn reserved_opcodes_are_spotted() {
+        let frame = Frame::message(Vec::new(), OpCode::Data(Data::Reserved(3)));
+        assert_eq!(reserved(&frame), Some(3));
+        assert_eq!(reserved(&fragment(Data::Text, true, b"")), None);
+    }
+
+    #[test]
+    fn text_must_be_utf8_once_whole() {
+        let validator = Validator::default();
+        for payload in [&b"abc\xffdef"[..], b"\xc0\xaf", b"\xed\xa0\x80"] {
+            assert!(matches!(
+                reassembled(&validator, vec![fragment(Data::Text, true, payload)]),
+                Err(Error::Utf8)
+            ));
+        }
+        // a character may straddle fragments, a bad byte may hide in a later one
+        let split = reassembled(
+            &validator,
+            vec![
+                fragment(Data::Text, false, b"\xce"),
+                fragment(Data::Continue, true, b"\xba"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(split[0].payload(), "κ".as_bytes());
+        assert!(matches!(
+            reassembled(
+                &validator,
+                vec![
+                    fragment(Data::Text, false, b"valid"),
+                    fragment(Data::Continue, true, b"\xff"),
+                ]
+            ),
+            Err(Error::Utf8)
+        ));
+        // binary payloads are anybody's business
+        assert!(reassembled(&validator, vec![fragment(Data::Binary, true, b"\xff")]).is_ok());
+    }
+}
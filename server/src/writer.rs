@@ -0,0 +1,144 @@
+//! Frame writer that tolerates partial writes
+//!
+//! A non-blocking socket may take only part of a frame, or none of it. The
+//! writer keeps whatever is left as pending bytes, so the caller can wait for
+//! the socket to become writable and pick up where it stopped, instead of
+//! losing track of the frame boundary the way an interrupted `write_all`
+//! would.
+
+use crate::frame::Frame;
+use std::io::{self, ErrorKind, Write};
+
+/// Where a flush stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    /// Every queued byte was written.
+    Flushed,
+    /// The output would block; the rest is still pending.
+    Pending,
+}
+
+#[derive(Debug, Default)]
+pub struct FrameWriter {
+    buffer: Vec<u8>,
+    /// How much of `buffer` has been written already.
+    written: usize,
+}
+
+impl FrameWriter {
+    /// Encode a frame behind whatever is still pending, returning its
+    /// encoded size.
+    pub fn queue(&mut self, frame: Frame) -> usize {
+        if self.written > 0 && self.written == self.buffer.len() {
+            self.buffer.clear();
+            self.written = 0;
+        }
+        let before = self.buffer.len();
+        frame
+            .format(&mut self.buffer)
+            .expect("can't write to vector");
+        self.buffer.len() - before
+    }
+
+    /// Bytes queued but not written yet.
+    pub fn pending(&self) -> usize {
+        self.buffer.len() - self.written
+    }
+
+    /// Write as much as `output` takes. On `Progress::Pending` wait until the
+    /// output is writable again and call this again.
+    pub fn flush_to(&mut self, output: &mut impl Write) -> io::Result<Progress> {
+        while self.written < self.buffer.len() {
+            match output.write(&self.buffer[self.written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(size) => self.written += size,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                    return Ok(Progress::Pending)
+                }
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        self.buffer.clear();
+        self.written = 0;
+        output.flush()?;
+        Ok(Progress::Flushed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{Data, OpCode};
+
+    /// Takes at most `room` bytes, then would block until given more.
+    struct Cramped {
+        taken: Vec<u8>,
+        room: usize,
+    }
+
+    impl Write for Cramped {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let size = buf.len().min(self.room);
+            self.taken.extend_from_slice(&buf[..size]);
+            self.room -= size;
+            Ok(size)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn text(payload: &str) -> Frame {
+        Frame::message(payload.as_bytes().to_vec(), OpCode::Data(Data::Text))
+    }
+
+    fn encoded(frame: Frame) -> Vec<u8> {
+        let mut output = Vec::new();
+        frame.format(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn flushes_a_frame_whole_when_the_output_takes_it() {
+        let mut writer = FrameWriter::default();
+        let size = writer.queue(text("hello"));
+        let mut output = Vec::new();
+        assert_eq!(writer.flush_to(&mut output).unwrap(), Progress::Flushed);
+        assert_eq!(output, encoded(text("hello")));
+        assert_eq!(size, output.len());
+        assert_eq!(writer.pending(), 0);
+    }
+
+    #[test]
+    fn resumes_a_partial_write_where_it_stopped() {
+        let mut writer = FrameWriter::default();
+        let size = writer.queue(text("hello"));
+        let mut output = Cramped {
+            taken: Vec::new(),
+            room: 3,
+        };
+        assert_eq!(writer.flush_to(&mut output).unwrap(), Progress::Pending);
+        assert_eq!(writer.pending(), size - 3);
+
+        // a frame queued meanwhile goes out behind the rest of the first
+        writer.queue(text("world"));
+        output.room = usize::MAX;
+        assert_eq!(writer.flush_to(&mut output).unwrap(), Progress::Flushed);
+        let mut expected = encoded(text("hello"));
+        expected.extend(encoded(text("world")));
+        assert_eq!(output.taken, expected);
+    }
+
+    #[test]
+    fn output_that_takes_nothing_is_an_error() {
+        let mut writer = FrameWriter::default();
+        writer.queue(text("hello"));
+        let error = writer.flush_to(&mut &mut [0u8; 0][..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::WriteZero);
+    }
+}
@@ -0,0 +1,82 @@
+//! Client side checks of the server's opening handshake response, against a
+//! scripted server on a local listener.
+
+use server::client::WebSocket;
+use server::error::Error;
+use server::frame::{Data, Frame, OpCode};
+use server::handshake::accept_key;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+/// Accept one connection, answer its upgrade with `accept` derived from the
+/// client's key, push a Text frame right behind the response and report
+/// whether the client read anything afterwards without hanging up.
+fn scripted_server(accept: fn(&str) -> String) -> (String, thread::JoinHandle<bool>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut key = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Sec-WebSocket-Key:") {
+                key = value.trim().to_string();
+            }
+        }
+
+        let mut response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept(&key)
+        )
+        .into_bytes();
+        Frame::message(b"hello".to_vec(), OpCode::Data(Data::Text))
+            .format(&mut response)
+            .unwrap();
+        let mut stream = stream;
+        stream.write_all(&response).unwrap();
+
+        // a client that gave up closes the connection without sending more
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        matches!(stream.read(&mut [0; 64]), Ok(size) if size > 0)
+    });
+    (addr, server)
+}
+
+#[test]
+fn tampered_accept_aborts_before_any_frame() {
+    let (addr, server) = scripted_server(|key| {
+        let mut accept = accept_key(key.as_bytes()).into_bytes();
+        accept[0] ^= 1;
+        String::from_utf8(accept).unwrap()
+    });
+
+    match WebSocket::connect(&addr, "/") {
+        Err(Error::Handshake(reason)) => assert!(reason.contains("Sec-WebSocket-Accept")),
+        Err(error) => panic!("unexpected error: {error}"),
+        Ok(_) => panic!("handshake with a tampered accept value succeeded"),
+    }
+    assert!(!server.join().unwrap(), "client kept talking to the server");
+}
+
+#[test]
+fn matching_accept_hands_over_the_first_frame() {
+    let (addr, server) = scripted_server(|key| accept_key(key.as_bytes()));
+
+    let mut socket = WebSocket::connect(&addr, "/").unwrap();
+    let frame = socket.read().unwrap().unwrap();
+    assert_eq!(frame.payload(), b"hello");
+    socket
+        .send(Frame::message(b"bye".to_vec(), OpCode::Data(Data::Text)))
+        .unwrap();
+    assert!(server.join().unwrap());
+}
@@ -0,0 +1,84 @@
+//! The client's keepalive, against the test server and a server that went
+//! quiet.
+
+use server::client::{Keepalive, WebSocket};
+use server::error::Error;
+use server::frame::{Control, Data, Frame, OpCode};
+use server::handshake::accept_key;
+use server::testing::ws_test_server;
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const KEEPALIVE: Keepalive = Keepalive {
+    interval: Duration::from_millis(50),
+    max_missed: 2,
+};
+
+#[test]
+fn answered_pings_keep_an_idle_connection_fresh() {
+    let server = ws_test_server();
+    let mut socket = WebSocket::connect(&server.addr().to_string(), "/").unwrap();
+    socket.keepalive(KEEPALIVE);
+    let id = server.connections()[0];
+    let pusher = thread::spawn(move || {
+        thread::sleep(KEEPALIVE.interval * 8);
+        server
+            .send(
+                id,
+                Frame::message(b"news".to_vec(), OpCode::Data(Data::Text)),
+            )
+            .unwrap();
+        server
+    });
+
+    // the keepalive's Pongs arrive meanwhile and are swallowed
+    assert_eq!(socket.read().unwrap().unwrap().payload(), b"news");
+    assert!(!socket.is_stale());
+    pusher.join().unwrap();
+}
+
+#[test]
+fn silent_server_makes_the_connection_stale() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut key = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Sec-WebSocket-Key:") {
+                key = value.trim().to_string();
+            }
+        }
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key.as_bytes())
+        );
+        std::io::Write::write_all(&mut &stream, response.as_bytes()).unwrap();
+
+        // take the Pings in and never answer them
+        let mut pings = 0;
+        while let Ok(Some(frame)) = Frame::parse(&mut reader) {
+            if frame.header().opcode == OpCode::Control(Control::Ping) {
+                pings += 1;
+            }
+        }
+        pings
+    });
+
+    let mut socket = WebSocket::connect(&addr, "/").unwrap();
+    socket.keepalive(KEEPALIVE);
+    let started = Instant::now();
+    assert!(matches!(socket.read(), Err(Error::Stale)));
+    assert!(started.elapsed() >= KEEPALIVE.interval * (KEEPALIVE.max_missed + 1));
+    assert!(socket.is_broken());
+    assert_eq!(server.join().unwrap(), KEEPALIVE.max_missed);
+}
@@ -0,0 +1,302 @@
+//! The echo server binary, driven over raw sockets.
+
+use server::conformance::{self, Outcome};
+use server::frame::{Control, Data, Frame, OpCode};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+struct ServerProcess {
+    child: Child,
+    addr: String,
+}
+
+impl ServerProcess {
+    fn spawn(args: &[&str]) -> ServerProcess {
+        // grab a free port from the kernel, then hand it to the server
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .to_string();
+        let child = Command::new(env!("CARGO_BIN_EXE_server"))
+            .arg(&addr)
+            .args(args)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("can't start server");
+        // from here on, dropping the handle reaps the process
+        let server = ServerProcess { child, addr };
+
+        for _ in 0..50 {
+            if TcpStream::connect(&server.addr).is_ok() {
+                return server;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("server did not start listening on {}", server.addr);
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+fn request(addr: &str) -> String {
+    let key = base64::encode(rand::random::<[u8; 16]>());
+    format!(
+        "GET / HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    )
+}
+
+/// Send the upgrade request and read the response head, returning its status
+/// line.
+fn handshake(stream: &TcpStream, reader: &mut BufReader<TcpStream>, addr: &str) -> String {
+    (&mut &*stream).write_all(request(addr).as_bytes()).unwrap();
+    let mut status = String::new();
+    reader.read_line(&mut status).unwrap();
+    let mut line = status.clone();
+    while line != "\r\n" {
+        line.clear();
+        if reader.read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+    }
+    status
+}
+
+fn connect(addr: &str) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let status = handshake(&stream, &mut reader, addr);
+    assert!(
+        status.starts_with("HTTP/1.1 101"),
+        "unexpected status: {status}"
+    );
+    (stream, reader)
+}
+
+fn encode(payload: &[u8], opcode: OpCode) -> Vec<u8> {
+    let mut frame = Frame::message(payload.to_vec(), opcode);
+    frame.header_mut().set_random_mask();
+    let mut buffer = Vec::new();
+    frame.format(&mut buffer).unwrap();
+    buffer
+}
+
+/// The next frame that isn't one of the server's keepalive Pings.
+fn next_frame(reader: &mut BufReader<TcpStream>) -> Frame {
+    loop {
+        let frame = Frame::parse(reader).unwrap().expect("connection closed");
+        if frame.header().opcode != OpCode::Control(Control::Ping) {
+            return frame;
+        }
+    }
+}
+
+#[test]
+fn frame_split_across_a_deadline_arrives_whole() {
+    let server = ServerProcess::spawn(&["--ping-interval", "1"]);
+    let (mut stream, mut reader) = connect(&server.addr);
+
+    // cut after the first byte, then inside the mask, so the header itself is
+    // what arrives in two pieces
+    for (split, payload) in [(1, "split inside the head"), (3, "split inside the mask")] {
+        let frame = encode(payload.as_bytes(), OpCode::Data(Data::Text));
+        let (head, rest) = frame.split_at(split);
+        stream.write_all(head).unwrap();
+        // the server's next Ping falls due while the frame is half sent
+        thread::sleep(Duration::from_millis(1500));
+        stream.write_all(rest).unwrap();
+        assert_eq!(next_frame(&mut reader).payload(), payload.as_bytes());
+    }
+    stream
+        .write_all(&encode(b"and the next one", OpCode::Data(Data::Text)))
+        .unwrap();
+    assert_eq!(next_frame(&mut reader).payload(), b"and the next one");
+}
+
+#[test]
+fn silent_client_does_not_hold_up_the_next_handshake() {
+    let server = ServerProcess::spawn(&[]);
+    let _silent = TcpStream::connect(&server.addr).unwrap();
+    connect(&server.addr);
+}
+
+#[test]
+fn silent_refused_client_does_not_hold_up_the_next_refusal() {
+    // the probe that saw the server listening took the only handshake
+    let server = ServerProcess::spawn(&["--handshake-limit", "1"]);
+    let _silent = TcpStream::connect(&server.addr).unwrap();
+
+    let stream = TcpStream::connect(&server.addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let status = handshake(&stream, &mut reader, &server.addr);
+    assert!(
+        status.starts_with("HTTP/1.1 429"),
+        "unexpected status: {status}"
+    );
+}
+
+#[test]
+fn oversized_frame_is_refused_with_1009() {
+    let server = ServerProcess::spawn(&["--max-message-size", "1024"]);
+    let (mut stream, mut reader) = connect(&server.addr);
+
+    // claims 2^62 bytes, the server must not try to buffer them
+    let mut head = vec![0x82, 0xff, 0x40, 0, 0, 0, 0, 0, 0, 0];
+    head.extend_from_slice(&[1, 2, 3, 4]);
+    stream.write_all(&head).unwrap();
+
+    let close = next_frame(&mut reader);
+    assert_eq!(close.header().opcode, OpCode::Control(Control::Close));
+    assert_eq!(close.payload()[..2], 1009u16.to_be_bytes());
+}
+
+#[test]
+fn reserved_bits_nobody_claimed_are_answered_with_1002() {
+    let server = ServerProcess::spawn(&[]);
+    let (mut stream, mut reader) = connect(&server.addr);
+
+    let mut frame = Frame::message(b"reserved bits".to_vec(), OpCode::Data(Data::Text));
+    frame.header_mut().rsv2 = true;
+    frame.header_mut().set_random_mask();
+    let mut buffer = Vec::new();
+    frame.format(&mut buffer).unwrap();
+    stream.write_all(&buffer).unwrap();
+
+    let close = next_frame(&mut reader);
+    assert_eq!(close.header().opcode, OpCode::Control(Control::Close));
+    assert_eq!(close.payload()[..2], 1002u16.to_be_bytes());
+}
+
+#[test]
+fn fragmented_message_is_validated_and_echoed_whole() {
+    let server = ServerProcess::spawn(&["--allow", "/=text", "--reject", "drop"]);
+    let (mut stream, mut reader) = connect(&server.addr);
+
+    let mut buffer = Vec::new();
+    for (opcode, is_final, payload) in [
+        // refused as a whole, its continuation included
+        (Data::Binary, false, &b"dropped "[..]),
+        (Data::Continue, true, b"binary"),
+        (Data::Text, false, b"He"),
+        (Data::Continue, false, b""),
+        (Data::Continue, true, b"llo"),
+    ] {
+        let mut frame = Frame::message(payload.to_vec(), OpCode::Data(opcode));
+        frame.header_mut().is_final = is_final;
+        frame.header_mut().set_random_mask();
+        frame.format(&mut buffer).unwrap();
+    }
+    stream.write_all(&buffer).unwrap();
+
+    let echo = next_frame(&mut reader);
+    assert!(echo.header().is_final);
+    assert_eq!(echo.payload(), b"Hello");
+}
+
+#[test]
+fn text_that_is_not_utf8_is_answered_with_1007() {
+    let server = ServerProcess::spawn(&[]);
+    for payload in [&b"abc\xffdef"[..], b"\xc0\xaf", b"\xed\xa0\x80"] {
+        let (mut stream, mut reader) = connect(&server.addr);
+        stream
+            .write_all(&encode(payload, OpCode::Data(Data::Text)))
+            .unwrap();
+
+        let close = next_frame(&mut reader);
+        assert_eq!(close.header().opcode, OpCode::Control(Control::Close));
+        assert_eq!(close.payload()[..2], 1007u16.to_be_bytes(), "{payload:?}");
+    }
+
+    let server = ServerProcess::spawn(&["--close-code", "utf8=4007"]);
+    let (mut stream, mut reader) = connect(&server.addr);
+    stream
+        .write_all(&encode(b"\xff", OpCode::Data(Data::Text)))
+        .unwrap();
+    assert_eq!(
+        next_frame(&mut reader).payload()[..2],
+        4007u16.to_be_bytes()
+    );
+}
+
+#[test]
+fn virtual_time_skips_the_wait_for_a_ping() {
+    let server = ServerProcess::spawn(&["--virtual-time", "--ping-interval", "3600"]);
+    let (_stream, mut reader) = connect(&server.addr);
+    let frame = Frame::parse(&mut reader)
+        .unwrap()
+        .expect("connection closed");
+    assert_eq!(frame.header().opcode, OpCode::Control(Control::Ping));
+}
+
+#[test]
+fn close_codes_nobody_may_send_are_answered_with_1002() {
+    let server = ServerProcess::spawn(&[]);
+    let with_code = |code: u16, reason: &[u8]| {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason);
+        payload
+    };
+    for (payload, reply) in [
+        (with_code(1000, b""), 1000u16),
+        (with_code(4000, b"bye"), 4000),
+        (with_code(999, b""), 1002),
+        (with_code(1005, b""), 1002),
+        (with_code(1015, b""), 1002),
+        // too short to hold a code
+        (vec![3], 1002),
+        (with_code(1000, b"\xce\xba\xff"), 1007),
+    ] {
+        let (mut stream, mut reader) = connect(&server.addr);
+        stream
+            .write_all(&encode(&payload, OpCode::Control(Control::Close)))
+            .unwrap();
+
+        let answer = next_frame(&mut reader);
+        assert_eq!(answer.header().opcode, OpCode::Control(Control::Close));
+        assert_eq!(
+            answer.payload()[..2],
+            reply.to_be_bytes(),
+            "reply to {payload:?}"
+        );
+    }
+}
+
+#[test]
+fn delivered_reserved_opcodes_never_go_back_on_the_wire() {
+    let server = ServerProcess::spawn(&["--reserved", "deliver"]);
+    let (mut stream, mut reader) = connect(&server.addr);
+
+    for opcode in [
+        OpCode::Data(Data::Reserved(3)),
+        OpCode::Control(Control::Reserved(11)),
+    ] {
+        stream.write_all(&encode(b"experimental", opcode)).unwrap();
+        let echo = next_frame(&mut reader);
+        assert_eq!(echo.header().opcode, OpCode::Data(Data::Binary));
+        assert_eq!(echo.payload(), b"experimental");
+    }
+}
+
+#[test]
+fn every_conformance_case_passes() {
+    let server = ServerProcess::spawn(&[]);
+    let outcomes = conformance::run(&format!("ws://{}/", server.addr)).unwrap();
+    assert!(
+        outcomes.iter().all(Outcome::passed),
+        "{}",
+        conformance::matrix(&outcomes)
+    );
+}
@@ -0,0 +1,89 @@
+//! The connection pool, against the in-process test server.
+
+use server::pool::{Pool, PoolConfig};
+use server::registry::ConnectionId;
+use server::sim::Sources;
+use server::testing::{ws_test_server, TestServer};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Wait up to five seconds for the server to hold exactly `count`
+/// connections, returning their ids.
+fn open_connections(server: &TestServer, count: usize) -> Vec<ConnectionId> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let connections = server.connections();
+        if connections.len() == count || Instant::now() > deadline {
+            return connections;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn released_connections_are_reused() {
+    let server = ws_test_server();
+    let pool = Pool::new(&server.addr().to_string(), "/", PoolConfig::default());
+
+    drop(pool.get().unwrap());
+    assert_eq!(pool.idle(), 1);
+    let first = open_connections(&server, 1);
+
+    drop(pool.get().unwrap());
+    assert_eq!(pool.idle(), 1);
+    assert_eq!(open_connections(&server, 1), first);
+}
+
+#[test]
+fn connections_past_max_idle_are_closed() {
+    let server = ws_test_server();
+    let config = PoolConfig {
+        max_idle: 1,
+        ..PoolConfig::default()
+    };
+    let pool = Pool::new(&server.addr().to_string(), "/", config);
+
+    let (first, second) = (pool.get().unwrap(), pool.get().unwrap());
+    assert_eq!(open_connections(&server, 2).len(), 2);
+    drop(first);
+    drop(second);
+    assert_eq!(pool.idle(), 1);
+    assert_eq!(open_connections(&server, 1).len(), 1);
+}
+
+#[test]
+fn connections_past_max_lifetime_are_replaced() {
+    let server = ws_test_server();
+    let (sources, clock) = Sources::simulated(7);
+    let config = PoolConfig {
+        max_lifetime: Duration::from_secs(60),
+        ..PoolConfig::default()
+    };
+    let pool = Pool::with_sources(&server.addr().to_string(), "/", config, sources);
+
+    drop(pool.get().unwrap());
+    let old = open_connections(&server, 1);
+    clock.advance(Duration::from_secs(60));
+
+    let _socket = pool.get().unwrap();
+    assert_eq!(pool.idle(), 0);
+    let new = open_connections(&server, 1);
+    assert_eq!(new.len(), 1);
+    assert_ne!(new, old);
+}
+
+#[test]
+fn connections_failing_their_health_ping_are_dropped() {
+    let server = ws_test_server();
+    let pool = Pool::new(&server.addr().to_string(), "/", PoolConfig::default());
+
+    drop(pool.get().unwrap());
+    let old = open_connections(&server, 1);
+    server.close(old[0], 1001, "going away").unwrap();
+
+    let _socket = pool.get().unwrap();
+    assert_eq!(pool.idle(), 0);
+    let new = open_connections(&server, 1);
+    assert_eq!(new.len(), 1);
+    assert_ne!(new, old);
+}
@@ -0,0 +1,170 @@
+//! Soak harness: runs many connect/message/close cycles against a real server
+//! process and checks that its resident memory, open file descriptors and thread
+//! count do not keep growing.
+//!
+//! Run with `cargo test -p server --test soak -- --ignored`. The number of cycles
+//! can be changed through `SOAK_CYCLES`.
+#![cfg(target_os = "linux")]
+
+use server::frame::{Data, Frame, OpCode};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_CYCLES: usize = 20_000;
+const SAMPLES: usize = 10;
+
+struct ServerProcess {
+    child: Child,
+    addr: String,
+}
+
+impl ServerProcess {
+    fn spawn() -> ServerProcess {
+        // grab a free port from the kernel, then hand it to the server
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .to_string();
+        let child = Command::new(env!("CARGO_BIN_EXE_server"))
+            .arg(&addr)
+            .spawn()
+            .expect("can't start server");
+        // from here on, dropping the handle reaps the process
+        let server = ServerProcess { child, addr };
+
+        for _ in 0..50 {
+            if TcpStream::connect(&server.addr).is_ok() {
+                return server;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("server did not start listening on {}", server.addr);
+    }
+
+    fn sample(&self) -> Sample {
+        let pid = self.child.id();
+        let status = fs::read_to_string(format!("/proc/{pid}/status")).unwrap();
+        let field = |name: &str| -> u64 {
+            status
+                .lines()
+                .find(|line| line.starts_with(name))
+                .and_then(|line| line.split_whitespace().nth(1))
+                .and_then(|value| value.parse().ok())
+                .unwrap_or_else(|| panic!("no {name} in /proc/{pid}/status"))
+        };
+        Sample {
+            rss_kb: field("VmRSS:"),
+            threads: field("Threads:"),
+            fds: fs::read_dir(format!("/proc/{pid}/fd")).unwrap().count() as u64,
+        }
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    rss_kb: u64,
+    threads: u64,
+    fds: u64,
+}
+
+fn send(stream: &mut TcpStream, payload: &[u8], opcode: OpCode) {
+    let mut frame = Frame::message(payload.to_vec(), opcode);
+    frame.header_mut().set_random_mask();
+    let mut buffer = Vec::new();
+    frame.format(&mut buffer).unwrap();
+    stream.write_all(&buffer).unwrap();
+}
+
+fn cycle(addr: &str, index: usize) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    let key = base64::encode(rand::random::<[u8; 16]>());
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(
+        line.starts_with("HTTP/1.1 101"),
+        "unexpected status: {line}"
+    );
+    while line != "\r\n" {
+        line.clear();
+        if reader.read_line(&mut line).unwrap() == 0 {
+            panic!("handshake response ended early");
+        }
+    }
+
+    let message = format!("soak message {index}");
+    send(&mut stream, message.as_bytes(), OpCode::Data(Data::Text));
+    let echo = Frame::parse(&mut reader).unwrap().expect("no echo");
+    assert_eq!(echo.payload(), message.as_bytes());
+
+    send(
+        &mut stream,
+        &1000_u16.to_be_bytes(),
+        OpCode::Control(server::frame::Control::Close),
+    );
+}
+
+/// Fails when a resource grew at every single sample and ended up more than
+/// `slack` above where it started.
+fn assert_no_monotonic_growth(name: &str, values: &[u64], slack: u64) {
+    let monotonic = values.windows(2).all(|pair| pair[1] >= pair[0]);
+    let growth = values.last().unwrap().saturating_sub(values[0]);
+    assert!(
+        !(monotonic && growth > slack),
+        "{name} grew monotonically by {growth}: {values:?}"
+    );
+}
+
+#[test]
+#[ignore]
+fn soak_connect_message_close() {
+    let cycles = std::env::var("SOAK_CYCLES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_CYCLES);
+    let server = ServerProcess::spawn();
+
+    // let allocator pools and the like settle before taking the baseline
+    for index in 0..cycles / 20 {
+        cycle(&server.addr, index);
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    let mut samples = vec![server.sample()];
+    let per_sample = (cycles / SAMPLES).max(1);
+    for index in 0..cycles {
+        cycle(&server.addr, index);
+        if (index + 1) % per_sample == 0 {
+            // give the connection threads a moment to wind down
+            thread::sleep(Duration::from_millis(200));
+            samples.push(server.sample());
+        }
+    }
+
+    let rss: Vec<u64> = samples.iter().map(|sample| sample.rss_kb).collect();
+    let fds: Vec<u64> = samples.iter().map(|sample| sample.fds).collect();
+    let threads: Vec<u64> = samples.iter().map(|sample| sample.threads).collect();
+    assert_no_monotonic_growth("RSS (kB)", &rss, rss[0] / 10);
+    assert_no_monotonic_growth("open file descriptors", &fds, 4);
+    assert_no_monotonic_growth("thread count", &threads, 4);
+}
@@ -0,0 +1,98 @@
+//! The in-process test server, driven by the blocking client.
+
+use server::client::WebSocket;
+use server::config::Config;
+use server::frame::{Control, Data, Frame, OpCode};
+use server::testing::{ws_test_server, TestServer};
+use std::time::Duration;
+
+fn text(payload: &str) -> Frame {
+    Frame::message(payload.as_bytes().to_vec(), OpCode::Data(Data::Text))
+}
+
+#[test]
+fn echoes_records_injects_and_closes() {
+    let server = ws_test_server();
+    let mut socket = WebSocket::connect(&server.addr().to_string(), "/").unwrap();
+    let id = server.connections()[0];
+
+    socket.send(text("hello")).unwrap();
+    assert_eq!(socket.read().unwrap().unwrap().payload(), b"hello");
+    let received = server.wait_for(1, Duration::from_secs(5)).unwrap();
+    assert_eq!(received[0].0, id);
+    assert_eq!(received[0].1.payload(), b"hello");
+
+    server.send(id, text("pushed")).unwrap();
+    assert_eq!(socket.read().unwrap().unwrap().payload(), b"pushed");
+
+    server.close(id, 4001, "kicked").unwrap();
+    let close = socket.read().unwrap().unwrap();
+    assert_eq!(close.header().opcode, OpCode::Control(Control::Close));
+    assert_eq!(&close.payload()[..2], &4001u16.to_be_bytes());
+}
+
+fn start(config: Config) -> (TestServer, WebSocket) {
+    let server = TestServer::start(config).unwrap();
+    let socket = WebSocket::connect(&server.addr().to_string(), "/").unwrap();
+    (server, socket)
+}
+
+fn close_code(socket: &mut WebSocket) -> u16 {
+    let close = socket.read().unwrap().unwrap();
+    assert_eq!(close.header().opcode, OpCode::Control(Control::Close));
+    u16::from_be_bytes([close.payload()[0], close.payload()[1]])
+}
+
+#[test]
+fn fragmented_messages_are_recorded_whole() {
+    let (server, mut socket) = start(Config::default());
+    for (opcode, is_final, payload) in [(Data::Text, false, "Hel"), (Data::Continue, true, "lo")] {
+        let mut frame = Frame::message(payload.as_bytes().to_vec(), OpCode::Data(opcode));
+        frame.header_mut().is_final = is_final;
+        socket.send(frame).unwrap();
+    }
+    assert_eq!(socket.read().unwrap().unwrap().payload(), b"Hello");
+    let received = server.wait_for(1, Duration::from_secs(5)).unwrap();
+    assert_eq!(received[0].1.payload(), b"Hello");
+}
+
+#[test]
+fn limits_and_closes_match_the_server_binary() {
+    let config = Config {
+        max_message_size: Some(4),
+        max_fragments: Some(2),
+        ..Config::default()
+    };
+    let (_server, mut socket) = start(config.clone());
+    socket.send(text("too long")).unwrap();
+    assert_eq!(close_code(&mut socket), 1009);
+
+    let (_server, mut socket) = start(config);
+    for opcode in [Data::Text, Data::Continue, Data::Continue] {
+        let mut frame = Frame::message(Vec::new(), OpCode::Data(opcode));
+        frame.header_mut().is_final = false;
+        socket.send(frame).unwrap();
+    }
+    assert_eq!(close_code(&mut socket), 1008);
+
+    let (_server, mut socket) = start(Config::default());
+    socket
+        .send(Frame::message(vec![3], OpCode::Control(Control::Close)))
+        .unwrap();
+    assert_eq!(close_code(&mut socket), 1002);
+
+    let (_server, mut socket) = start(Config::default());
+    socket.send(Frame::close(4000, "bye")).unwrap();
+    assert_eq!(close_code(&mut socket), 4000);
+}
+
+#[test]
+fn quiet_clients_are_pinged() {
+    let config = Config {
+        ping_interval: Some(Duration::from_millis(50)),
+        ..Config::default()
+    };
+    let (_server, mut socket) = start(config);
+    let ping = socket.read().unwrap().unwrap();
+    assert_eq!(ping.header().opcode, OpCode::Control(Control::Ping));
+}
@@ -0,0 +1,373 @@
+//! Print a breakdown of the WebSocket frames in a raw capture
+//!
+//! Usage: `ws-decode [FILE]`, reading stdin without a file. A leading HTTP
+//! upgrade request or response is skipped, so a TCP stream pulled out of a
+//! tcpdump capture can be fed in as is. Exits with 1 if any frame breaks the
+//! protocol.
+
+use server::close;
+use server::error::Error;
+use server::frame::{apply_mask, Control, Data, Frame, FrameHeader, OpCode, MAX_CONTROL_PAYLOAD};
+use std::io::{self, Cursor, Read};
+use std::process;
+
+/// Payload bytes shown per frame.
+const PREVIEW: usize = 32;
+
+fn read_input() -> io::Result<Vec<u8>> {
+    let mut input = Vec::new();
+    match std::env::args().nth(1) {
+        Some(path) if path != "-" => input = std::fs::read(path)?,
+        _ => {
+            io::stdin().read_to_end(&mut input)?;
+        }
+    }
+    Ok(input)
+}
+
+/// Where the frames start, past the HTTP handshake if the capture has one.
+fn frames_start(input: &[u8]) -> usize {
+    if !input.starts_with(b"GET ") && !input.starts_with(b"HTTP/") {
+        return 0;
+    }
+    input
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(input.len(), |end| end + 4)
+}
+
+fn length_format(second: u8) -> &'static str {
+    match second & 0b0111_1111 {
+        126 => "16-bit",
+        127 => "64-bit",
+        _ => "7-bit",
+    }
+}
+
+fn preview(payload: &[u8], text: bool) -> String {
+    let shown = &payload[..payload.len().min(PREVIEW)];
+    let more = if payload.len() > PREVIEW { "..." } else { "" };
+    match std::str::from_utf8(shown) {
+        Ok(shown) if text => format!("{shown:?}{more}"),
+        _ => {
+            let hex: Vec<String> = shown.iter().map(|byte| format!("{byte:02x}")).collect();
+            format!("{}{more}", hex.join(" "))
+        }
+    }
+}
+
+/// Protocol violations in one frame, given the raw second header byte and
+/// whether a fragmented message is in progress.
+fn violations(
+    header: &FrameHeader,
+    second: u8,
+    length: u64,
+    payload: &[u8],
+    fragmented: bool,
+) -> Vec<String> {
+    let mut found = Vec::new();
+    if header.rsv1 || header.rsv2 || header.rsv3 {
+        found.push(String::from(
+            "reserved bits set without a negotiated extension",
+        ));
+    }
+    match (second & 0b0111_1111, length) {
+        (126, length) if length < 126 => found.push(String::from("16-bit length below 126")),
+        (127, length) if length < 65536 => found.push(String::from("64-bit length below 65536")),
+        (127, length) if length >> 63 != 0 => {
+            found.push(String::from("64-bit length has its top bit set"))
+        }
+        _ => {}
+    }
+    match header.opcode {
+        OpCode::Control(Control::Reserved(code)) | OpCode::Data(Data::Reserved(code)) => {
+            found.push(format!("reserved opcode {code:#x}"));
+        }
+        OpCode::Control(control) => {
+            if !header.is_final {
+                found.push(String::from("fragmented control frame"));
+            }
+            if length > MAX_CONTROL_PAYLOAD {
+                found.push(format!(
+                    "{length} byte control frame, limit is {MAX_CONTROL_PAYLOAD}"
+                ));
+            }
+            if control == Control::Close {
+                // the same rules the server holds its peers' closes to
+                let close = Frame::from_parts(header.clone(), payload.to_vec());
+                match close::status(&close) {
+                    Ok(_) => {}
+                    Err(Error::Utf8) => found.push(String::from("Close reason is not valid UTF-8")),
+                    Err(Error::Protocol(reason)) => found.push(reason),
+                    Err(error) => found.push(error.to_string()),
+                }
+            }
+        }
+        OpCode::Data(Data::Continue) if !fragmented => {
+            found.push(String::from("continuation without a message to continue"));
+        }
+        OpCode::Data(Data::Text) | OpCode::Data(Data::Binary) if fragmented => {
+            found.push(String::from(
+                "new message before the fragmented one finished",
+            ));
+        }
+        OpCode::Data(_) => {}
+    }
+    // a fragment may end mid character, so only whole messages are checked,
+    // fragmented ones by `Fragments`
+    if header.opcode == OpCode::Data(Data::Text)
+        && header.is_final
+        && std::str::from_utf8(payload).is_err()
+    {
+        found.push(String::from("Text payload is not valid UTF-8"));
+    }
+    found
+}
+
+/// The fragmented Text message in progress, if any, gathered so its UTF-8
+/// can be checked once it is whole.
+#[derive(Default)]
+struct Fragments {
+    text: Option<Vec<u8>>,
+}
+
+impl Fragments {
+    /// Take in a data frame, returning the violation if it completes a Text
+    /// message that isn't valid UTF-8.
+    fn feed(&mut self, header: &FrameHeader, payload: &[u8]) -> Option<String> {
+        match header.opcode {
+            OpCode::Data(Data::Text) if !header.is_final => self.text = Some(payload.to_vec()),
+            OpCode::Data(Data::Continue) => {
+                if let Some(text) = &mut self.text {
+                    text.extend_from_slice(payload);
+                }
+                if header.is_final {
+                    let text = self.text.take()?;
+                    if std::str::from_utf8(&text).is_err() {
+                        return Some(String::from("reassembled Text message is not valid UTF-8"));
+                    }
+                }
+            }
+            OpCode::Data(_) => self.text = None,
+            OpCode::Control(_) => {}
+        }
+        None
+    }
+}
+
+fn main() {
+    let input = read_input().unwrap_or_else(|error| {
+        eprintln!("can't read input: {error}");
+        process::exit(2);
+    });
+
+    let start = frames_start(&input);
+    if start > 0 {
+        println!("skipped {start} byte HTTP handshake");
+    }
+    let mut cursor = Cursor::new(&input[start..]);
+    let mut fragmented = false;
+    let mut fragments = Fragments::default();
+    let mut invalid = false;
+
+    for index in 0.. {
+        let offset = start + cursor.position() as usize;
+        if offset == input.len() {
+            break;
+        }
+        let (header, length) = match FrameHeader::parse(&mut cursor) {
+            Ok(Some(parsed)) => parsed,
+            _ => {
+                println!("truncated frame header at offset {offset}");
+                invalid = true;
+                break;
+            }
+        };
+        let second = input[offset + 1];
+        let body_start = start + cursor.position() as usize;
+        let available = (input.len() - body_start) as u64;
+        if length > available {
+            println!(
+                "frame {index} at offset {offset}: {length} byte payload, \
+                 only {available} bytes captured"
+            );
+            invalid = true;
+            break;
+        }
+        let mut payload = input[body_start..body_start + length as usize].to_vec();
+        cursor.set_position(cursor.position() + length);
+        if let Some(mask) = header.mask {
+            apply_mask(&mut payload, mask);
+        }
+
+        println!("frame {index} at offset {offset}");
+        println!(
+            "  fin={} rsv={}{}{} opcode={:?} ({:#x})",
+            header.is_final as u8,
+            header.rsv1 as u8,
+            header.rsv2 as u8,
+            header.rsv3 as u8,
+            header.opcode,
+            u8::from(header.opcode)
+        );
+        match header.mask {
+            Some(mask) => println!(
+                "  length {length} ({}), masked with {:02x}{:02x}{:02x}{:02x}",
+                length_format(second),
+                mask[0],
+                mask[1],
+                mask[2],
+                mask[3]
+            ),
+            None => println!("  length {length} ({}), unmasked", length_format(second)),
+        }
+        if header.opcode == OpCode::Control(Control::Close) && payload.len() >= 2 {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            println!(
+                "  close code {code}, reason {}",
+                preview(&payload[2..], true)
+            );
+        } else if !payload.is_empty() {
+            let text = matches!(
+                header.opcode,
+                OpCode::Data(Data::Text) | OpCode::Data(Data::Continue)
+            );
+            println!("  payload {}", preview(&payload, text));
+        }
+
+        let found = violations(&header, second, length, &payload, fragmented);
+        for violation in found.into_iter().chain(fragments.feed(&header, &payload)) {
+            println!("  error: {violation}");
+            invalid = true;
+        }
+        if let OpCode::Data(_) = header.opcode {
+            fragmented = !header.is_final;
+        }
+    }
+
+    if fragmented {
+        println!("capture ends inside a fragmented message");
+    }
+    if invalid {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(opcode: OpCode, is_final: bool) -> FrameHeader {
+        FrameHeader {
+            is_final,
+            rsv1: false,
+            rsv2: false,
+            rsv3: false,
+            opcode,
+            mask: None,
+        }
+    }
+
+    fn found(header: &FrameHeader, second: u8, length: u64, payload: &[u8]) -> Vec<String> {
+        violations(header, second, length, payload, false)
+    }
+
+    #[test]
+    fn reserved_bits_are_violations() {
+        let mut rsv = header(OpCode::Data(Data::Binary), true);
+        assert!(found(&rsv, 0, 0, b"").is_empty());
+        rsv.rsv3 = true;
+        assert_eq!(
+            found(&rsv, 0, 0, b""),
+            ["reserved bits set without a negotiated extension"]
+        );
+    }
+
+    #[test]
+    fn lengths_must_use_the_shortest_format() {
+        let binary = header(OpCode::Data(Data::Binary), true);
+        assert_eq!(
+            found(&binary, 126, 125, &[0; 125]),
+            ["16-bit length below 126"]
+        );
+        assert!(found(&binary, 126, 126, &[0; 126]).is_empty());
+        assert_eq!(
+            found(&binary, 127, 65535, b""),
+            ["64-bit length below 65536"]
+        );
+        assert!(found(&binary, 127, 65536, b"").is_empty());
+        assert_eq!(
+            found(&binary, 127, 1 << 63, b""),
+            ["64-bit length has its top bit set"]
+        );
+    }
+
+    #[test]
+    fn control_frames_must_be_final_and_short() {
+        let ping = header(OpCode::Control(Control::Ping), true);
+        assert!(found(&ping, 125, 125, &[0; 125]).is_empty());
+        assert_eq!(
+            found(&ping, 126, 126, &[0; 126]),
+            ["126 byte control frame, limit is 125"]
+        );
+        let fragmented = header(OpCode::Control(Control::Pong), false);
+        assert_eq!(found(&fragmented, 0, 0, b""), ["fragmented control frame"]);
+    }
+
+    #[test]
+    fn closes_must_carry_a_sendable_code_and_a_utf8_reason() {
+        let close = header(OpCode::Control(Control::Close), true);
+        let with = |code: u16, reason: &[u8]| {
+            let mut payload = code.to_be_bytes().to_vec();
+            payload.extend_from_slice(reason);
+            found(&close, payload.len() as u8, payload.len() as u64, &payload)
+        };
+        assert!(found(&close, 0, 0, b"").is_empty());
+        assert!(with(1000, b"bye").is_empty());
+        assert!(with(4000, "\u{3ba}".as_bytes()).is_empty());
+        assert_eq!(
+            found(&close, 1, 1, &[3]),
+            ["Close frame with a 1-byte payload"]
+        );
+        assert_eq!(with(1005, b""), ["invalid close code 1005"]);
+        assert_eq!(with(999, b""), ["invalid close code 999"]);
+        assert_eq!(
+            with(1000, b"\xce\xba\xff"),
+            ["Close reason is not valid UTF-8"]
+        );
+    }
+
+    #[test]
+    fn character_split_across_fragments_is_fine() {
+        let mut fragments = Fragments::default();
+        let text = "h\u{e9}llo".as_bytes();
+        assert_eq!(
+            fragments.feed(&header(OpCode::Data(Data::Text), false), &text[..2]),
+            None
+        );
+        assert_eq!(
+            fragments.feed(&header(OpCode::Data(Data::Continue), true), &text[2..]),
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_in_a_fragmented_message_is_found() {
+        let mut fragments = Fragments::default();
+        fragments.feed(&header(OpCode::Data(Data::Text), false), b"ok ");
+        fragments.feed(&header(OpCode::Data(Data::Continue), false), &[0xff]);
+        assert!(fragments
+            .feed(&header(OpCode::Data(Data::Continue), true), b" end")
+            .is_some());
+    }
+
+    #[test]
+    fn binary_messages_are_not_checked() {
+        let mut fragments = Fragments::default();
+        fragments.feed(&header(OpCode::Data(Data::Binary), false), &[0xff]);
+        assert_eq!(
+            fragments.feed(&header(OpCode::Data(Data::Continue), true), &[0xfe]),
+            None
+        );
+    }
+}